@@ -0,0 +1,295 @@
+use chrono::{DateTime, Duration, Utc};
+
+use crate::models::{
+    AttendanceOverride, AttendanceRecord, AttendanceSession, AttendanceStatus, Lesson, LessonMember,
+};
+
+// Joining this long after the start still counts as on time
+const LATE_GRACE_MINUTES: i64 = 5;
+// Leaving this long before the end still counts as staying
+const EARLY_LEAVE_GRACE_MINUTES: i64 = 5;
+
+// Builds one attendance record per student from their sessions in the lesson.
+// Sessions still open (or left open by a crash) count until now or the lesson end.
+pub fn compute_attendance(
+    lesson: &Lesson,
+    members: &[LessonMember],
+    sessions: &[AttendanceSession],
+    overrides: &[AttendanceOverride],
+    now: DateTime<Utc>,
+) -> Vec<AttendanceRecord> {
+    let start = lesson.scheduled_at;
+    let end = lesson.ends_at();
+
+    members
+        .iter()
+        .map(|member| {
+            let mine: Vec<&AttendanceSession> =
+                sessions.iter().filter(|s| s.user_id == member.user_id).collect();
+            let first_joined_at = mine.iter().map(|s| s.joined_at).min();
+            let still_connected = mine.iter().any(|s| s.left_at.is_none());
+            let last_left_at = if still_connected {
+                None
+            } else {
+                mine.iter().filter_map(|s| s.left_at).max()
+            };
+
+            // Sessions from several tabs overlap; time covered twice counts once
+            let mut spans: Vec<(DateTime<Utc>, DateTime<Utc>)> = mine
+                .iter()
+                .map(|s| (s.joined_at.max(start), s.left_at.unwrap_or(now).min(end)))
+                .collect();
+            spans.sort();
+            let mut present = Duration::zero();
+            let mut covered_until = start;
+            for (from, to) in spans {
+                let from = from.max(covered_until);
+                if to > from {
+                    present += to - from;
+                    covered_until = to;
+                }
+            }
+            let minutes_present = present.num_minutes();
+
+            let computed_status = if minutes_present == 0 {
+                AttendanceStatus::Absent
+            } else if first_joined_at.is_some_and(|t| t > start + Duration::minutes(LATE_GRACE_MINUTES)) {
+                AttendanceStatus::Late
+            } else if last_left_at.is_some_and(|t| t < end - Duration::minutes(EARLY_LEAVE_GRACE_MINUTES)) {
+                AttendanceStatus::LeftEarly
+            } else {
+                AttendanceStatus::Present
+            };
+
+            let manual = overrides.iter().find(|o| o.user_id == member.user_id);
+            AttendanceRecord {
+                lesson_id: lesson.id,
+                lesson_title: lesson.title.clone(),
+                scheduled_at: lesson.scheduled_at,
+                user_id: member.user_id,
+                first_name: member.first_name.clone(),
+                last_name: member.last_name.clone(),
+                status: manual.map(|o| o.status).unwrap_or(computed_status),
+                computed_status,
+                overridden: manual.is_some(),
+                note: manual.and_then(|o| o.note.clone()),
+                first_joined_at,
+                last_left_at,
+                minutes_present,
+            }
+        })
+        .collect()
+}
+
+// Quotes where needed and defuses spreadsheet formulas in user-supplied text
+fn csv_field(s: &str) -> String {
+    let s = if s.starts_with(['=', '+', '-', '@']) {
+        format!("'{s}")
+    } else {
+        s.to_string()
+    };
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+pub fn to_csv(records: &[AttendanceRecord]) -> String {
+    let mut out = String::from(
+        "lesson_id,lesson_title,scheduled_at,user_id,first_name,last_name,status,computed_status,overridden,note,first_joined_at,last_left_at,minutes_present\n",
+    );
+    for r in records {
+        let fields = [
+            r.lesson_id.to_string(),
+            csv_field(&r.lesson_title),
+            r.scheduled_at.to_rfc3339(),
+            r.user_id.to_string(),
+            csv_field(&r.first_name),
+            csv_field(&r.last_name),
+            r.status.to_string(),
+            r.computed_status.to_string(),
+            r.overridden.to_string(),
+            csv_field(r.note.as_deref().unwrap_or("")),
+            r.first_joined_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            r.last_left_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            r.minutes_present.to_string(),
+        ];
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LessonStatus, UserType};
+    use uuid::Uuid;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    // A 60 minute lesson from 10:00 to 11:00
+    fn lesson() -> Lesson {
+        Lesson {
+            id: Uuid::new_v4(),
+            classroom_id: Uuid::new_v4(),
+            teacher_id: Uuid::new_v4(),
+            title: "Past tenses".to_string(),
+            description: String::new(),
+            scheduled_at: utc("2025-03-03T10:00:00Z"),
+            duration_minutes: 60,
+            status: LessonStatus::Ended,
+            chat_closed: false,
+            chat_slow_mode_seconds: None,
+            chat_max_length: None,
+            whiteboard_students_can_draw: false,
+            created_at: utc("2025-03-01T09:00:00Z"),
+            cancellation_reason: None,
+            series_id: None,
+            occurrence_start: None,
+        }
+    }
+
+    fn student(first_name: &str) -> LessonMember {
+        LessonMember {
+            user_id: Uuid::new_v4(),
+            first_name: first_name.to_string(),
+            last_name: "Student".to_string(),
+            user_type: UserType::Student,
+        }
+    }
+
+    fn session(lesson: &Lesson, member: &LessonMember, joined: &str, left: Option<&str>) -> AttendanceSession {
+        AttendanceSession {
+            id: Uuid::new_v4(),
+            lesson_id: lesson.id,
+            user_id: member.user_id,
+            joined_at: utc(joined),
+            left_at: left.map(utc),
+        }
+    }
+
+    fn after_lesson() -> DateTime<Utc> {
+        utc("2025-03-03T12:00:00Z")
+    }
+
+    fn one(lesson: &Lesson, member: &LessonMember, sessions: &[AttendanceSession]) -> AttendanceRecord {
+        compute_attendance(lesson, std::slice::from_ref(member), sessions, &[], after_lesson()).remove(0)
+    }
+
+    #[test]
+    fn joining_or_leaving_within_the_grace_period_is_present() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        let record = one(&lesson, &ana, &[session(&lesson, &ana, "2025-03-03T10:05:00Z", Some("2025-03-03T10:55:00Z"))]);
+        assert_eq!(record.status, AttendanceStatus::Present);
+        assert_eq!(record.minutes_present, 50);
+    }
+
+    #[test]
+    fn joining_after_the_grace_period_is_late() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        let record = one(&lesson, &ana, &[session(&lesson, &ana, "2025-03-03T10:06:00Z", Some("2025-03-03T11:00:00Z"))]);
+        assert_eq!(record.status, AttendanceStatus::Late);
+    }
+
+    #[test]
+    fn leaving_before_the_grace_period_is_left_early() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        let record = one(&lesson, &ana, &[session(&lesson, &ana, "2025-03-03T09:58:00Z", Some("2025-03-03T10:54:00Z"))]);
+        assert_eq!(record.status, AttendanceStatus::LeftEarly);
+        assert_eq!(record.first_joined_at, Some(utc("2025-03-03T09:58:00Z")));
+        assert_eq!(record.minutes_present, 54);
+    }
+
+    #[test]
+    fn no_sessions_or_only_outside_the_lesson_is_absent() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        assert_eq!(one(&lesson, &ana, &[]).status, AttendanceStatus::Absent);
+        let before = session(&lesson, &ana, "2025-03-03T09:00:00Z", Some("2025-03-03T09:30:00Z"));
+        let record = one(&lesson, &ana, &[before]);
+        assert_eq!(record.status, AttendanceStatus::Absent);
+        assert_eq!(record.minutes_present, 0);
+    }
+
+    #[test]
+    fn overlapping_sessions_from_tabs_count_once() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        let sessions = [
+            session(&lesson, &ana, "2025-03-03T10:00:00Z", Some("2025-03-03T10:40:00Z")),
+            session(&lesson, &ana, "2025-03-03T10:20:00Z", Some("2025-03-03T10:30:00Z")),
+            session(&lesson, &ana, "2025-03-03T10:35:00Z", Some("2025-03-03T11:00:00Z")),
+        ];
+        let record = one(&lesson, &ana, &sessions);
+        assert_eq!(record.minutes_present, 60);
+        assert_eq!(record.status, AttendanceStatus::Present);
+        assert_eq!(record.last_left_at, Some(utc("2025-03-03T11:00:00Z")));
+    }
+
+    #[test]
+    fn a_tab_still_open_counts_until_now() {
+        let lesson = lesson();
+        let ana = student("Ana");
+        let sessions = [
+            session(&lesson, &ana, "2025-03-03T10:00:00Z", Some("2025-03-03T10:10:00Z")),
+            session(&lesson, &ana, "2025-03-03T10:00:00Z", None),
+        ];
+        let now = utc("2025-03-03T10:30:00Z");
+        let record = compute_attendance(&lesson, std::slice::from_ref(&ana), &sessions, &[], now).remove(0);
+        assert_eq!(record.minutes_present, 30);
+        assert_eq!(record.last_left_at, None);
+        assert_eq!(record.status, AttendanceStatus::Present);
+    }
+
+    #[test]
+    fn overrides_replace_the_status_but_keep_the_computed_one() {
+        let lesson = lesson();
+        let (ana, ben) = (student("Ana"), student("Ben"));
+        let overrides = [AttendanceOverride {
+            lesson_id: lesson.id,
+            user_id: ana.user_id,
+            status: AttendanceStatus::Present,
+            note: Some("Connection problems".to_string()),
+            set_by: lesson.teacher_id,
+            set_at: after_lesson(),
+        }];
+        let records = compute_attendance(&lesson, &[ana, ben], &[], &overrides, after_lesson());
+        assert_eq!(records[0].status, AttendanceStatus::Present);
+        assert_eq!(records[0].computed_status, AttendanceStatus::Absent);
+        assert!(records[0].overridden);
+        assert_eq!(records[0].note.as_deref(), Some("Connection problems"));
+        assert_eq!(records[1].status, AttendanceStatus::Absent);
+        assert!(!records[1].overridden);
+    }
+
+    #[test]
+    fn csv_quotes_fields_and_defuses_formulas() {
+        assert_eq!(csv_field("Ana"), "Ana");
+        assert_eq!(csv_field("Smith, Jr."), "\"Smith, Jr.\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@home"), "'@home");
+    }
+
+    #[test]
+    fn csv_has_a_header_and_one_line_per_record() {
+        let lesson = Lesson { title: "Tenses, part 1".to_string(), ..lesson() };
+        let ana = student("Ana");
+        let records = compute_attendance(&lesson, std::slice::from_ref(&ana), &[], &[], after_lesson());
+        let csv = to_csv(&records);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("lesson_id,lesson_title,"));
+        assert!(lines[1].contains(",\"Tenses, part 1\","));
+        assert!(lines[1].ends_with(",absent,absent,false,,,,0"));
+    }
+}
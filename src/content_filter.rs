@@ -0,0 +1,278 @@
+// Server-side filter for students' chat messages, driven by the classroom's
+// rules. Every rule that matches applies: `block` rejects the message, `mask`
+// replaces the match with asterisks, and `flag` lets the message through but
+// records why, for the teacher's moderation queue. Block and flag rules look at
+// the message as sent, so masking can't hide a match from them.
+//
+// Compiled rules are cached per classroom. The cache is dropped when a rule is
+// added or removed here, and rebuilt whenever the classroom's rule ids in the
+// database differ from the cached ones, which catches changes made on other
+// instances. The ids come with the chat policy, so a cache hit costs no query.
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use uuid::Uuid;
+
+use crate::models::{ChatFilterAction, ChatFilterKind, ChatFilterRule};
+
+pub const MAX_PATTERN_LENGTH: usize = 200;
+
+static LINK: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\b(?:https?://|www\.)\S+|\b[a-z0-9-]+(?:\.[a-z0-9-]+)*\.(?:com|net|org|io|co|me|ly|gg|tv|app|xyz|info|ru|de|uk)\b(?:/\S*)?").unwrap()
+});
+// Eight or more digits, optionally separated by spaces, dots, dashes or brackets
+static PHONE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\+?\d(?:[\s().-]*\d){7,}").unwrap());
+static EMAIL: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b").unwrap());
+
+// A classroom's rules, in order, with their matchers. Rules are only ever added
+// and removed, never edited, so their ids identify the set.
+pub struct CompiledRules {
+    ids: Vec<Uuid>,
+    rules: Vec<(ChatFilterRule, Regex)>,
+}
+
+static COMPILED: Lazy<Mutex<HashMap<Uuid, Arc<CompiledRules>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub struct Verdict {
+    // The message with masks applied
+    pub text: String,
+    pub blocked: bool,
+    // Descriptions of the flag rules that matched
+    pub flags: Vec<String>,
+}
+
+// The matcher for a rule; the error is meant for the teacher who wrote it
+pub fn compile(kind: ChatFilterKind, pattern: Option<&str>) -> Result<Regex, String> {
+    let pattern = pattern.map(str::trim).filter(|p| !p.is_empty());
+    match kind {
+        ChatFilterKind::Word => {
+            let word = pattern.ok_or("word rules need a pattern")?;
+            // \b only means something next to a word character
+            let boundary = |c: Option<char>| if c.is_some_and(|c| c.is_alphanumeric() || c == '_') { r"\b" } else { "" };
+            let source = format!("{}{}{}", boundary(word.chars().next()), regex::escape(word), boundary(word.chars().last()));
+            RegexBuilder::new(&source).case_insensitive(true).build().map_err(|e| e.to_string())
+        }
+        ChatFilterKind::Regex => {
+            let source = pattern.ok_or("regex rules need a pattern")?;
+            RegexBuilder::new(source)
+                .case_insensitive(true)
+                .size_limit(1 << 20)
+                .build()
+                .map_err(|e| e.to_string())
+        }
+        ChatFilterKind::Link => Ok(LINK.clone()),
+        ChatFilterKind::Phone => Ok(PHONE.clone()),
+        ChatFilterKind::Email => Ok(EMAIL.clone()),
+    }
+}
+
+fn describe(rule: &ChatFilterRule) -> String {
+    match (rule.kind, rule.pattern.as_deref()) {
+        (ChatFilterKind::Word, Some(p)) => format!("word: {p}"),
+        (ChatFilterKind::Regex, Some(p)) => format!("pattern: {p}"),
+        (ChatFilterKind::Link, _) => "link".to_string(),
+        (ChatFilterKind::Phone, _) => "phone number".to_string(),
+        (ChatFilterKind::Email, _) => "email address".to_string(),
+        (kind, None) => format!("{kind:?}").to_lowercase(),
+    }
+}
+
+// Where in `text` the rule matches. The domain part of an email address isn't a link.
+fn find(kind: ChatFilterKind, re: &Regex, text: &str) -> Vec<Range<usize>> {
+    let found = re.find_iter(text).map(|m| m.range());
+    if kind != ChatFilterKind::Link {
+        return found.collect();
+    }
+    let emails: Vec<_> = EMAIL.find_iter(text).map(|m| m.range()).collect();
+    found.filter(|r| !emails.iter().any(|e| e.start < r.end && r.start < e.end)).collect()
+}
+
+fn mask(text: &str, ranges: &[Range<usize>]) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let mut last = 0;
+    for range in ranges {
+        masked.push_str(&text[last..range.start]);
+        masked.extend(std::iter::repeat_n('*', text[range.clone()].chars().count()));
+        last = range.end;
+    }
+    masked.push_str(&text[last..]);
+    masked
+}
+
+// The classroom's compiled rules if they are cached and still `rule_ids`
+pub fn cached(classroom_id: Uuid, rule_ids: &[Uuid]) -> Option<Arc<CompiledRules>> {
+    COMPILED.lock().unwrap().get(&classroom_id).filter(|c| c.ids == rule_ids).cloned()
+}
+
+// Compiles the classroom's current `rules`, or reuses them if cached
+pub fn compiled(classroom_id: Uuid, rules: &[ChatFilterRule]) -> Arc<CompiledRules> {
+    let ids: Vec<Uuid> = rules.iter().map(|r| r.id).collect();
+    if let Some(cached) = cached(classroom_id, &ids) {
+        return cached;
+    }
+    let compiled = Arc::new(CompiledRules {
+        ids,
+        // Patterns are checked when rules are created, so this only skips rules
+        // that stopped compiling with a newer regex engine
+        rules: rules
+            .iter()
+            .filter_map(|rule| Some((rule.clone(), compile(rule.kind, rule.pattern.as_deref()).ok()?)))
+            .collect(),
+    });
+    COMPILED.lock().unwrap().insert(classroom_id, compiled.clone());
+    compiled
+}
+
+// Drops the classroom's compiled rules; call after changing them
+pub fn forget(classroom_id: Uuid) {
+    COMPILED.lock().unwrap().remove(&classroom_id);
+}
+
+// Runs a classroom's compiled rules over a message
+pub fn apply(rules: &CompiledRules, text: &str) -> Verdict {
+    let mut verdict = Verdict { text: text.to_string(), blocked: false, flags: Vec::new() };
+    for (rule, re) in &rules.rules {
+        match rule.action {
+            ChatFilterAction::Block if !find(rule.kind, re, text).is_empty() => {
+                verdict.blocked = true;
+                return verdict;
+            }
+            ChatFilterAction::Flag if !find(rule.kind, re, text).is_empty() => verdict.flags.push(describe(rule)),
+            ChatFilterAction::Mask => {
+                let ranges = find(rule.kind, re, &verdict.text);
+                if !ranges.is_empty() {
+                    verdict.text = mask(&verdict.text, &ranges);
+                }
+            }
+            _ => {}
+        }
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn rule(kind: ChatFilterKind, pattern: Option<&str>, action: ChatFilterAction) -> ChatFilterRule {
+        ChatFilterRule {
+            id: Uuid::new_v4(),
+            classroom_id: Uuid::nil(),
+            kind,
+            pattern: pattern.map(str::to_string),
+            action,
+            created_by: Uuid::nil(),
+            created_at: Utc::now(),
+        }
+    }
+
+    // Each test uses its own classroom, so the shared cache can't mix them up
+    fn run(rules: &[ChatFilterRule], text: &str) -> Verdict {
+        apply(&compiled(Uuid::new_v4(), rules), text)
+    }
+
+    #[test]
+    fn word_rules_match_whole_words_only() {
+        let rules = [rule(ChatFilterKind::Word, Some("ass"), ChatFilterAction::Mask)];
+        assert_eq!(run(&rules, "first class assignment").text, "first class assignment");
+        assert_eq!(run(&rules, "don't be an ass!").text, "don't be an ***!");
+    }
+
+    #[test]
+    fn word_rules_without_word_characters_at_the_edges_match_anywhere() {
+        let rules = [rule(ChatFilterKind::Word, Some(":("), ChatFilterAction::Mask)];
+        assert_eq!(run(&rules, "sad:(").text, "sad**");
+    }
+
+    #[test]
+    fn regex_rules_match_substrings() {
+        let rules = [rule(ChatFilterKind::Regex, Some("d[a4]mn"), ChatFilterAction::Mask)];
+        assert_eq!(run(&rules, "goddamnit, d4mn").text, "god****it, ****");
+    }
+
+    #[test]
+    fn matching_ignores_case() {
+        let rules = [
+            rule(ChatFilterKind::Word, Some("Idiot"), ChatFilterAction::Flag),
+            rule(ChatFilterKind::Word, Some("straße"), ChatFilterAction::Mask),
+        ];
+        let verdict = run(&rules, "IDIOT on the STRASSE, not the STRAẞE");
+        assert_eq!(verdict.flags, vec!["word: Idiot"]);
+        assert_eq!(verdict.text, "IDIOT on the STRASSE, not the ******");
+    }
+
+    #[test]
+    fn masks_have_one_asterisk_per_character() {
+        let rules = [
+            rule(ChatFilterKind::Word, Some("tonto"), ChatFilterAction::Mask),
+            rule(ChatFilterKind::Word, Some("çé日本"), ChatFilterAction::Mask),
+        ];
+        let verdict = run(&rules, "eres TONTO, çé日本!");
+        assert_eq!(verdict.text, "eres *****, ****!");
+    }
+
+    #[test]
+    fn block_and_flag_see_the_message_before_masking() {
+        let rules = [
+            rule(ChatFilterKind::Word, Some("secret"), ChatFilterAction::Mask),
+            rule(ChatFilterKind::Word, Some("secret"), ChatFilterAction::Flag),
+        ];
+        let verdict = run(&rules, "a secret");
+        assert!(!verdict.blocked);
+        assert_eq!(verdict.text, "a ******");
+        assert_eq!(verdict.flags, vec!["word: secret"]);
+
+        let rules = [
+            rule(ChatFilterKind::Word, Some("secret"), ChatFilterAction::Mask),
+            rule(ChatFilterKind::Word, Some("secret"), ChatFilterAction::Block),
+        ];
+        assert!(run(&rules, "a secret").blocked);
+    }
+
+    #[test]
+    fn links_skip_the_domain_of_an_email_address() {
+        let rules = [rule(ChatFilterKind::Link, None, ChatFilterAction::Mask)];
+        assert_eq!(run(&rules, "mail ana@example.com").text, "mail ana@example.com");
+        assert_eq!(run(&rules, "see example.com/x").text, "see *************");
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        assert!(compile(ChatFilterKind::Regex, Some("(unclosed")).is_err());
+        assert!(compile(ChatFilterKind::Regex, Some("   ")).is_err());
+        assert!(compile(ChatFilterKind::Word, None).is_err());
+        assert!(compile(ChatFilterKind::Regex, Some("a{1000}{1000}")).is_err());
+        // Word patterns are literal, so regex syntax in them is fine
+        assert!(compile(ChatFilterKind::Word, Some("(unclosed")).is_ok());
+        assert!(compile(ChatFilterKind::Email, None).is_ok());
+    }
+
+    #[test]
+    fn cache_follows_the_rule_ids() {
+        let classroom_id = Uuid::new_v4();
+        let first = vec![rule(ChatFilterKind::Word, Some("one"), ChatFilterAction::Mask)];
+        let ids: Vec<Uuid> = first.iter().map(|r| r.id).collect();
+        assert!(cached(classroom_id, &ids).is_none());
+
+        let compiled_once = compiled(classroom_id, &first);
+        let hit = cached(classroom_id, &ids).unwrap();
+        assert!(Arc::ptr_eq(&compiled_once, &hit));
+        assert!(Arc::ptr_eq(&compiled(classroom_id, &first), &hit));
+
+        // Another instance added a rule: the ids differ, so the cache misses
+        let mut second = first.clone();
+        second.push(rule(ChatFilterKind::Word, Some("two"), ChatFilterAction::Mask));
+        let second_ids: Vec<Uuid> = second.iter().map(|r| r.id).collect();
+        assert!(cached(classroom_id, &second_ids).is_none());
+        assert_eq!(apply(&compiled(classroom_id, &second), "one two").text, "*** ***");
+        assert!(cached(classroom_id, &ids).is_none());
+        assert!(cached(classroom_id, &second_ids).is_some());
+
+        forget(classroom_id);
+        assert!(cached(classroom_id, &second_ids).is_none());
+    }
+}
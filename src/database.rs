@@ -165,7 +165,6 @@ impl Database {
                 title VARCHAR(255) NOT NULL,
                 description TEXT,
                 scheduled_at TIMESTAMPTZ NOT NULL,
-                is_active BOOLEAN DEFAULT TRUE,
                 chat_closed BOOLEAN DEFAULT FALSE,
                 created_at TIMESTAMPTZ DEFAULT NOW()
             );
@@ -199,267 +198,2617 @@ impl Database {
             "#
         ).execute(&self.pool).await?;
 
-        Ok(())
-    }
+        // 13. Create lesson_status enum if not exists
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'lesson_status') THEN
+                    CREATE TYPE lesson_status AS ENUM ('scheduled', 'live', 'ended', 'cancelled');
+                END IF;
+            END$$;
+            "#
+        ).execute(&self.pool).await?;
 
-    pub async fn create_user(&self, user: &User) -> anyhow::Result<()> {
+        // 14. Add status lifecycle fields to lessons
         sqlx::query(
             r#"
-            INSERT INTO users (id, email, password_hash, user_type, first_name, last_name)
-            VALUES ($1, $2, $3, $4, $5, $6)
+            ALTER TABLE lessons
+            ADD COLUMN IF NOT EXISTS status lesson_status NOT NULL DEFAULT 'scheduled',
+            ADD COLUMN IF NOT EXISTS cancellation_reason TEXT;
             "#
-        )
-        .bind(user.id)
-        .bind(&user.email)
-        .bind(&user.password_hash)
-        .bind(&user.user_type) // bind as enum, not string
-        .bind(&user.first_name)
-        .bind(&user.last_name)
-        .execute(&self.pool)
-        .await?;
+        ).execute(&self.pool).await?;
 
-        Ok(())
+        // 15. Replace the old is_active flag with status
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF EXISTS (
+                    SELECT 1 FROM information_schema.columns
+                    WHERE table_name = 'lessons' AND column_name = 'is_active'
+                ) THEN
+                    UPDATE lessons SET status = 'ended' WHERE is_active = FALSE;
+                    ALTER TABLE lessons DROP COLUMN is_active;
+                END IF;
+            END$$;
+            "#
+        ).execute(&self.pool).await?;
+
+        // 16. Create lesson_changes table (edit/reschedule/cancel history)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_changes (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id),
+                changed_by UUID NOT NULL REFERENCES users(id),
+                action VARCHAR(32) NOT NULL,
+                details JSONB NOT NULL DEFAULT '{}',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 17. Create classroom_enrollments table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS classroom_enrollments (
+                classroom_id UUID NOT NULL REFERENCES classrooms(id),
+                student_id UUID NOT NULL REFERENCES users(id),
+                enrolled_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (classroom_id, student_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 18. Create notifications table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notifications (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                user_id UUID NOT NULL REFERENCES users(id),
+                kind VARCHAR(64) NOT NULL,
+                title VARCHAR(255) NOT NULL,
+                body TEXT NOT NULL,
+                lesson_id UUID REFERENCES lessons(id),
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                read_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 19. Create lesson_series table and link lessons to it
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_series (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                classroom_id UUID NOT NULL REFERENCES classrooms(id),
+                teacher_id UUID NOT NULL REFERENCES users(id),
+                title VARCHAR(255) NOT NULL,
+                description TEXT NOT NULL DEFAULT '',
+                starts_at TIMESTAMPTZ NOT NULL,
+                rrule TEXT NOT NULL,
+                exception_dates DATE[] NOT NULL DEFAULT '{}',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            ALTER TABLE lessons
+            ADD COLUMN IF NOT EXISTS series_id UUID REFERENCES lesson_series(id),
+            ADD COLUMN IF NOT EXISTS occurrence_start TIMESTAMPTZ;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lessons_series ON lessons(series_id, occurrence_start);"#
+        ).execute(&self.pool).await?;
+
+        // 20. Durations and IANA time zones
+        sqlx::query(
+            r#"ALTER TABLE users ADD COLUMN IF NOT EXISTS time_zone VARCHAR(64) NOT NULL DEFAULT 'UTC';"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"ALTER TABLE classrooms ADD COLUMN IF NOT EXISTS time_zone VARCHAR(64) NOT NULL DEFAULT 'UTC';"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"ALTER TABLE lessons ADD COLUMN IF NOT EXISTS duration_minutes INTEGER NOT NULL DEFAULT 60;"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            ALTER TABLE lesson_series
+            ADD COLUMN IF NOT EXISTS duration_minutes INTEGER NOT NULL DEFAULT 60,
+            ADD COLUMN IF NOT EXISTS time_zone VARCHAR(64) NOT NULL DEFAULT 'UTC';
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lessons_teacher_time ON lessons(teacher_id, scheduled_at);"#
+        ).execute(&self.pool).await?;
+
+        // 21. Create calendar_feed_tokens table
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS calendar_feed_tokens (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                user_id UUID NOT NULL REFERENCES users(id),
+                token VARCHAR(128) NOT NULL UNIQUE,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                revoked_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 22. Attendance: presence sessions recorded by the WebSocket layer plus manual overrides
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'attendance_status') THEN
+                    CREATE TYPE attendance_status AS ENUM ('present', 'late', 'left_early', 'absent');
+                END IF;
+            END$$;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_attendance_sessions (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id),
+                user_id UUID NOT NULL REFERENCES users(id),
+                joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                left_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_attendance_overrides (
+                lesson_id UUID NOT NULL REFERENCES lessons(id),
+                user_id UUID NOT NULL REFERENCES users(id),
+                status attendance_status NOT NULL,
+                note TEXT,
+                set_by UUID NOT NULL REFERENCES users(id),
+                set_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (lesson_id, user_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 23. Reminders: per-user preferences and a log of sent reminders. The log's
+        // primary key is what keeps sends idempotent across restarts.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS reminder_preferences (
+                user_id UUID PRIMARY KEY REFERENCES users(id),
+                offsets_minutes INTEGER[] NOT NULL,
+                channels TEXT[] NOT NULL,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_reminders (
+                lesson_id UUID NOT NULL REFERENCES lessons(id),
+                user_id UUID NOT NULL REFERENCES users(id),
+                offset_minutes INTEGER NOT NULL,
+                scheduled_for TIMESTAMPTZ NOT NULL,
+                sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (lesson_id, user_id, offset_minutes, scheduled_for)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 24. Lesson plans (templates have no lesson)
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_plans (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                teacher_id UUID NOT NULL REFERENCES users(id),
+                lesson_id UUID UNIQUE REFERENCES lessons(id),
+                source_plan_id UUID REFERENCES lesson_plans(id) ON DELETE SET NULL,
+                title VARCHAR(255) NOT NULL,
+                level VARCHAR(50) NOT NULL DEFAULT '',
+                objectives TEXT[] NOT NULL DEFAULT '{}',
+                vocabulary TEXT[] NOT NULL DEFAULT '{}',
+                grammar_points TEXT[] NOT NULL DEFAULT '{}',
+                activities JSONB NOT NULL DEFAULT '[]',
+                materials JSONB NOT NULL DEFAULT '[]',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_plans_teacher ON lesson_plans(teacher_id, updated_at DESC);"#
+        ).execute(&self.pool).await?;
+
+        // 25. Chat messages carry the sender's role; names and roles come from users,
+        // never from the client. Rows written before this are backfilled once.
+        sqlx::query(
+            r#"ALTER TABLE lesson_chat_messages ADD COLUMN IF NOT EXISTS user_type user_type;"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            UPDATE lesson_chat_messages m
+            SET user_type = u.user_type,
+                username = LEFT(TRIM(u.first_name || ' ' || u.last_name), 100)
+            FROM users u
+            WHERE u.id = m.user_id AND m.user_type IS NULL;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"ALTER TABLE lesson_chat_messages ALTER COLUMN user_type SET NOT NULL;"#
+        ).execute(&self.pool).await?;
+
+        // 26. Chat sequence numbers, so reconnecting clients can resume after the last
+        // message they saw. Existing rows are numbered in timestamp order.
+        sqlx::query(r#"CREATE SEQUENCE IF NOT EXISTS lesson_chat_messages_seq;"#)
+            .execute(&self.pool).await?;
+
+        sqlx::query(r#"ALTER TABLE lesson_chat_messages ADD COLUMN IF NOT EXISTS seq BIGINT;"#)
+            .execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            UPDATE lesson_chat_messages m
+            SET seq = numbered.seq
+            FROM (
+                SELECT id, nextval('lesson_chat_messages_seq') AS seq
+                FROM (SELECT id FROM lesson_chat_messages WHERE seq IS NULL ORDER BY timestamp, id) ordered
+            ) numbered
+            WHERE m.id = numbered.id;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            ALTER TABLE lesson_chat_messages
+                ALTER COLUMN seq SET DEFAULT nextval('lesson_chat_messages_seq'),
+                ALTER COLUMN seq SET NOT NULL;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(r#"ALTER SEQUENCE lesson_chat_messages_seq OWNED BY lesson_chat_messages.seq;"#)
+            .execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_chat_messages_lesson_seq ON lesson_chat_messages(lesson_id, seq);"#
+        ).execute(&self.pool).await?;
+
+        // 27. Room events too large for a NOTIFY payload, fetched by id by each instance
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS room_event_payloads (
+                id UUID PRIMARY KEY,
+                payload JSONB NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 28. Per-lesson chat limits set by the teacher; NULL means no limit
+        sqlx::query(
+            r#"
+            ALTER TABLE lessons
+                ADD COLUMN IF NOT EXISTS chat_slow_mode_seconds INTEGER,
+                ADD COLUMN IF NOT EXISTS chat_max_length INTEGER;
+            "#
+        ).execute(&self.pool).await?;
+
+        // 29. Chat content filter: per-classroom rules, and the queue of messages
+        // flagged for the teacher to review
+        sqlx::query(
+            r#"
+            DO $$
+            BEGIN
+                IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'chat_filter_kind') THEN
+                    CREATE TYPE chat_filter_kind AS ENUM ('word', 'regex', 'link', 'phone', 'email');
+                END IF;
+                IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'chat_filter_action') THEN
+                    CREATE TYPE chat_filter_action AS ENUM ('mask', 'block', 'flag');
+                END IF;
+                IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'chat_flag_status') THEN
+                    CREATE TYPE chat_flag_status AS ENUM ('pending', 'dismissed', 'removed');
+                END IF;
+            END$$;
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS chat_filter_rules (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                classroom_id UUID NOT NULL REFERENCES classrooms(id) ON DELETE CASCADE,
+                kind chat_filter_kind NOT NULL,
+                pattern TEXT,
+                action chat_filter_action NOT NULL,
+                created_by UUID NOT NULL REFERENCES users(id),
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS flagged_chat_messages (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                classroom_id UUID NOT NULL REFERENCES classrooms(id) ON DELETE CASCADE,
+                lesson_id UUID REFERENCES lessons(id) ON DELETE CASCADE,
+                message_id UUID NOT NULL,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                message TEXT NOT NULL,
+                reasons TEXT[] NOT NULL DEFAULT '{}',
+                status chat_flag_status NOT NULL DEFAULT 'pending',
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                reviewed_by UUID REFERENCES users(id),
+                reviewed_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_chat_filter_rules_classroom ON chat_filter_rules(classroom_id);"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_flagged_chat_classroom ON flagged_chat_messages(classroom_id, status, created_at);"#
+        ).execute(&self.pool).await?;
+
+        // 30. Chat edits (previous versions are kept), replies and emoji reactions
+        sqlx::query(
+            r#"
+            ALTER TABLE lesson_chat_messages
+                ADD COLUMN IF NOT EXISTS edited_at TIMESTAMPTZ,
+                ADD COLUMN IF NOT EXISTS reply_to UUID REFERENCES lesson_chat_messages(id);
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_chat_message_edits (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                message_id UUID NOT NULL REFERENCES lesson_chat_messages(id) ON DELETE CASCADE,
+                previous_message TEXT NOT NULL,
+                edited_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_chat_reactions (
+                message_id UUID NOT NULL REFERENCES lesson_chat_messages(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                emoji VARCHAR(32) NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (message_id, user_id, emoji)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_chat_message_edits_message ON lesson_chat_message_edits(message_id, edited_at);"#
+        ).execute(&self.pool).await?;
+
+        // 31. Co-teachers and private messages in lessons
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS classroom_co_teachers (
+                classroom_id UUID NOT NULL REFERENCES classrooms(id),
+                teacher_id UUID NOT NULL REFERENCES users(id),
+                added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (classroom_id, teacher_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_private_messages (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                sender_id UUID NOT NULL REFERENCES users(id),
+                sender_name VARCHAR(100) NOT NULL,
+                sender_type user_type NOT NULL,
+                recipient_id UUID NOT NULL REFERENCES users(id),
+                recipient_name VARCHAR(100) NOT NULL,
+                message TEXT NOT NULL,
+                include_co_teachers BOOLEAN NOT NULL DEFAULT FALSE,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_private_messages_lesson ON lesson_private_messages(lesson_id, created_at);"#
+        ).execute(&self.pool).await?;
+
+        // 32. Raised hands (the live queue) and the speaking turns given from it
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_raised_hands (
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                raised_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (lesson_id, user_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_speaking_turns (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                called_by UUID NOT NULL REFERENCES users(id),
+                raised_at TIMESTAMPTZ,
+                called_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_speaking_turns_lesson ON lesson_speaking_turns(lesson_id, called_at);"#
+        ).execute(&self.pool).await?;
+
+        // 33. Polls and quizzes (a quiz is a poll with a correct option) and their answers
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_polls (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                created_by UUID NOT NULL REFERENCES users(id),
+                question TEXT NOT NULL,
+                options TEXT[] NOT NULL,
+                correct_option INTEGER,
+                revealed BOOLEAN NOT NULL DEFAULT FALSE,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                closed_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_poll_responses (
+                poll_id UUID NOT NULL REFERENCES lesson_polls(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                option INTEGER NOT NULL,
+                answered_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                PRIMARY KEY (poll_id, user_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_polls_lesson ON lesson_polls(lesson_id, created_at);"#
+        ).execute(&self.pool).await?;
+
+        // 34. Whiteboard op log, and whether students may draw
+        sqlx::query(
+            r#"ALTER TABLE lessons ADD COLUMN IF NOT EXISTS whiteboard_students_can_draw BOOLEAN NOT NULL DEFAULT FALSE;"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_whiteboard_ops (
+                seq BIGSERIAL PRIMARY KEY,
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                op JSONB NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_whiteboard_ops_lesson ON lesson_whiteboard_ops(lesson_id, seq);"#
+        ).execute(&self.pool).await?;
+
+        // Element ids are chosen by clients, so make sure each is only drawn once
+        sqlx::query(
+            r#"
+            CREATE UNIQUE INDEX IF NOT EXISTS idx_whiteboard_ops_element ON lesson_whiteboard_ops(lesson_id, (op->>'id'))
+            WHERE op->>'kind' IN ('stroke', 'shape');
+            "#
+        ).execute(&self.pool).await?;
+
+        // 35. Watch together: the video each lesson is playing and where it is
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_playback (
+                lesson_id UUID PRIMARY KEY REFERENCES lessons(id) ON DELETE CASCADE,
+                video_url TEXT NOT NULL,
+                playing BOOLEAN NOT NULL DEFAULT FALSE,
+                position DOUBLE PRECISION NOT NULL DEFAULT 0,
+                rate DOUBLE PRECISION NOT NULL DEFAULT 1,
+                updated_by UUID NOT NULL REFERENCES users(id),
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        // 36. Breakout rooms, who was put in each, and their chat
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_breakout_rooms (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                name VARCHAR(100) NOT NULL,
+                position INT NOT NULL,
+                opened_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                ends_at TIMESTAMPTZ,
+                closed_at TIMESTAMPTZ
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_breakout_rooms_lesson ON lesson_breakout_rooms(lesson_id, opened_at);"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_breakout_members (
+                room_id UUID NOT NULL REFERENCES lesson_breakout_rooms(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                PRIMARY KEY (room_id, user_id)
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_breakout_messages (
+                id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+                room_id UUID NOT NULL REFERENCES lesson_breakout_rooms(id) ON DELETE CASCADE,
+                user_id UUID NOT NULL REFERENCES users(id),
+                username VARCHAR(100) NOT NULL,
+                user_type user_type NOT NULL,
+                message TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_breakout_messages_room ON lesson_breakout_messages(room_id, created_at);"#
+        ).execute(&self.pool).await?;
+
+        // 37. Moderation actions taken in each lesson, for transcripts
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS lesson_moderation_log (
+                id BIGSERIAL PRIMARY KEY,
+                lesson_id UUID NOT NULL REFERENCES lessons(id) ON DELETE CASCADE,
+                moderator_id UUID NOT NULL REFERENCES users(id),
+                event JSONB NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_moderation_log_lesson ON lesson_moderation_log(lesson_id, created_at);"#
+        ).execute(&self.pool).await?;
+
+        // 38. Room presence, kept here so every instance sees the same rooms
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS room_connections (
+                id UUID PRIMARY KEY,
+                room JSONB NOT NULL,
+                user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                username VARCHAR(100) NOT NULL,
+                user_type user_type NOT NULL,
+                joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                idle BOOLEAN NOT NULL DEFAULT FALSE
+            );
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_room_connections_room ON room_connections(room, user_id);"#
+        ).execute(&self.pool).await?;
+
+        // 39. Flags raised in breakout rooms point at the room the message is in
+        sqlx::query(
+            r#"ALTER TABLE flagged_chat_messages ADD COLUMN IF NOT EXISTS breakout_room_id UUID REFERENCES lesson_breakout_rooms(id) ON DELETE CASCADE;"#
+        ).execute(&self.pool).await?;
+
+        // 40. Deleted breakout messages are kept, with who deleted them, and hidden from students
+        sqlx::query(
+            r#"
+            ALTER TABLE lesson_breakout_messages
+                ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ,
+                ADD COLUMN IF NOT EXISTS deleted_by UUID REFERENCES users(id);
+            "#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_attendance_sessions_lesson ON lesson_attendance_sessions(lesson_id, user_id);"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_lesson_changes_lesson ON lesson_changes(lesson_id, created_at);"#
+        ).execute(&self.pool).await?;
+
+        sqlx::query(
+            r#"CREATE INDEX IF NOT EXISTS idx_notifications_user ON notifications(user_id, created_at DESC);"#
+        ).execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    pub async fn create_user(&self, user: &User) -> anyhow::Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, email, password_hash, user_type, first_name, last_name, time_zone)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#
+        )
+        .bind(user.id)
+        .bind(&user.email)
+        .bind(&user.password_hash)
+        .bind(&user.user_type) // bind as enum, not string
+        .bind(&user.first_name)
+        .bind(&user.last_name)
+        .bind(&user.time_zone)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_user_by_email(&self, email: &str) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"SELECT id, email, password_hash, user_type, 
+               first_name, last_name, created_at, updated_at, is_active,
+               zoom_access_token, zoom_refresh_token, zoom_token_expiry, time_zone
+               FROM users WHERE email = $1"#
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    pub async fn get_user_by_id(&self, user_id: Uuid) -> anyhow::Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            r#"SELECT id, email, password_hash, user_type,
+               first_name, last_name, created_at, updated_at, is_active,
+               zoom_access_token, zoom_refresh_token, zoom_token_expiry, time_zone
+               FROM users WHERE id = $1"#
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    pub async fn update_user_time_zone(&self, user_id: Uuid, time_zone: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE users SET time_zone = $1, updated_at = NOW() WHERE id = $2"
+        )
+        .bind(time_zone)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_classrooms_by_teacher(&self, teacher_id: Uuid) -> anyhow::Result<Vec<Classroom>> {
+        let classrooms = sqlx::query_as::<_, Classroom>(
+            "SELECT * FROM classrooms WHERE teacher_id = $1 AND is_active = TRUE"
+        )
+        .bind(teacher_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(classrooms)
+    }
+
+    // --- Zoom meeting management ---
+
+    pub async fn set_classroom_zoom_meeting(
+        &self,
+        classroom_id: Uuid,
+        meeting_id: &str,
+        join_url: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE classrooms SET zoom_meeting_id = $1, zoom_join_url = $2 WHERE id = $3"
+        )
+        .bind(meeting_id)
+        .bind(join_url)
+        .bind(classroom_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn clear_classroom_zoom_meeting(&self, classroom_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE classrooms SET zoom_meeting_id = NULL, zoom_join_url = NULL WHERE id = $1"
+        )
+        .bind(classroom_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // --- Meeting request management ---
+
+    pub async fn create_meeting_request(
+        &self,
+        classroom_id: Uuid,
+        student_id: Uuid,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO meeting_requests (id, classroom_id, student_id) VALUES (uuid_generate_v4(), $1, $2)"
+        )
+        .bind(classroom_id)
+        .bind(student_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_pending_meeting_requests(
+        &self,
+        classroom_id: Uuid,
+    ) -> anyhow::Result<Vec<crate::models::MeetingRequest>> {
+        let requests = sqlx::query_as::<_, crate::models::MeetingRequest>(
+            "SELECT * FROM meeting_requests WHERE classroom_id = $1 AND status = 'pending'"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(requests)
+    }
+
+    pub async fn update_meeting_request_status(
+        &self,
+        request_id: Uuid,
+        status: &str,
+    ) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE meeting_requests SET status = $1 WHERE id = $2"
+        )
+        .bind(status)
+        .bind(request_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Lesson CRUD
+    pub async fn create_lesson(&self, lesson: &crate::models::Lesson) -> anyhow::Result<()> {
+        Self::write_new_lesson(&self.pool, lesson).await
+    }
+
+    async fn write_new_lesson<'e>(executor: impl sqlx::PgExecutor<'e>, lesson: &crate::models::Lesson) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lessons (id, classroom_id, teacher_id, title, description, scheduled_at, status, chat_closed, created_at,
+                                  cancellation_reason, series_id, occurrence_start, duration_minutes)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13)"
+        )
+        .bind(lesson.id)
+        .bind(lesson.classroom_id)
+        .bind(lesson.teacher_id)
+        .bind(&lesson.title)
+        .bind(&lesson.description)
+        .bind(lesson.scheduled_at)
+        .bind(lesson.status)
+        .bind(lesson.chat_closed)
+        .bind(lesson.created_at)
+        .bind(&lesson.cancellation_reason)
+        .bind(lesson.series_id)
+        .bind(lesson.occurrence_start)
+        .bind(lesson.duration_minutes)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_lesson(&self, lesson: &crate::models::Lesson) -> anyhow::Result<()> {
+        Self::write_lesson_update(&self.pool, lesson).await
+    }
+
+    async fn write_lesson_update<'e>(executor: impl sqlx::PgExecutor<'e>, lesson: &crate::models::Lesson) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE lessons SET title = $1, description = $2, scheduled_at = $3, status = $4, cancellation_reason = $5,
+                                series_id = $6, occurrence_start = $7, duration_minutes = $8
+             WHERE id = $9"
+        )
+        .bind(&lesson.title)
+        .bind(&lesson.description)
+        .bind(lesson.scheduled_at)
+        .bind(lesson.status)
+        .bind(&lesson.cancellation_reason)
+        .bind(lesson.series_id)
+        .bind(lesson.occurrence_start)
+        .bind(lesson.duration_minutes)
+        .bind(lesson.id)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    // Lesson series
+    // A new series and its lessons, all or nothing
+    pub async fn create_lesson_series(
+        &self,
+        series: &crate::models::LessonSeries,
+        lessons: &[crate::models::Lesson],
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::write_new_lesson_series(&mut *tx, series).await?;
+        for lesson in lessons {
+            Self::write_new_lesson(&mut *tx, lesson).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn write_new_lesson_series<'e>(executor: impl sqlx::PgExecutor<'e>, series: &crate::models::LessonSeries) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_series (id, classroom_id, teacher_id, title, description, starts_at, duration_minutes, time_zone,
+                                        rrule, exception_dates, created_at)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11)"
+        )
+        .bind(series.id)
+        .bind(series.classroom_id)
+        .bind(series.teacher_id)
+        .bind(&series.title)
+        .bind(&series.description)
+        .bind(series.starts_at)
+        .bind(series.duration_minutes)
+        .bind(&series.time_zone)
+        .bind(&series.rrule)
+        .bind(&series.exception_dates)
+        .bind(series.created_at)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_lesson_series(&self, series: &crate::models::LessonSeries) -> anyhow::Result<()> {
+        Self::write_lesson_series_update(&self.pool, series).await
+    }
+
+    async fn write_lesson_series_update<'e>(executor: impl sqlx::PgExecutor<'e>, series: &crate::models::LessonSeries) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE lesson_series SET title = $1, description = $2, starts_at = $3, duration_minutes = $4, rrule = $5,
+                                      exception_dates = $6
+             WHERE id = $7"
+        )
+        .bind(&series.title)
+        .bind(&series.description)
+        .bind(series.starts_at)
+        .bind(series.duration_minutes)
+        .bind(&series.rrule)
+        .bind(&series.exception_dates)
+        .bind(series.id)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_lesson_series(&self, series_id: Uuid) -> anyhow::Result<Option<crate::models::LessonSeries>> {
+        let series = sqlx::query_as::<_, crate::models::LessonSeries>(
+            "SELECT * FROM lesson_series WHERE id = $1"
+        )
+        .bind(series_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(series)
+    }
+
+    pub async fn get_series_lessons(&self, series_id: Uuid) -> anyhow::Result<Vec<crate::models::Lesson>> {
+        let lessons = sqlx::query_as::<_, crate::models::Lesson>(
+            "SELECT * FROM lessons WHERE series_id = $1 ORDER BY occurrence_start"
+        )
+        .bind(series_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(lessons)
+    }
+
+    // Everything a "this and following" edit writes, all or nothing: the series
+    // (and the one split off from it, if any), the lessons moved onto it and
+    // their change history
+    pub async fn save_series_edit(
+        &self,
+        series: &crate::models::LessonSeries,
+        split_off: Option<&crate::models::LessonSeries>,
+        lessons: &[crate::models::Lesson],
+        changes: &[crate::models::LessonChange],
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(following) = split_off {
+            Self::write_new_lesson_series(&mut *tx, following).await?;
+        }
+        Self::write_lesson_series_update(&mut *tx, series).await?;
+        for lesson in lessons {
+            Self::write_lesson_update(&mut *tx, lesson).await?;
+        }
+        for change in changes {
+            Self::write_lesson_change(&mut *tx, change).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn add_lesson_change(&self, change: &crate::models::LessonChange) -> anyhow::Result<()> {
+        Self::write_lesson_change(&self.pool, change).await
+    }
+
+    async fn write_lesson_change<'e>(executor: impl sqlx::PgExecutor<'e>, change: &crate::models::LessonChange) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_changes (id, lesson_id, changed_by, action, details, created_at)
+             VALUES ($1,$2,$3,$4,$5,$6)"
+        )
+        .bind(change.id)
+        .bind(change.lesson_id)
+        .bind(change.changed_by)
+        .bind(&change.action)
+        .bind(&change.details)
+        .bind(change.created_at)
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_lesson_changes(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonChange>> {
+        let changes = sqlx::query_as::<_, crate::models::LessonChange>(
+            "SELECT * FROM lesson_changes WHERE lesson_id = $1 ORDER BY created_at"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(changes)
+    }
+
+    // Students who should hear about changes to a lesson: everyone enrolled in
+    // its classroom plus anyone already registered as a participant.
+    pub async fn get_lesson_audience(&self, lesson_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+            "SELECT e.student_id AS user_id FROM classroom_enrollments e
+             JOIN lessons l ON l.classroom_id = e.classroom_id
+             WHERE l.id = $1
+             UNION
+             SELECT p.user_id FROM lesson_participants p
+             JOIN lessons l ON l.id = p.lesson_id
+             WHERE p.lesson_id = $1 AND p.user_id <> l.teacher_id"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.iter().map(|r| r.get::<Uuid, _>("user_id")).collect())
+    }
+
+    // Classroom enrollment
+    pub async fn get_classroom(&self, classroom_id: Uuid) -> anyhow::Result<Option<Classroom>> {
+        let classroom = sqlx::query_as::<_, Classroom>(
+            "SELECT * FROM classrooms WHERE id = $1"
+        )
+        .bind(classroom_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(classroom)
+    }
+
+    pub async fn enroll_student(&self, classroom_id: Uuid, student_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO classroom_enrollments (classroom_id, student_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        )
+        .bind(classroom_id)
+        .bind(student_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn unenroll_student(&self, classroom_id: Uuid, student_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "DELETE FROM classroom_enrollments WHERE classroom_id = $1 AND student_id = $2"
+        )
+        .bind(classroom_id)
+        .bind(student_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_classroom_enrollments(&self, classroom_id: Uuid) -> anyhow::Result<Vec<crate::models::ClassroomEnrollment>> {
+        let enrollments = sqlx::query_as::<_, crate::models::ClassroomEnrollment>(
+            "SELECT * FROM classroom_enrollments WHERE classroom_id = $1 ORDER BY enrolled_at"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(enrollments)
+    }
+
+    pub async fn is_enrolled(&self, classroom_id: Uuid, student_id: Uuid) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "SELECT EXISTS (SELECT 1 FROM classroom_enrollments WHERE classroom_id = $1 AND student_id = $2) AS enrolled"
+        )
+        .bind(classroom_id)
+        .bind(student_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<bool, _>("enrolled"))
+    }
+
+    pub async fn add_co_teacher(&self, classroom_id: Uuid, teacher_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO classroom_co_teachers (classroom_id, teacher_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        )
+        .bind(classroom_id)
+        .bind(teacher_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_co_teacher(&self, classroom_id: Uuid, teacher_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM classroom_co_teachers WHERE classroom_id = $1 AND teacher_id = $2")
+            .bind(classroom_id)
+            .bind(teacher_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_co_teachers(&self, classroom_id: Uuid) -> anyhow::Result<Vec<crate::models::ClassroomCoTeacher>> {
+        let co_teachers = sqlx::query_as::<_, crate::models::ClassroomCoTeacher>(
+            "SELECT * FROM classroom_co_teachers WHERE classroom_id = $1 ORDER BY added_at"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(co_teachers)
+    }
+
+    pub async fn is_co_teacher(&self, classroom_id: Uuid, teacher_id: Uuid) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "SELECT EXISTS (SELECT 1 FROM classroom_co_teachers WHERE classroom_id = $1 AND teacher_id = $2) AS co_teacher"
+        )
+        .bind(classroom_id)
+        .bind(teacher_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<bool, _>("co_teacher"))
+    }
+
+    // Everyone teaching a lesson: its teacher, then the classroom's co-teachers
+    pub async fn get_lesson_teacher_ids(&self, lesson_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let rows = sqlx::query(
+            "SELECT teacher_id FROM (
+                 SELECT l.teacher_id, NULL::TIMESTAMPTZ AS added_at FROM lessons l WHERE l.id = $1
+                 UNION
+                 SELECT c.teacher_id, c.added_at FROM lessons l
+                 JOIN classroom_co_teachers c ON c.classroom_id = l.classroom_id
+                 WHERE l.id = $1
+             ) t
+             ORDER BY added_at NULLS FIRST"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|r| r.get::<Uuid, _>("teacher_id")).collect())
+    }
+
+    // Whether two users share a classroom that at least one of them teaches or
+    // co-teaches; two students in the same class don't count
+    pub async fn share_classroom(&self, a: Uuid, b: Uuid) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "WITH members AS (
+                 SELECT id AS classroom_id, teacher_id AS user_id, TRUE AS teaches FROM classrooms
+                 UNION ALL
+                 SELECT classroom_id, student_id, FALSE FROM classroom_enrollments
+                 UNION ALL
+                 SELECT classroom_id, teacher_id, TRUE FROM classroom_co_teachers
+             )
+             SELECT EXISTS (
+                 SELECT 1 FROM members x JOIN members y ON x.classroom_id = y.classroom_id
+                 WHERE x.user_id = $1 AND y.user_id = $2 AND (x.teaches OR y.teaches)
+             ) AS shared"
+        )
+        .bind(a)
+        .bind(b)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<bool, _>("shared"))
+    }
+
+    // A user belongs to a lesson if they teach it (or co-teach its classroom),
+    // are enrolled in its classroom or have joined it
+    pub async fn is_lesson_member(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<bool> {
+        let row = sqlx::query(
+            "SELECT EXISTS (
+                SELECT 1 FROM lessons l
+                WHERE l.id = $1 AND (
+                    l.teacher_id = $2
+                    OR EXISTS (SELECT 1 FROM classroom_co_teachers c WHERE c.classroom_id = l.classroom_id AND c.teacher_id = $2)
+                    OR EXISTS (SELECT 1 FROM classroom_enrollments e WHERE e.classroom_id = l.classroom_id AND e.student_id = $2)
+                    OR EXISTS (SELECT 1 FROM lesson_participants p WHERE p.lesson_id = l.id AND p.user_id = $2)
+                )
+            ) AS member"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<bool, _>("member"))
+    }
+
+    pub async fn get_lesson_members(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonMember>> {
+        let members = sqlx::query_as::<_, crate::models::LessonMember>(
+            "SELECT u.id AS user_id, u.first_name, u.last_name, u.user_type FROM users u
+             WHERE u.id IN (
+                 SELECT l.teacher_id FROM lessons l WHERE l.id = $1
+                 UNION
+                 SELECT e.student_id FROM classroom_enrollments e
+                 JOIN lessons l ON l.classroom_id = e.classroom_id WHERE l.id = $1
+                 UNION
+                 SELECT p.user_id FROM lesson_participants p WHERE p.lesson_id = $1
+             )
+             ORDER BY u.last_name, u.first_name"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(members)
+    }
+
+    // The lesson's teachers and every student who has been in its room
+    pub async fn get_lesson_attendees(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonMember>> {
+        let attendees = sqlx::query_as::<_, crate::models::LessonMember>(
+            "SELECT u.id AS user_id, u.first_name, u.last_name, u.user_type FROM users u
+             WHERE u.id IN (
+                 SELECT l.teacher_id FROM lessons l WHERE l.id = $1
+                 UNION
+                 SELECT c.teacher_id FROM classroom_co_teachers c
+                 JOIN lessons l ON l.classroom_id = c.classroom_id WHERE l.id = $1
+                 UNION
+                 SELECT p.user_id FROM lesson_participants p WHERE p.lesson_id = $1
+             )
+             ORDER BY u.last_name, u.first_name"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(attendees)
+    }
+
+    // Lesson plans
+    pub async fn create_lesson_plan(&self, plan: &crate::models::LessonPlan) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_plans (id, teacher_id, lesson_id, source_plan_id, title, level, objectives, vocabulary,
+                                       grammar_points, activities, materials, created_at, updated_at)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13)"
+        )
+        .bind(plan.id)
+        .bind(plan.teacher_id)
+        .bind(plan.lesson_id)
+        .bind(plan.source_plan_id)
+        .bind(&plan.title)
+        .bind(&plan.level)
+        .bind(&plan.objectives)
+        .bind(&plan.vocabulary)
+        .bind(&plan.grammar_points)
+        .bind(&plan.activities)
+        .bind(&plan.materials)
+        .bind(plan.created_at)
+        .bind(plan.updated_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_lesson_plan(&self, plan: &crate::models::LessonPlan) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE lesson_plans
+             SET title = $2, level = $3, objectives = $4, vocabulary = $5, grammar_points = $6,
+                 activities = $7, materials = $8, updated_at = $9
+             WHERE id = $1"
+        )
+        .bind(plan.id)
+        .bind(&plan.title)
+        .bind(&plan.level)
+        .bind(&plan.objectives)
+        .bind(&plan.vocabulary)
+        .bind(&plan.grammar_points)
+        .bind(&plan.activities)
+        .bind(&plan.materials)
+        .bind(plan.updated_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_lesson_plan(&self, plan_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM lesson_plans WHERE id = $1")
+            .bind(plan_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_lesson_plan(&self, plan_id: Uuid) -> anyhow::Result<Option<crate::models::LessonPlan>> {
+        let plan = sqlx::query_as::<_, crate::models::LessonPlan>(
+            "SELECT * FROM lesson_plans WHERE id = $1"
+        )
+        .bind(plan_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(plan)
+    }
+
+    pub async fn get_lesson_plan_for_lesson(&self, lesson_id: Uuid) -> anyhow::Result<Option<crate::models::LessonPlan>> {
+        let plan = sqlx::query_as::<_, crate::models::LessonPlan>(
+            "SELECT * FROM lesson_plans WHERE lesson_id = $1"
+        )
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(plan)
+    }
+
+    pub async fn get_lesson_plans_by_teacher(
+        &self,
+        teacher_id: Uuid,
+        templates_only: bool,
+    ) -> anyhow::Result<Vec<crate::models::LessonPlan>> {
+        let plans = sqlx::query_as::<_, crate::models::LessonPlan>(
+            "SELECT * FROM lesson_plans
+             WHERE teacher_id = $1 AND (NOT $2 OR lesson_id IS NULL)
+             ORDER BY updated_at DESC"
+        )
+        .bind(teacher_id)
+        .bind(templates_only)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(plans)
+    }
+
+    pub async fn get_book(&self, book_id: Uuid) -> anyhow::Result<Option<DigitalBook>> {
+        let book = sqlx::query_as::<_, DigitalBook>(
+            "SELECT * FROM digital_books WHERE id = $1"
+        )
+        .bind(book_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(book)
+    }
+
+    // Reminders
+    pub async fn get_reminder_preferences(&self, user_id: Uuid) -> anyhow::Result<Option<crate::models::ReminderPreferences>> {
+        let prefs = sqlx::query_as::<_, crate::models::ReminderPreferences>(
+            "SELECT offsets_minutes, channels FROM reminder_preferences WHERE user_id = $1"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(prefs)
+    }
+
+    pub async fn set_reminder_preferences(&self, user_id: Uuid, prefs: &crate::models::ReminderPreferences) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO reminder_preferences (user_id, offsets_minutes, channels, updated_at)
+             VALUES ($1, $2, $3, NOW())
+             ON CONFLICT (user_id) DO UPDATE
+             SET offsets_minutes = EXCLUDED.offsets_minutes, channels = EXCLUDED.channels, updated_at = NOW()"
+        )
+        .bind(user_id)
+        .bind(&prefs.offsets_minutes)
+        .bind(&prefs.channels)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Every (lesson, member, offset) whose reminder time has passed but which has
+    // not been sent yet for the lesson's current start time. Users without a
+    // preferences row get the defaults.
+    pub async fn get_due_reminders(
+        &self,
+        default_offsets: &[i32],
+        default_channels: &[String],
+    ) -> anyhow::Result<Vec<crate::models::DueReminder>> {
+        let due = sqlx::query_as::<_, crate::models::DueReminder>(
+            "WITH members AS (
+                 SELECT l.id AS lesson_id, l.teacher_id AS user_id FROM lessons l
+                 WHERE l.status = 'scheduled' AND l.scheduled_at > NOW()
+                 UNION
+                 SELECT l.id, e.student_id FROM lessons l
+                 JOIN classroom_enrollments e ON e.classroom_id = l.classroom_id
+                 WHERE l.status = 'scheduled' AND l.scheduled_at > NOW()
+                 UNION
+                 SELECT l.id, p.user_id FROM lessons l
+                 JOIN lesson_participants p ON p.lesson_id = l.id
+                 WHERE l.status = 'scheduled' AND l.scheduled_at > NOW()
+             )
+             SELECT m.lesson_id, m.user_id, o.offset_minutes, l.scheduled_at,
+                    COALESCE(rp.channels, $2) AS channels
+             FROM members m
+             JOIN lessons l ON l.id = m.lesson_id
+             LEFT JOIN reminder_preferences rp ON rp.user_id = m.user_id
+             CROSS JOIN LATERAL unnest(COALESCE(rp.offsets_minutes, $1)) AS o(offset_minutes)
+             WHERE l.scheduled_at - make_interval(mins => o.offset_minutes) <= NOW()
+               AND NOT EXISTS (
+                   SELECT 1 FROM lesson_reminders r
+                   WHERE r.lesson_id = m.lesson_id AND r.user_id = m.user_id
+                     AND r.offset_minutes = o.offset_minutes AND r.scheduled_for = l.scheduled_at
+               )
+             ORDER BY m.lesson_id, m.user_id, o.offset_minutes"
+        )
+        .bind(default_offsets)
+        .bind(default_channels)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(due)
+    }
+
+    // Returns false if the reminder was already claimed, e.g. by another instance
+    pub async fn claim_reminder(
+        &self,
+        lesson_id: Uuid,
+        user_id: Uuid,
+        offset_minutes: i32,
+        scheduled_for: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO lesson_reminders (lesson_id, user_id, offset_minutes, scheduled_for, sent_at)
+             VALUES ($1, $2, $3, $4, NOW())
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .bind(offset_minutes)
+        .bind(scheduled_for)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    // Attendance
+    pub async fn start_attendance_session(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO lesson_attendance_sessions (id, lesson_id, user_id, joined_at) VALUES ($1, $2, $3, NOW())"
+        )
+        .bind(id)
+        .bind(lesson_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(id)
+    }
+
+    pub async fn end_attendance_session(&self, session_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE lesson_attendance_sessions SET left_at = NOW() WHERE id = $1 AND left_at IS NULL"
+        )
+        .bind(session_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_attendance_sessions(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::AttendanceSession>> {
+        let sessions = sqlx::query_as::<_, crate::models::AttendanceSession>(
+            "SELECT * FROM lesson_attendance_sessions WHERE lesson_id = $1 ORDER BY joined_at"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(sessions)
+    }
+
+    pub async fn get_attendance_overrides(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::AttendanceOverride>> {
+        let overrides = sqlx::query_as::<_, crate::models::AttendanceOverride>(
+            "SELECT * FROM lesson_attendance_overrides WHERE lesson_id = $1"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(overrides)
+    }
+
+    pub async fn set_attendance_override(&self, o: &crate::models::AttendanceOverride) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_attendance_overrides (lesson_id, user_id, status, note, set_by, set_at)
+             VALUES ($1,$2,$3,$4,$5,$6)
+             ON CONFLICT (lesson_id, user_id)
+             DO UPDATE SET status = EXCLUDED.status, note = EXCLUDED.note, set_by = EXCLUDED.set_by, set_at = EXCLUDED.set_at"
+        )
+        .bind(o.lesson_id)
+        .bind(o.user_id)
+        .bind(o.status)
+        .bind(&o.note)
+        .bind(o.set_by)
+        .bind(o.set_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn clear_attendance_override(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "DELETE FROM lesson_attendance_overrides WHERE lesson_id = $1 AND user_id = $2"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_lessons_by_classroom(&self, classroom_id: Uuid) -> anyhow::Result<Vec<crate::models::Lesson>> {
+        let lessons = sqlx::query_as::<_, crate::models::Lesson>(
+            "SELECT * FROM lessons WHERE classroom_id = $1 ORDER BY scheduled_at"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(lessons)
+    }
+
+    // Calendar feeds: every lesson the user belongs to, see is_lesson_member
+    pub async fn get_calendar_entries_for_user(
+        &self,
+        user_id: Uuid,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<Vec<crate::models::CalendarEntry>> {
+        let entries = sqlx::query_as::<_, crate::models::CalendarEntry>(
+            "SELECT l.*, c.zoom_join_url FROM lessons l
+             JOIN classrooms c ON c.id = l.classroom_id
+             WHERE l.scheduled_at >= $2 AND (
+                 l.teacher_id = $1
+                 OR EXISTS (SELECT 1 FROM classroom_enrollments e WHERE e.classroom_id = l.classroom_id AND e.student_id = $1)
+                 OR EXISTS (SELECT 1 FROM lesson_participants p WHERE p.lesson_id = l.id AND p.user_id = $1)
+             )
+             ORDER BY l.scheduled_at"
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(entries)
+    }
+
+    pub async fn get_calendar_entry(&self, lesson_id: Uuid) -> anyhow::Result<Option<crate::models::CalendarEntry>> {
+        let entry = sqlx::query_as::<_, crate::models::CalendarEntry>(
+            "SELECT l.*, c.zoom_join_url FROM lessons l
+             JOIN classrooms c ON c.id = l.classroom_id
+             WHERE l.id = $1"
+        )
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(entry)
+    }
+
+    pub async fn create_feed_token(&self, token: &crate::models::CalendarFeedToken) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO calendar_feed_tokens (id, user_id, token, created_at) VALUES ($1, $2, $3, $4)"
+        )
+        .bind(token.id)
+        .bind(token.user_id)
+        .bind(&token.token)
+        .bind(token.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_active_feed_token(&self, user_id: Uuid) -> anyhow::Result<Option<crate::models::CalendarFeedToken>> {
+        let token = sqlx::query_as::<_, crate::models::CalendarFeedToken>(
+            "SELECT * FROM calendar_feed_tokens WHERE user_id = $1 AND revoked_at IS NULL
+             ORDER BY created_at DESC LIMIT 1"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    pub async fn revoke_feed_tokens(&self, user_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE calendar_feed_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL"
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_feed_token_owner(&self, token: &str) -> anyhow::Result<Option<Uuid>> {
+        let row = sqlx::query(
+            "SELECT user_id FROM calendar_feed_tokens WHERE token = $1 AND revoked_at IS NULL"
+        )
+        .bind(token)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| r.get::<Uuid, _>("user_id")))
+    }
+
+    // Notifications
+    pub async fn create_notification(&self, notification: &crate::models::Notification) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO notifications (id, user_id, kind, title, body, lesson_id, created_at, read_at)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8)"
+        )
+        .bind(notification.id)
+        .bind(notification.user_id)
+        .bind(&notification.kind)
+        .bind(&notification.title)
+        .bind(&notification.body)
+        .bind(notification.lesson_id)
+        .bind(notification.created_at)
+        .bind(notification.read_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_notifications_for_user(&self, user_id: Uuid) -> anyhow::Result<Vec<crate::models::Notification>> {
+        let notifications = sqlx::query_as::<_, crate::models::Notification>(
+            "SELECT * FROM notifications WHERE user_id = $1 ORDER BY created_at DESC LIMIT 100"
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(notifications)
+    }
+
+    pub async fn mark_notification_read(&self, notification_id: Uuid, user_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE notifications SET read_at = NOW() WHERE id = $1 AND user_id = $2 AND read_at IS NULL"
+        )
+        .bind(notification_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_lesson(&self, lesson_id: Uuid) -> anyhow::Result<Option<crate::models::Lesson>> {
+        let lesson = sqlx::query_as::<_, crate::models::Lesson>(
+            "SELECT * FROM lessons WHERE id = $1"
+        )
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(lesson)
+    }
+
+    pub async fn create_classroom(&self, classroom: &crate::models::Classroom) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO classrooms (id, name, description, teacher_id, is_active, created_at, time_zone)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)"
+        )
+        .bind(classroom.id)
+        .bind(&classroom.name)
+        .bind(&classroom.description)
+        .bind(classroom.teacher_id)
+        .bind(classroom.is_active)
+        .bind(classroom.created_at)
+        .bind(&classroom.time_zone)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn update_classroom(&self, classroom: &crate::models::Classroom) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE classrooms SET name = $1, description = $2, time_zone = $3 WHERE id = $4"
+        )
+        .bind(&classroom.name)
+        .bind(&classroom.description)
+        .bind(&classroom.time_zone)
+        .bind(classroom.id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Lesson participants
+    pub async fn add_lesson_participant(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_participants (lesson_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_participant_muted(&self, lesson_id: Uuid, user_id: Uuid, muted: bool) -> anyhow::Result<()> {
+        sqlx::query(
+            "UPDATE lesson_participants SET is_muted = $1 WHERE lesson_id = $2 AND user_id = $3"
+        )
+        .bind(muted)
+        .bind(lesson_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Lesson chat
+    // Returns the sequence number assigned to the message
+    pub async fn add_chat_message(&self, msg: &crate::models::LessonChatMessage) -> anyhow::Result<i64> {
+        let row = sqlx::query(
+            "INSERT INTO lesson_chat_messages (id, lesson_id, user_id, username, user_type, message, timestamp, deleted, reply_to)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)
+             RETURNING seq"
+        )
+        .bind(msg.id)
+        .bind(msg.lesson_id)
+        .bind(msg.user_id)
+        .bind(&msg.username)
+        .bind(&msg.user_type)
+        .bind(&msg.message)
+        .bind(msg.timestamp)
+        .bind(msg.deleted)
+        .bind(msg.reply_to)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get::<i64, _>("seq"))
+    }
+
+    // A message of the lesson that hasn't been deleted
+    pub async fn get_chat_message(&self, lesson_id: Uuid, message_id: Uuid) -> anyhow::Result<Option<crate::models::LessonChatMessage>> {
+        let message = sqlx::query_as::<_, crate::models::LessonChatMessage>(
+            "SELECT * FROM lesson_chat_messages WHERE id = $1 AND lesson_id = $2 AND NOT deleted"
+        )
+        .bind(message_id)
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(message)
+    }
+
+    // Replaces a message's text, keeping the previous version
+    pub async fn edit_chat_message(
+        &self,
+        message_id: Uuid,
+        message: &str,
+        edited_at: chrono::DateTime<chrono::Utc>,
+    ) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO lesson_chat_message_edits (message_id, previous_message, edited_at)
+             SELECT id, message, $2 FROM lesson_chat_messages WHERE id = $1"
+        )
+        .bind(message_id)
+        .bind(edited_at)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE lesson_chat_messages SET message = $2, edited_at = $3 WHERE id = $1")
+            .bind(message_id)
+            .bind(message)
+            .bind(edited_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_chat_message_edits(&self, message_id: Uuid) -> anyhow::Result<Vec<crate::models::ChatMessageEdit>> {
+        let edits = sqlx::query_as::<_, crate::models::ChatMessageEdit>(
+            "SELECT previous_message, edited_at FROM lesson_chat_message_edits WHERE message_id = $1 ORDER BY edited_at"
+        )
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(edits)
+    }
+
+    pub async fn add_chat_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_chat_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_chat_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM lesson_chat_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3")
+            .bind(message_id)
+            .bind(user_id)
+            .bind(emoji)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Reactions to any of `message_ids`, oldest first
+    pub async fn get_chat_reactions(&self, message_ids: &[Uuid]) -> anyhow::Result<Vec<crate::models::ChatReaction>> {
+        let reactions = sqlx::query_as::<_, crate::models::ChatReaction>(
+            "SELECT message_id, user_id, emoji FROM lesson_chat_reactions
+             WHERE message_id = ANY($1) ORDER BY created_at"
+        )
+        .bind(message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(reactions)
+    }
+
+    // Puts the user's hand up at the back of the queue; false if it was already up
+    pub async fn raise_hand(&self, lesson_id: Uuid, user_id: Uuid, username: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO lesson_raised_hands (lesson_id, user_id, username) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // False if the hand wasn't up
+    pub async fn lower_hand(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM lesson_raised_hands WHERE lesson_id = $1 AND user_id = $2")
+            .bind(lesson_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn clear_hands(&self, lesson_id: Uuid) -> anyhow::Result<u64> {
+        let result = sqlx::query("DELETE FROM lesson_raised_hands WHERE lesson_id = $1")
+            .bind(lesson_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // The queue in the order hands went up, with the turns each person has had
+    // in this lesson so far
+    pub async fn get_raised_hands(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::RaisedHand>> {
+        let hands = sqlx::query_as::<_, crate::models::RaisedHand>(
+            "SELECT h.user_id, h.username, h.raised_at,
+                    (SELECT COUNT(*) FROM lesson_speaking_turns t WHERE t.lesson_id = h.lesson_id AND t.user_id = h.user_id) AS turns
+             FROM lesson_raised_hands h
+             WHERE h.lesson_id = $1
+             ORDER BY h.raised_at, h.user_id"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(hands)
+    }
+
+    // Gives someone the floor: takes their hand out of the queue, if it was up,
+    // and logs the turn
+    pub async fn call_on(
+        &self,
+        lesson_id: Uuid,
+        user_id: Uuid,
+        username: &str,
+        called_by: Uuid,
+    ) -> anyhow::Result<crate::models::SpeakingTurn> {
+        let mut tx = self.pool.begin().await?;
+        let raised_at: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar(
+            "DELETE FROM lesson_raised_hands WHERE lesson_id = $1 AND user_id = $2 RETURNING raised_at"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let turn = sqlx::query_as::<_, crate::models::SpeakingTurn>(
+            "INSERT INTO lesson_speaking_turns (lesson_id, user_id, username, called_by, raised_at)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING *"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .bind(username)
+        .bind(called_by)
+        .bind(raised_at)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(turn)
+    }
+
+    pub async fn get_speaking_turns(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::SpeakingTurn>> {
+        let turns = sqlx::query_as::<_, crate::models::SpeakingTurn>(
+            "SELECT * FROM lesson_speaking_turns WHERE lesson_id = $1 ORDER BY called_at"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(turns)
+    }
+
+    // Speaking turns per enrolled student across the classroom's lessons,
+    // fewest first, so the teacher can see who hasn't been heard from
+    pub async fn get_classroom_participation(&self, classroom_id: Uuid) -> anyhow::Result<Vec<crate::models::ParticipationSummary>> {
+        let summary = sqlx::query_as::<_, crate::models::ParticipationSummary>(
+            "SELECT u.id AS user_id, u.first_name, u.last_name,
+                    COUNT(t.id) AS turns,
+                    COUNT(t.raised_at) AS turns_from_raised_hand,
+                    MAX(t.called_at) AS last_called_at
+             FROM classroom_enrollments e
+             JOIN users u ON u.id = e.student_id
+             LEFT JOIN lessons l ON l.classroom_id = e.classroom_id
+             LEFT JOIN lesson_speaking_turns t ON t.lesson_id = l.id AND t.user_id = e.student_id
+             WHERE e.classroom_id = $1
+             GROUP BY u.id, u.first_name, u.last_name
+             ORDER BY turns, last_called_at NULLS FIRST, u.last_name, u.first_name"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(summary)
+    }
+
+    pub async fn create_poll(&self, poll: &crate::models::LessonPoll) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_polls (id, lesson_id, created_by, question, options, correct_option, revealed, created_at, closed_at)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)"
+        )
+        .bind(poll.id)
+        .bind(poll.lesson_id)
+        .bind(poll.created_by)
+        .bind(&poll.question)
+        .bind(&poll.options)
+        .bind(poll.correct_option)
+        .bind(poll.revealed)
+        .bind(poll.created_at)
+        .bind(poll.closed_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_poll(&self, lesson_id: Uuid, poll_id: Uuid) -> anyhow::Result<Option<crate::models::LessonPoll>> {
+        let poll = sqlx::query_as::<_, crate::models::LessonPoll>(
+            "SELECT * FROM lesson_polls WHERE id = $1 AND lesson_id = $2"
+        )
+        .bind(poll_id)
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(poll)
+    }
+
+    pub async fn get_polls(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonPoll>> {
+        let polls = sqlx::query_as::<_, crate::models::LessonPoll>(
+            "SELECT * FROM lesson_polls WHERE lesson_id = $1 ORDER BY created_at"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(polls)
+    }
+
+    // Records an answer while the poll is open; false if it's closed or the
+    // user already answered
+    pub async fn answer_poll(&self, poll_id: Uuid, user_id: Uuid, username: &str, option: i32) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO lesson_poll_responses (poll_id, user_id, username, option)
+             SELECT id, $2, $3, $4 FROM lesson_polls WHERE id = $1 AND closed_at IS NULL
+             ON CONFLICT DO NOTHING"
+        )
+        .bind(poll_id)
+        .bind(user_id)
+        .bind(username)
+        .bind(option)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Stops taking answers; false if it was already closed
+    pub async fn close_poll(&self, poll_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE lesson_polls SET closed_at = NOW() WHERE id = $1 AND closed_at IS NULL")
+            .bind(poll_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Shows the results (and a quiz's answer) to the class, closing the poll
+    // if it was still open; false if it was already revealed
+    pub async fn reveal_poll(&self, poll_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE lesson_polls SET revealed = TRUE, closed_at = COALESCE(closed_at, NOW())
+             WHERE id = $1 AND NOT revealed"
+        )
+        .bind(poll_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // How many answers each option of a poll got, as (option, count)
+    pub async fn get_poll_counts(&self, poll_id: Uuid) -> anyhow::Result<Vec<(i32, i64)>> {
+        let counts = sqlx::query_as::<_, (i32, i64)>(
+            "SELECT option, COUNT(*) FROM lesson_poll_responses WHERE poll_id = $1 GROUP BY option"
+        )
+        .bind(poll_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(counts)
+    }
+
+    // The user's answers to the lesson's polls, as (poll_id, option)
+    pub async fn get_poll_answers(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<Vec<(Uuid, i32)>> {
+        let answers = sqlx::query_as::<_, (Uuid, i32)>(
+            "SELECT r.poll_id, r.option FROM lesson_poll_responses r
+             JOIN lesson_polls p ON p.id = r.poll_id
+             WHERE p.lesson_id = $1 AND r.user_id = $2"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(answers)
+    }
+
+    // Everyone's answer to a poll, in the order they came in; `correct` is only
+    // set for quizzes
+    pub async fn get_poll_responses(&self, poll_id: Uuid) -> anyhow::Result<Vec<crate::models::PollResponse>> {
+        let responses = sqlx::query_as::<_, crate::models::PollResponse>(
+            "SELECT r.user_id, r.username, r.option, r.answered_at, r.option = p.correct_option AS correct
+             FROM lesson_poll_responses r
+             JOIN lesson_polls p ON p.id = r.poll_id
+             WHERE r.poll_id = $1
+             ORDER BY r.answered_at"
+        )
+        .bind(poll_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(responses)
+    }
+
+    pub async fn set_whiteboard_access(&self, lesson_id: Uuid, students_can_draw: bool) -> anyhow::Result<()> {
+        sqlx::query("UPDATE lessons SET whiteboard_students_can_draw = $2 WHERE id = $1")
+            .bind(lesson_id)
+            .bind(students_can_draw)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Appends an op to the lesson's whiteboard log and returns its seq, or None
+    // if it draws an element whose id is already on the board or the board
+    // already holds `max_elements`. The log is compacted as it goes, so it only
+    // holds what is on the board: a clear drops everything before it, and an
+    // erase drops the element it erases and any earlier erases.
+    pub async fn add_whiteboard_op(
+        &self,
+        lesson_id: Uuid,
+        user_id: Uuid,
+        op: &serde_json::Value,
+        max_elements: i64,
+    ) -> anyhow::Result<Option<i64>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM lesson_whiteboard_ops
+             WHERE lesson_id = $1
+               AND ($2->>'kind' = 'clear'
+                    OR ($2->>'kind' = 'erase'
+                        AND (op->>'kind' = 'erase' OR (op->>'kind' IN ('stroke', 'shape') AND op->>'id' = $2->>'id'))))"
+        )
+        .bind(lesson_id)
+        .bind(op)
+        .execute(&mut *tx)
+        .await?;
+        // idx_whiteboard_ops_element keeps element ids unique
+        let seq = sqlx::query_scalar(
+            "INSERT INTO lesson_whiteboard_ops (lesson_id, user_id, op)
+             SELECT $1, $2, $3
+             WHERE $3->>'kind' NOT IN ('stroke', 'shape')
+                OR (SELECT COUNT(*) FROM lesson_whiteboard_ops
+                    WHERE lesson_id = $1 AND op->>'kind' IN ('stroke', 'shape')) < $4
+             ON CONFLICT (lesson_id, (op->>'id')) WHERE op->>'kind' IN ('stroke', 'shape') DO NOTHING
+             RETURNING seq"
+        )
+        .bind(lesson_id)
+        .bind(user_id)
+        .bind(op)
+        .bind(max_elements)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(seq)
+    }
+
+    // The whiteboard ops that still matter, in order: the last clear and
+    // everything after it, or the whole log if the board was never cleared
+    pub async fn get_whiteboard_ops(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::WhiteboardOpRecord>> {
+        let ops = sqlx::query_as::<_, crate::models::WhiteboardOpRecord>(
+            "SELECT seq, user_id, op FROM lesson_whiteboard_ops
+             WHERE lesson_id = $1 AND seq >= COALESCE(
+                 (SELECT MAX(seq) FROM lesson_whiteboard_ops WHERE lesson_id = $1 AND op->>'kind' = 'clear'), 0)
+             ORDER BY seq"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ops)
+    }
+
+    // Who drew a stroke or shape, if it is still on the board
+    pub async fn get_whiteboard_element_author(&self, lesson_id: Uuid, element_id: Uuid) -> anyhow::Result<Option<Uuid>> {
+        let author = sqlx::query_scalar(
+            "SELECT user_id FROM lesson_whiteboard_ops
+             WHERE lesson_id = $1 AND op->>'id' = $2 AND op->>'kind' IN ('stroke', 'shape')"
+        )
+        .bind(lesson_id)
+        .bind(element_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(author)
+    }
+
+    pub async fn get_playback(&self, lesson_id: Uuid) -> anyhow::Result<Option<crate::models::LessonPlayback>> {
+        let playback = sqlx::query_as::<_, crate::models::LessonPlayback>(
+            "SELECT * FROM lesson_playback WHERE lesson_id = $1"
+        )
+        .bind(lesson_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(playback)
+    }
+
+    pub async fn save_playback(&self, playback: &crate::models::LessonPlayback) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO lesson_playback (lesson_id, video_url, playing, position, rate, updated_by, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (lesson_id) DO UPDATE SET video_url = $2, playing = $3, position = $4, rate = $5,
+                 updated_by = $6, updated_at = $7"
+        )
+        .bind(playback.lesson_id)
+        .bind(&playback.video_url)
+        .bind(playback.playing)
+        .bind(playback.position)
+        .bind(playback.rate)
+        .bind(playback.updated_by)
+        .bind(playback.updated_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Returns whether there was a video to close
+    pub async fn clear_playback(&self, lesson_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM lesson_playback WHERE lesson_id = $1")
+            .bind(lesson_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Opens a breakout room per group, each with its name and members. Returns
+    // false, opening nothing, if the lesson already has rooms open.
+    pub async fn open_breakouts(
+        &self,
+        lesson_id: Uuid,
+        rooms: &[(String, Vec<(Uuid, String)>)],
+        ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> anyhow::Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        // Two teachers starting breakouts at once must not both succeed
+        sqlx::query("SELECT id FROM lessons WHERE id = $1 FOR UPDATE")
+            .bind(lesson_id)
+            .execute(&mut *tx)
+            .await?;
+        let open: bool = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM lesson_breakout_rooms WHERE lesson_id = $1 AND closed_at IS NULL)"
+        )
+        .bind(lesson_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if open {
+            return Ok(false);
+        }
+        for (position, (name, members)) in rooms.iter().enumerate() {
+            let room_id: Uuid = sqlx::query_scalar(
+                "INSERT INTO lesson_breakout_rooms (lesson_id, name, position, ends_at) VALUES ($1, $2, $3, $4) RETURNING id"
+            )
+            .bind(lesson_id)
+            .bind(name)
+            .bind(position as i32)
+            .bind(ends_at)
+            .fetch_one(&mut *tx)
+            .await?;
+            for (user_id, username) in members {
+                sqlx::query("INSERT INTO lesson_breakout_members (room_id, user_id, username) VALUES ($1, $2, $3)")
+                    .bind(room_id)
+                    .bind(user_id)
+                    .bind(username)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(true)
     }
 
-    pub async fn get_user_by_email(&self, email: &str) -> anyhow::Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            r#"SELECT id, email, password_hash, user_type, 
-               first_name, last_name, created_at, updated_at, is_active,
-               zoom_access_token, zoom_refresh_token, zoom_token_expiry
-               FROM users WHERE email = $1"#
+    pub async fn get_breakout_room(&self, lesson_id: Uuid, room_id: Uuid) -> anyhow::Result<Option<crate::models::LessonBreakoutRoom>> {
+        let room = sqlx::query_as::<_, crate::models::LessonBreakoutRoom>(
+            "SELECT * FROM lesson_breakout_rooms WHERE id = $1 AND lesson_id = $2"
         )
-        .bind(email)
+        .bind(room_id)
+        .bind(lesson_id)
         .fetch_optional(&self.pool)
         .await?;
-        Ok(user)
+        Ok(room)
     }
 
-    pub async fn get_classrooms_by_teacher(&self, teacher_id: Uuid) -> anyhow::Result<Vec<Classroom>> {
-        let classrooms = sqlx::query_as::<_, Classroom>(
-            "SELECT * FROM classrooms WHERE teacher_id = $1 AND is_active = TRUE"
+    // Every breakout room the lesson has had, or only those still open, in the
+    // order they were opened
+    pub async fn get_breakout_rooms(&self, lesson_id: Uuid, open_only: bool) -> anyhow::Result<Vec<crate::models::LessonBreakoutRoom>> {
+        let rooms = sqlx::query_as::<_, crate::models::LessonBreakoutRoom>(
+            "SELECT * FROM lesson_breakout_rooms
+             WHERE lesson_id = $1 AND (NOT $2 OR closed_at IS NULL)
+             ORDER BY opened_at, position"
         )
-        .bind(teacher_id)
+        .bind(lesson_id)
+        .bind(open_only)
         .fetch_all(&self.pool)
         .await?;
+        Ok(rooms)
+    }
 
-        Ok(classrooms)
+    pub async fn get_breakout_members(&self, room_ids: &[Uuid]) -> anyhow::Result<Vec<crate::models::BreakoutRoomMember>> {
+        let members = sqlx::query_as::<_, crate::models::BreakoutRoomMember>(
+            "SELECT * FROM lesson_breakout_members WHERE room_id = ANY($1) ORDER BY username, user_id"
+        )
+        .bind(room_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(members)
+    }
+
+    pub async fn is_breakout_member(&self, room_id: Uuid, user_id: Uuid) -> anyhow::Result<bool> {
+        let member = sqlx::query_scalar(
+            "SELECT EXISTS(SELECT 1 FROM lesson_breakout_members WHERE room_id = $1 AND user_id = $2)"
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(member)
     }
 
-    pub async fn get_all_books(&self) -> anyhow::Result<Vec<DigitalBook>> {
-        let books = sqlx::query_as::<_, DigitalBook>(
-            "SELECT * FROM digital_books ORDER BY created_at DESC"
+    // Returns the ids of the open rooms whose time changed
+    pub async fn set_breakout_timer(&self, lesson_id: Uuid, ends_at: Option<chrono::DateTime<chrono::Utc>>) -> anyhow::Result<Vec<Uuid>> {
+        let ids = sqlx::query_scalar(
+            "UPDATE lesson_breakout_rooms SET ends_at = $2 WHERE lesson_id = $1 AND closed_at IS NULL RETURNING id"
         )
+        .bind(lesson_id)
+        .bind(ends_at)
         .fetch_all(&self.pool)
         .await?;
+        Ok(ids)
+    }
 
-        Ok(books)
+    // Returns the ids of the rooms that were closed
+    pub async fn close_breakouts(&self, lesson_id: Uuid) -> anyhow::Result<Vec<Uuid>> {
+        let ids = sqlx::query_scalar(
+            "UPDATE lesson_breakout_rooms SET closed_at = NOW() WHERE lesson_id = $1 AND closed_at IS NULL RETURNING id"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids)
     }
 
-    // --- Zoom meeting management ---
+    // Closes every open room whose timer has run out. Returns the lesson and id
+    // of each, so only the instance that closed a room announces it.
+    pub async fn close_expired_breakouts(&self) -> anyhow::Result<Vec<(Uuid, Uuid)>> {
+        let rooms = sqlx::query_as(
+            "UPDATE lesson_breakout_rooms SET closed_at = NOW()
+             WHERE closed_at IS NULL AND ends_at <= NOW()
+             RETURNING lesson_id, id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rooms)
+    }
 
-    pub async fn set_classroom_zoom_meeting(
-        &self,
-        classroom_id: Uuid,
-        meeting_id: &str,
-        join_url: &str,
-    ) -> anyhow::Result<()> {
+    pub async fn add_breakout_message(&self, msg: &crate::models::LessonBreakoutMessage) -> anyhow::Result<()> {
         sqlx::query(
-            "UPDATE classrooms SET zoom_meeting_id = $1, zoom_join_url = $2 WHERE id = $3"
+            "INSERT INTO lesson_breakout_messages (id, room_id, user_id, username, user_type, message, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)"
         )
-        .bind(meeting_id)
-        .bind(join_url)
-        .bind(classroom_id)
+        .bind(msg.id)
+        .bind(msg.room_id)
+        .bind(msg.user_id)
+        .bind(&msg.username)
+        .bind(&msg.user_type)
+        .bind(&msg.message)
+        .bind(msg.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn clear_classroom_zoom_meeting(&self, classroom_id: Uuid) -> anyhow::Result<()> {
-        sqlx::query(
-            "UPDATE classrooms SET zoom_meeting_id = NULL, zoom_join_url = NULL WHERE id = $1"
+    // The latest `limit` non-deleted messages of a breakout room, in order
+    pub async fn get_breakout_messages(&self, room_id: Uuid, limit: i64) -> anyhow::Result<Vec<crate::models::LessonBreakoutMessage>> {
+        let mut messages = sqlx::query_as::<_, crate::models::LessonBreakoutMessage>(
+            "SELECT * FROM lesson_breakout_messages
+             WHERE room_id = $1 AND deleted_at IS NULL
+             ORDER BY created_at DESC, id LIMIT $2"
         )
-        .bind(classroom_id)
-        .execute(&self.pool)
+        .bind(room_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(())
+        messages.reverse();
+        Ok(messages)
     }
 
-    // --- Meeting request management ---
+    // A breakout room's whole chat in order, deleted messages included
+    pub async fn get_all_breakout_messages(&self, room_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonBreakoutMessage>> {
+        let messages = sqlx::query_as::<_, crate::models::LessonBreakoutMessage>(
+            "SELECT * FROM lesson_breakout_messages WHERE room_id = $1 ORDER BY created_at, id"
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
 
-    pub async fn create_meeting_request(
-        &self,
-        classroom_id: Uuid,
-        student_id: Uuid,
-    ) -> anyhow::Result<()> {
+    // Returns false if the room has no such message
+    pub async fn delete_breakout_message(&self, room_id: Uuid, message_id: Uuid, deleted_by: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE lesson_breakout_messages SET deleted_at = COALESCE(deleted_at, NOW()), deleted_by = COALESCE(deleted_by, $3)
+             WHERE id = $1 AND room_id = $2"
+        )
+        .bind(message_id)
+        .bind(room_id)
+        .bind(deleted_by)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn add_private_message(&self, msg: &crate::models::LessonPrivateMessage) -> anyhow::Result<()> {
         sqlx::query(
-            "INSERT INTO meeting_requests (id, classroom_id, student_id) VALUES (uuid_generate_v4(), $1, $2)"
+            "INSERT INTO lesson_private_messages
+                 (id, lesson_id, sender_id, sender_name, sender_type, recipient_id, recipient_name, message, include_co_teachers, created_at)
+             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10)"
         )
-        .bind(classroom_id)
-        .bind(student_id)
+        .bind(msg.id)
+        .bind(msg.lesson_id)
+        .bind(msg.sender_id)
+        .bind(&msg.sender_name)
+        .bind(&msg.sender_type)
+        .bind(msg.recipient_id)
+        .bind(&msg.recipient_name)
+        .bind(&msg.message)
+        .bind(msg.include_co_teachers)
+        .bind(msg.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn get_pending_meeting_requests(
+    // The latest `limit` private messages of a lesson that `user_id` may read,
+    // oldest first: those they sent or received, and if they teach the lesson,
+    // those shared with its co-teachers
+    pub async fn get_private_messages(
         &self,
-        classroom_id: Uuid,
-    ) -> anyhow::Result<Vec<crate::models::MeetingRequest>> {
-        let requests = sqlx::query_as::<_, crate::models::MeetingRequest>(
-            "SELECT * FROM meeting_requests WHERE classroom_id = $1 AND status = 'pending'"
+        lesson_id: Uuid,
+        user_id: Uuid,
+        is_teacher: bool,
+        limit: i64,
+    ) -> anyhow::Result<Vec<crate::models::LessonPrivateMessage>> {
+        let mut messages = sqlx::query_as::<_, crate::models::LessonPrivateMessage>(
+            "SELECT * FROM lesson_private_messages
+             WHERE lesson_id = $1 AND (sender_id = $2 OR recipient_id = $2 OR ($3 AND include_co_teachers))
+             ORDER BY created_at DESC LIMIT $4"
         )
-        .bind(classroom_id)
+        .bind(lesson_id)
+        .bind(user_id)
+        .bind(is_teacher)
+        .bind(limit)
         .fetch_all(&self.pool)
         .await?;
-        Ok(requests)
+        messages.reverse();
+        Ok(messages)
     }
 
-    pub async fn update_meeting_request_status(
+    // Non-deleted messages of a lesson in seq order: the first `limit` after
+    // `after`, or else the last `limit` before `before` (or overall)
+    pub async fn get_chat_messages(
         &self,
-        request_id: Uuid,
-        status: &str,
-    ) -> anyhow::Result<()> {
-        sqlx::query(
-            "UPDATE meeting_requests SET status = $1 WHERE id = $2"
+        lesson_id: Uuid,
+        after: Option<i64>,
+        before: Option<i64>,
+        limit: i64,
+    ) -> anyhow::Result<Vec<crate::models::LessonChatMessage>> {
+        if let Some(after) = after {
+            let messages = sqlx::query_as::<_, crate::models::LessonChatMessage>(
+                "SELECT * FROM lesson_chat_messages
+                 WHERE lesson_id = $1 AND NOT deleted AND seq > $2 AND ($3::BIGINT IS NULL OR seq < $3)
+                 ORDER BY seq LIMIT $4"
+            )
+            .bind(lesson_id)
+            .bind(after)
+            .bind(before)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            return Ok(messages);
+        }
+        let mut messages = sqlx::query_as::<_, crate::models::LessonChatMessage>(
+            "SELECT * FROM lesson_chat_messages
+             WHERE lesson_id = $1 AND NOT deleted AND ($2::BIGINT IS NULL OR seq < $2)
+             ORDER BY seq DESC LIMIT $3"
         )
-        .bind(status)
-        .bind(request_id)
-        .execute(&self.pool)
+        .bind(lesson_id)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(())
+        messages.reverse();
+        Ok(messages)
     }
 
-    // Lesson CRUD
-    pub async fn create_lesson(&self, lesson: &crate::models::Lesson) -> anyhow::Result<()> {
-        sqlx::query(
-            "INSERT INTO lessons (id, classroom_id, teacher_id, title, description, scheduled_at, is_active, chat_closed, created_at)
-             VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9)"
+    // Messages up to `seq` that were edited, or deleted by a moderator, since
+    // message `seq` was posted; what a client resuming after it has missed
+    pub async fn get_chat_changes(&self, lesson_id: Uuid, seq: i64, limit: i64) -> anyhow::Result<Vec<crate::models::LessonChatMessage>> {
+        let messages = sqlx::query_as::<_, crate::models::LessonChatMessage>(
+            "WITH since AS (SELECT timestamp FROM lesson_chat_messages WHERE lesson_id = $1 AND seq = $2)
+             SELECT c.* FROM lesson_chat_messages c, since
+             WHERE c.lesson_id = $1 AND c.seq <= $2
+               AND (c.edited_at >= since.timestamp OR (c.deleted AND EXISTS (
+                   SELECT 1 FROM lesson_moderation_log m
+                   WHERE m.lesson_id = $1 AND m.created_at >= since.timestamp
+                     AND m.event->>'action' = 'message_deleted' AND m.event->>'message_id' = c.id::text
+               )))
+             ORDER BY c.seq LIMIT $3"
         )
-        .bind(lesson.id)
-        .bind(lesson.classroom_id)
-        .bind(lesson.teacher_id)
-        .bind(&lesson.title)
-        .bind(&lesson.description)
-        .bind(lesson.scheduled_at)
-        .bind(lesson.is_active)
-        .bind(lesson.chat_closed)
-        .bind(lesson.created_at)
-        .execute(&self.pool)
+        .bind(lesson_id)
+        .bind(seq)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    // The lesson's whole chat in seq order, deleted messages included
+    pub async fn get_all_chat_messages(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::LessonChatMessage>> {
+        let messages = sqlx::query_as::<_, crate::models::LessonChatMessage>(
+            "SELECT * FROM lesson_chat_messages WHERE lesson_id = $1 ORDER BY seq"
+        )
+        .bind(lesson_id)
+        .fetch_all(&self.pool)
         .await?;
+        Ok(messages)
+    }
+
+    // `event` is a protocol::ModerationEvent
+    pub async fn log_moderation(&self, lesson_id: Uuid, moderator_id: Uuid, event: &serde_json::Value) -> anyhow::Result<()> {
+        sqlx::query("INSERT INTO lesson_moderation_log (lesson_id, moderator_id, event) VALUES ($1, $2, $3)")
+            .bind(lesson_id)
+            .bind(moderator_id)
+            .bind(event)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
-    pub async fn get_lesson(&self, lesson_id: Uuid) -> anyhow::Result<Option<crate::models::Lesson>> {
-        let lesson = sqlx::query_as::<_, crate::models::Lesson>(
-            "SELECT * FROM lessons WHERE id = $1"
+    pub async fn get_moderation_log(&self, lesson_id: Uuid) -> anyhow::Result<Vec<crate::models::ModerationLogEntry>> {
+        let entries = sqlx::query_as::<_, crate::models::ModerationLogEntry>(
+            "SELECT m.moderator_id, TRIM(u.first_name || ' ' || u.last_name) AS moderator_name, m.event, m.created_at
+             FROM lesson_moderation_log m JOIN users u ON u.id = m.moderator_id
+             WHERE m.lesson_id = $1
+             ORDER BY m.created_at, m.id"
         )
         .bind(lesson_id)
-        .fetch_optional(&self.pool)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(lesson)
+        Ok(entries)
     }
 
-    pub async fn create_classroom(&self, classroom: &crate::models::Classroom) -> anyhow::Result<()> {
-        sqlx::query(
-            "INSERT INTO classrooms (id, name, description, teacher_id, is_active, created_at)
-             VALUES ($1, $2, $3, $4, $5, $6)"
+    // Returns false if the lesson has no such message
+    pub async fn delete_chat_message(&self, lesson_id: Uuid, message_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE lesson_chat_messages SET deleted = TRUE WHERE id = $1 AND lesson_id = $2"
         )
-        .bind(classroom.id)
-        .bind(&classroom.name)
-        .bind(&classroom.description)
-        .bind(classroom.teacher_id)
-        .bind(classroom.is_active)
-        .bind(classroom.created_at)
+        .bind(message_id)
+        .bind(lesson_id)
         .execute(&self.pool)
         .await?;
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn create_material(&self, material: &crate::models::DigitalBook) -> anyhow::Result<()> {
+    pub async fn set_lesson_chat_settings(&self, lesson_id: Uuid, settings: &crate::models::ChatSettings) -> anyhow::Result<()> {
         sqlx::query(
-            "INSERT INTO digital_books (id, title, author, description, pdf_url, level, created_at)
-             VALUES ($1, $2, $3, $4, $5, $6, $7)"
+            "UPDATE lessons SET chat_slow_mode_seconds = $2, chat_max_length = $3 WHERE id = $1"
         )
-        .bind(material.id)
-        .bind(&material.title)
-        .bind(&material.author)
-        .bind(&material.description)
-        .bind(&material.pdf_url)
-        .bind(&material.level)
-        .bind(material.created_at)
+        .bind(lesson_id)
+        .bind(settings.slow_mode_seconds)
+        .bind(settings.max_length)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    // Lesson participants
-    pub async fn add_lesson_participant(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<()> {
+    // --- Chat content filter ---
+
+    pub async fn get_chat_filter_rules(&self, classroom_id: Uuid) -> anyhow::Result<Vec<crate::models::ChatFilterRule>> {
+        let rules = sqlx::query_as::<_, crate::models::ChatFilterRule>(
+            "SELECT * FROM chat_filter_rules WHERE classroom_id = $1 ORDER BY created_at"
+        )
+        .bind(classroom_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rules)
+    }
+
+    pub async fn create_chat_filter_rule(&self, rule: &crate::models::ChatFilterRule) -> anyhow::Result<()> {
         sqlx::query(
-            "INSERT INTO lesson_participants (lesson_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+            "INSERT INTO chat_filter_rules (id, classroom_id, kind, pattern, action, created_by, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)"
         )
-        .bind(lesson_id)
-        .bind(user_id)
+        .bind(rule.id)
+        .bind(rule.classroom_id)
+        .bind(rule.kind)
+        .bind(&rule.pattern)
+        .bind(rule.action)
+        .bind(rule.created_by)
+        .bind(rule.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn set_participant_muted(&self, lesson_id: Uuid, user_id: Uuid, muted: bool) -> anyhow::Result<()> {
+    pub async fn delete_chat_filter_rule(&self, classroom_id: Uuid, rule_id: Uuid) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM chat_filter_rules WHERE id = $1 AND classroom_id = $2")
+            .bind(rule_id)
+            .bind(classroom_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn create_flagged_chat_message(&self, flag: &crate::models::FlaggedChatMessage) -> anyhow::Result<()> {
         sqlx::query(
-            "UPDATE lesson_participants SET is_muted = $1 WHERE lesson_id = $2 AND user_id = $3"
+            "INSERT INTO flagged_chat_messages
+                 (id, classroom_id, lesson_id, breakout_room_id, message_id, user_id, username, message, reasons, status, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"
         )
-        .bind(muted)
-        .bind(lesson_id)
-        .bind(user_id)
+        .bind(flag.id)
+        .bind(flag.classroom_id)
+        .bind(flag.lesson_id)
+        .bind(flag.breakout_room_id)
+        .bind(flag.message_id)
+        .bind(flag.user_id)
+        .bind(&flag.username)
+        .bind(&flag.message)
+        .bind(&flag.reasons)
+        .bind(flag.status)
+        .bind(flag.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn is_participant_muted(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<bool> {
-        let row = sqlx::query(
-            "SELECT is_muted FROM lesson_participants WHERE lesson_id = $1 AND user_id = $2"
+    pub async fn get_flagged_chat_messages(
+        &self,
+        classroom_id: Uuid,
+        status: Option<crate::models::FlagStatus>,
+    ) -> anyhow::Result<Vec<crate::models::FlaggedChatMessage>> {
+        let flags = sqlx::query_as::<_, crate::models::FlaggedChatMessage>(
+            "SELECT f.*,
+                    CASE WHEN f.breakout_room_id IS NOT NULL THEN b.deleted_at IS NOT NULL
+                         ELSE COALESCE(c.deleted, FALSE) END AS message_deleted
+             FROM flagged_chat_messages f
+             LEFT JOIN lesson_breakout_messages b ON b.id = f.message_id AND b.room_id = f.breakout_room_id
+             LEFT JOIN lesson_chat_messages c ON c.id = f.message_id AND c.lesson_id = f.lesson_id
+             WHERE f.classroom_id = $1 AND ($2::chat_flag_status IS NULL OR f.status = $2)
+             ORDER BY f.created_at"
         )
-        .bind(lesson_id)
-        .bind(user_id)
+        .bind(classroom_id)
+        .bind(status)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(flags)
+    }
+
+    pub async fn get_flagged_chat_message(&self, id: Uuid) -> anyhow::Result<Option<crate::models::FlaggedChatMessage>> {
+        let flag = sqlx::query_as::<_, crate::models::FlaggedChatMessage>(
+            "SELECT f.*,
+                    CASE WHEN f.breakout_room_id IS NOT NULL THEN b.deleted_at IS NOT NULL
+                         ELSE COALESCE(c.deleted, FALSE) END AS message_deleted
+             FROM flagged_chat_messages f
+             LEFT JOIN lesson_breakout_messages b ON b.id = f.message_id AND b.room_id = f.breakout_room_id
+             LEFT JOIN lesson_chat_messages c ON c.id = f.message_id AND c.lesson_id = f.lesson_id
+             WHERE f.id = $1"
+        )
+        .bind(id)
         .fetch_optional(&self.pool)
         .await?;
-        Ok(row.map(|r| r.get::<bool, _>("is_muted")).unwrap_or(false))
+        Ok(flag)
     }
 
-    // Lesson chat
-    pub async fn add_chat_message(&self, msg: &crate::models::LessonChatMessage) -> anyhow::Result<()> {
+    pub async fn review_flagged_chat_message(
+        &self,
+        id: Uuid,
+        status: crate::models::FlagStatus,
+        reviewed_by: Uuid,
+    ) -> anyhow::Result<()> {
         sqlx::query(
-            "INSERT INTO lesson_chat_messages (id, lesson_id, user_id, username, message, timestamp, deleted)
-             VALUES ($1,$2,$3,$4,$5,$6,$7)"
+            "UPDATE flagged_chat_messages SET status = $2, reviewed_by = $3, reviewed_at = NOW() WHERE id = $1"
         )
-        .bind(msg.id)
-        .bind(msg.lesson_id)
-        .bind(msg.user_id)
-        .bind(&msg.username)
-        .bind(&msg.message)
-        .bind(msg.timestamp)
-        .bind(msg.deleted)
+        .bind(id)
+        .bind(status)
+        .bind(reviewed_by)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn delete_chat_message(&self, message_id: Uuid) -> anyhow::Result<()> {
-        sqlx::query(
-            "UPDATE lesson_chat_messages SET deleted = TRUE WHERE id = $1"
+    // Everything that decides whether `user_id` may post in the lesson chat, in one query
+    pub async fn get_chat_policy(&self, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<Option<crate::models::ChatPolicy>> {
+        let policy = sqlx::query_as::<_, crate::models::ChatPolicy>(
+            "SELECT l.classroom_id,
+                    COALESCE(l.chat_closed, FALSE) AS chat_closed,
+                    COALESCE(p.is_muted, FALSE) AS is_muted,
+                    l.chat_slow_mode_seconds AS slow_mode_seconds,
+                    l.chat_max_length AS max_length,
+                    ARRAY(SELECT r.id FROM chat_filter_rules r
+                          WHERE r.classroom_id = l.classroom_id ORDER BY r.created_at) AS filter_rule_ids
+             FROM lessons l
+             LEFT JOIN lesson_participants p ON p.lesson_id = l.id AND p.user_id = $2
+             WHERE l.id = $1"
         )
-        .bind(message_id)
-        .execute(&self.pool)
+        .bind(lesson_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
         .await?;
-        Ok(())
+        Ok(policy)
     }
 
-    pub async fn close_lesson_chat(&self, lesson_id: Uuid) -> anyhow::Result<()> {
+    pub async fn set_lesson_chat_closed(&self, lesson_id: Uuid, closed: bool) -> anyhow::Result<()> {
         sqlx::query(
-            "UPDATE lessons SET chat_closed = TRUE WHERE id = $1"
+            "UPDATE lessons SET chat_closed = $2 WHERE id = $1"
         )
         .bind(lesson_id)
+        .bind(closed)
         .execute(&self.pool)
         .await?;
         Ok(())
@@ -476,6 +2825,143 @@ impl Database {
         Ok(lessons)
     }
 
+    // Lessons of a teacher that overlap [start, end), ignoring cancelled ones
+    // and the excluded lessons (usually the ones being moved)
+    pub async fn get_teacher_conflicts(
+        &self,
+        teacher_id: Uuid,
+        start: chrono::DateTime<chrono::Utc>,
+        end: chrono::DateTime<chrono::Utc>,
+        exclude_lesson_ids: &[Uuid],
+    ) -> anyhow::Result<Vec<crate::models::Lesson>> {
+        let lessons = sqlx::query_as::<_, crate::models::Lesson>(
+            "SELECT * FROM lessons
+             WHERE teacher_id = $1
+               AND status <> 'cancelled'
+               AND scheduled_at < $3
+               AND scheduled_at + duration_minutes * INTERVAL '1 minute' > $2
+               AND id <> ALL($4)
+             ORDER BY scheduled_at"
+        )
+        .bind(teacher_id)
+        .bind(start)
+        .bind(end)
+        .bind(exclude_lesson_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(lessons)
+    }
+
+    // --- Room broadcasts across instances ---
+
+    pub async fn notify(&self, channel: &str, payload: &str) -> anyhow::Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(channel)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn store_room_event(&self, payload: &serde_json::Value) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        sqlx::query("INSERT INTO room_event_payloads (id, payload) VALUES ($1, $2)")
+            .bind(id)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    pub async fn get_room_event(&self, id: Uuid) -> anyhow::Result<Option<serde_json::Value>> {
+        let payload = sqlx::query_scalar("SELECT payload FROM room_event_payloads WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(payload)
+    }
+
+    // Every instance reads a stored event as soon as it is notified, so anything
+    // older than a few minutes has been delivered
+    pub async fn purge_room_events(&self) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM room_event_payloads WHERE created_at < NOW() - INTERVAL '5 minutes'")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // --- Room presence, shared by every instance ---
+
+    // Registers a connection, first dropping any left behind by an instance that
+    // stopped refreshing them
+    pub async fn add_room_connection(
+        &self,
+        room: &serde_json::Value,
+        connection: &crate::models::RoomConnection,
+        stale_after_secs: f64,
+    ) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM room_connections WHERE seen_at < NOW() - $1 * INTERVAL '1 second'")
+            .bind(stale_after_secs)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO room_connections (id, room, user_id, username, user_type, joined_at)
+             VALUES ($1, $2, $3, $4, $5, $6)"
+        )
+        .bind(connection.id)
+        .bind(room)
+        .bind(connection.user_id)
+        .bind(&connection.username)
+        .bind(&connection.user_type)
+        .bind(connection.joined_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn remove_room_connection(&self, id: Uuid) -> anyhow::Result<Option<crate::models::RoomConnection>> {
+        let connection = sqlx::query_as::<_, crate::models::RoomConnection>(
+            "DELETE FROM room_connections WHERE id = $1
+             RETURNING id, user_id, username, user_type, joined_at, idle"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(connection)
+    }
+
+    // Marks a connection as still there, and whether it is idle
+    pub async fn refresh_room_connection(&self, id: Uuid, idle: bool) -> anyhow::Result<()> {
+        sqlx::query("UPDATE room_connections SET seen_at = NOW(), idle = $2 WHERE id = $1")
+            .bind(id)
+            .bind(idle)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Live connections to a room (or only `user_id`'s), oldest first
+    pub async fn get_room_connections(
+        &self,
+        room: &serde_json::Value,
+        user_id: Option<Uuid>,
+        stale_after_secs: f64,
+    ) -> anyhow::Result<Vec<crate::models::RoomConnection>> {
+        let connections = sqlx::query_as::<_, crate::models::RoomConnection>(
+            "SELECT id, user_id, username, user_type, joined_at, idle FROM room_connections
+             WHERE room = $1
+               AND ($2::uuid IS NULL OR user_id = $2)
+               AND seen_at >= NOW() - $3 * INTERVAL '1 second'
+             ORDER BY joined_at"
+        )
+        .bind(room)
+        .bind(user_id)
+        .bind(stale_after_secs)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(connections)
+    }
+
     pub fn get_pool(&self) -> &PgPool {
         &self.pool
     }
@@ -1,23 +1,33 @@
 use axum::{
     extract::{Path, State, Extension, Query},
-    http::StatusCode,
-    response::{Html, Json, Redirect},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Json, Response},
 };
 use chrono::Utc;
 use uuid::Uuid;
 use tokio::fs;
-use reqwest;
 
 use crate::{
     auth::{create_token, hash_password, verify_password, Claims},
-    models::{AuthResponse, LoginRequest, RegisterRequest, User, UserInfo, UserType, MeetingRequest, Classroom, Lesson, DigitalBook},
+    models::{
+        AuthResponse, LoginRequest, RegisterRequest, User, UserInfo, UserType, MeetingRequest, Classroom, Lesson,
+        LessonStatus, UpdateLessonRequest, CancelLessonRequest, LessonChange, ClassroomEnrollment, Notification,
+        LessonSeries, LessonSeriesDetail, CreateLessonSeriesRequest, UpdateSeriesOccurrenceRequest, SeriesEditScope,
+        AddSeriesExceptionsRequest, LessonView, TimeZoneQuery, ConflictQuery, ConflictCheckQuery, UpdateProfileRequest,
+        UpdateClassroomRequest, CalendarFeedToken, CalendarFeedInfo, AttendanceOverride, AttendanceRecord,
+        SetAttendanceRequest, ReportFormatQuery, ReminderPreferences, LessonPlan, LessonPlanRequest, LessonPlanView,
+        LessonPlanListQuery, CopyLessonPlanRequest, ChatHistoryQuery, ChatSettings, ChatFilterRule, ChatFilterKind, ChatMessageEdit,
+        CreateChatFilterRuleRequest, FlaggedChatMessage, FlagStatus, ModerationQueueQuery, ReviewFlagRequest,
+        ClassroomCoTeacher, SpeakingTurn, ParticipationSummary, PollResponse, BreakoutRoomView, LessonBreakoutMessage,
+    },
+    attendance, content_filter, ical, notifier, websocket, whiteboard,
+    presence, rooms, throttle, transcript,
+    protocol::{ChatMessage, ModerationEvent, Participant, Poll, PrivateMessage, Room, ServerMessage, MAX_CHAT_LENGTH},
+    recurrence::RecurrenceRule,
+    timezone::{localize_lesson, parse_time_zone, DEFAULT_TIME_ZONE},
     AppState,
 };
 
-const ZOOM_CLIENT_ID: &str = "YOUR_ZOOM_CLIENT_ID";
-const ZOOM_CLIENT_SECRET: &str = "YOUR_ZOOM_CLIENT_SECRET";
-const ZOOM_REDIRECT_URI: &str = "http://localhost:8000/api/zoom/callback";
-
 pub async fn home() -> Html<String> {
     let html = fs::read_to_string("static/index.html").await.unwrap_or_else(|_| "<h1>Not found</h1>".to_string());
     Html(html)
@@ -39,6 +49,9 @@ pub async fn register(
     if payload.first_name.trim().is_empty() || payload.last_name.trim().is_empty() {
         return Err((StatusCode::BAD_REQUEST, "First and last name are required.".to_string()));
     }
+    let time_zone = payload.time_zone.as_deref().unwrap_or(DEFAULT_TIME_ZONE);
+    let time_zone = parse_time_zone(time_zone)
+        .ok_or((StatusCode::BAD_REQUEST, "Unknown time zone.".to_string()))?;
 
     let password_hash = hash_password(&payload.password)
         .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password.".to_string()))?;
@@ -56,6 +69,7 @@ pub async fn register(
         zoom_access_token: None,
         zoom_refresh_token: None,
         zoom_token_expiry: None,
+        time_zone: time_zone.name().to_string(),
     };
 
     // Check if user already exists
@@ -77,6 +91,7 @@ pub async fn register(
             user_type: user.user_type,
             first_name: user.first_name,
             last_name: user.last_name,
+            time_zone: user.time_zone,
         },
     };
 
@@ -106,6 +121,7 @@ pub async fn login(
             user_type: user.user_type,
             first_name: user.first_name,
             last_name: user.last_name,
+            time_zone: user.time_zone,
         },
     };
 
@@ -133,60 +149,6 @@ pub async fn classroom(
     Ok(Html(classroom_html))
 }
 
-// Redirect user to Zoom OAuth
-pub async fn zoom_connect() -> Redirect {
-    let url = format!(
-        "https://zoom.us/oauth/authorize?response_type=code&client_id={}&redirect_uri={}",
-        ZOOM_CLIENT_ID, ZOOM_REDIRECT_URI
-    );
-    Redirect::temporary(&url)
-}
-
-// Handle Zoom OAuth callback
-pub async fn zoom_callback(
-    State(state): State<AppState>,
-    Extension(claims): Extension<Claims>,
-    Query(params): axum::extract::Query<std::collections::HashMap<String, String>>,
-) -> Result<Redirect, StatusCode> {
-    let code = params.get("code").ok_or(StatusCode::BAD_REQUEST)?;
-    // Exchange code for tokens
-    let client = reqwest::Client::new();
-    let res = client.post("https://zoom.us/oauth/token")
-        .basic_auth(ZOOM_CLIENT_ID, Some(ZOOM_CLIENT_SECRET))
-        .form(&[
-            ("grant_type", "authorization_code"),
-            ("code", code),
-            ("redirect_uri", ZOOM_REDIRECT_URI),
-        ])
-        .send()
-        .await
-        .map_err(|_| StatusCode::BAD_GATEWAY)?;
-
-    if !res.status().is_success() {
-        return Err(StatusCode::BAD_GATEWAY);
-    }
-    let json: serde_json::Value = res.json().await.map_err(|_| StatusCode::BAD_GATEWAY)?;
-    let access_token = json.get("access_token").and_then(|v| v.as_str()).map(|s| s.to_string());
-    let refresh_token = json.get("refresh_token").and_then(|v| v.as_str()).map(|s| s.to_string());
-    let expires_in = json.get("expires_in").and_then(|v| v.as_i64()).unwrap_or(0);
-
-    // Store tokens in DB for this user
-    let expiry = chrono::Utc::now() + chrono::Duration::seconds(expires_in);
-    sqlx::query(
-        "UPDATE users SET zoom_access_token = $1, zoom_refresh_token = $2, zoom_token_expiry = $3 WHERE id = $4"
-    )
-    .bind(&access_token)
-    .bind(&refresh_token)
-    .bind(expiry)
-    .bind(claims.sub)
-    .execute(state.db.get_pool())
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Redirect to dashboard
-    Ok(Redirect::to("/dashboard"))
-}
-
 // --- Zoom Meeting Management Endpoints ---
 
 // Teacher: Create a Zoom meeting for a classroom
@@ -298,14 +260,57 @@ pub async fn get_zoom_join_url(
 // Lesson management endpoints
 use axum::extract::Json as AxumJson;
 
+fn is_valid_duration(minutes: i32) -> bool {
+    (5..=480).contains(&minutes)
+}
+
+// Rejects with 409 when the lesson would overlap another of the teacher's lessons
+async fn ensure_no_conflicts(state: &AppState, lesson: &Lesson, allow_conflicts: bool) -> Result<(), StatusCode> {
+    if allow_conflicts {
+        return Ok(());
+    }
+    let conflicts = state.db
+        .get_teacher_conflicts(lesson.teacher_id, lesson.scheduled_at, lesson.ends_at(), &[lesson.id])
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+// Explicit ?tz= wins, otherwise the caller's profile time zone
+async fn caller_time_zone(state: &AppState, claims: &Claims, requested: Option<String>) -> Result<chrono_tz::Tz, StatusCode> {
+    if let Some(name) = requested {
+        return parse_time_zone(&name).ok_or(StatusCode::BAD_REQUEST);
+    }
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(user
+        .and_then(|u| parse_time_zone(&u.time_zone))
+        .unwrap_or(chrono_tz::UTC))
+}
+
 pub async fn create_lesson(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    AxumJson(payload): AxumJson<Lesson>,
+    Query(conflicts): Query<ConflictQuery>,
+    AxumJson(mut payload): AxumJson<Lesson>,
 ) -> Result<AxumJson<Lesson>, StatusCode> {
     if claims.user_type != UserType::Teacher {
         return Err(StatusCode::FORBIDDEN);
     }
+    if !is_valid_duration(payload.duration_minutes) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Teachers schedule lessons for themselves, in their own classrooms
+    get_owned_classroom(&state, &claims, payload.classroom_id).await?;
+    payload.teacher_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // New lessons always start out scheduled
+    payload.status = LessonStatus::Scheduled;
+    payload.cancellation_reason = None;
+    ensure_no_conflicts(&state, &payload, conflicts.allow_conflicts).await?;
     state.db.create_lesson(&payload).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(AxumJson(payload))
 }
@@ -314,25 +319,96 @@ pub async fn create_lesson(
 pub async fn list_teacher_lessons(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-) -> Result<AxumJson<Vec<Lesson>>, StatusCode> {
+    Query(query): Query<TimeZoneQuery>,
+) -> Result<AxumJson<Vec<LessonView>>, StatusCode> {
     if claims.user_type != UserType::Teacher {
         return Err(StatusCode::FORBIDDEN);
     }
+    let tz = caller_time_zone(&state, &claims, query.tz).await?;
     let teacher_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let lessons = state.db.get_lessons_by_teacher(teacher_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(AxumJson(lessons))
+    Ok(AxumJson(lessons.into_iter().map(|l| localize_lesson(l, tz)).collect()))
 }
 
-// Chat moderation endpoints
-pub async fn mute_participant(
+// Teacher's lessons that would overlap a proposed slot, for warning before saving
+pub async fn check_lesson_conflicts(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    Path((lesson_id, user_id)): Path<(Uuid, Uuid)>,
-) -> Result<StatusCode, StatusCode> {
+    Query(query): Query<ConflictCheckQuery>,
+) -> Result<AxumJson<Vec<Lesson>>, StatusCode> {
     if claims.user_type != UserType::Teacher {
         return Err(StatusCode::FORBIDDEN);
     }
+    if !is_valid_duration(query.duration_minutes) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let teacher_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let end = query.start + chrono::Duration::minutes(query.duration_minutes as i64);
+    let conflicts = state.db.get_teacher_conflicts(teacher_id, query.start, end, query.exclude.as_slice())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(conflicts))
+}
+
+// Chat moderation endpoints. Each change is pushed to the lesson room so
+// connected clients update live.
+async fn get_moderated_lesson(state: &AppState, claims: &Claims, lesson_id: Uuid) -> Result<Lesson, StatusCode> {
+    let lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(lesson)
+}
+
+// Marking a student (present, muted) registers them as a lesson participant,
+// which grants lesson membership, so it is only allowed for students enrolled
+// in the lesson's classroom
+async fn ensure_enrolled(state: &AppState, lesson: &Lesson, user_id: Uuid) -> Result<(), StatusCode> {
+    if state.db.is_enrolled(lesson.classroom_id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        Ok(())
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+// Tells the lesson room about a moderation action, and logs it for the
+// lesson's transcript
+async fn publish_moderation(state: &AppState, claims: &Claims, lesson_id: Uuid, event: ModerationEvent) {
+    if let (Ok(moderator_id), Ok(logged)) = (claims.sub.parse(), serde_json::to_value(event)) {
+        if let Err(e) = state.db.log_moderation(lesson_id, moderator_id, &logged).await {
+            tracing::error!("failed to log moderation in lesson {lesson_id}: {e:?}");
+        }
+    }
+    state.broker.publish(Room::Lesson { id: lesson_id }, ServerMessage::Moderation(event));
+}
+
+// Tells a participant something in the lesson room and in any open breakout
+// room, wherever they are connected
+async fn tell_participant(state: &AppState, lesson_id: Uuid, user_id: Uuid, text: &str) {
+    let mut rooms = vec![Room::Lesson { id: lesson_id }];
+    match state.db.get_breakout_rooms(lesson_id, true).await {
+        Ok(open) => rooms.extend(open.into_iter().map(|r| Room::Breakout { lesson_id, id: r.id })),
+        Err(e) => tracing::error!("failed to load the breakout rooms of lesson {lesson_id}: {e:?}"),
+    }
+    for room in rooms {
+        state.broker.publish_to(room, user_id, ServerMessage::System { text: text.to_string() });
+    }
+}
+
+pub async fn mute_participant(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    let lesson = get_moderated_lesson(&state, &claims, lesson_id).await?;
+    ensure_enrolled(&state, &lesson, user_id).await?;
+    // Make sure a participant row exists so the mute sticks even before they join
+    state.db.add_lesson_participant(lesson_id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     state.db.set_participant_muted(lesson_id, user_id, true).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    publish_moderation(&state, &claims, lesson_id, ModerationEvent::UserMuted { user_id }).await;
+    tell_participant(&state, lesson_id, user_id, "You have been muted by the teacher").await;
     Ok(StatusCode::OK)
 }
 
@@ -341,10 +417,10 @@ pub async fn unmute_participant(
     Extension(claims): Extension<Claims>,
     Path((lesson_id, user_id)): Path<(Uuid, Uuid)>,
 ) -> Result<StatusCode, StatusCode> {
-    if claims.user_type != UserType::Teacher {
-        return Err(StatusCode::FORBIDDEN);
-    }
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
     state.db.set_participant_muted(lesson_id, user_id, false).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    publish_moderation(&state, &claims, lesson_id, ModerationEvent::UserUnmuted { user_id }).await;
+    tell_participant(&state, lesson_id, user_id, "You can chat again").await;
     Ok(StatusCode::OK)
 }
 
@@ -353,22 +429,326 @@ pub async fn delete_chat_message(
     Extension(claims): Extension<Claims>,
     Path((lesson_id, message_id)): Path<(Uuid, Uuid)>,
 ) -> Result<StatusCode, StatusCode> {
-    if claims.user_type != UserType::Teacher {
-        return Err(StatusCode::FORBIDDEN);
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    if !state.db.delete_chat_message(lesson_id, message_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(StatusCode::NOT_FOUND);
     }
-    state.db.delete_chat_message(message_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    publish_moderation(&state, &claims, lesson_id, ModerationEvent::MessageDeleted { message_id }).await;
     Ok(StatusCode::OK)
 }
 
+// Paginated chat history of a lesson, oldest first, for the same people who
+// may join its room. `after` resumes from a seq; `before` pages backwards.
+pub async fn get_chat_history(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ChatHistoryQuery>,
+) -> Result<AxumJson<Vec<ChatMessage>>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    websocket::authorize_room(&state, &user, Room::Lesson { id }).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let messages = state.db.get_chat_messages(id, query.after, query.before, limit).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let messages = websocket::chat_messages(&state, messages).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(messages))
+}
+
+// Private messages of a lesson the caller can read, oldest first; see
+// websocket::send_private
+pub async fn get_private_messages(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<PrivateMessage>>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    websocket::authorize_room(&state, &user, Room::Lesson { id: lesson_id }).await?;
+    let teachers = state.db.get_lesson_teacher_ids(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let messages = state.db.get_private_messages(lesson_id, user_id, teachers.contains(&user_id), 500)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(messages.into_iter().map(PrivateMessage::from).collect()))
+}
+
+// Who the teacher called on in a lesson, in order
+pub async fn get_speaking_turns(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<SpeakingTurn>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    let turns = state.db.get_speaking_turns(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(turns))
+}
+
+// A lesson's polls with their results, for its teacher
+pub async fn list_polls(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<Poll>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    let mut polls = Vec::new();
+    for poll in state.db.get_polls(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        polls.push(websocket::poll_view(&state, poll, true).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?);
+    }
+    Ok(AxumJson(polls))
+}
+
+// Each student's answer to a poll, and for quizzes whether it was right
+pub async fn get_poll_responses(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, poll_id)): Path<(Uuid, Uuid)>,
+) -> Result<AxumJson<Vec<PollResponse>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    state.db.get_poll(lesson_id, poll_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let responses = state.db.get_poll_responses(poll_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(responses))
+}
+
+// Every breakout room the lesson has had, open or closed, with who was in it
+pub async fn list_breakouts(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<BreakoutRoomView>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    let rooms = state.db.get_breakout_rooms(lesson_id, false).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let ids: Vec<Uuid> = rooms.iter().map(|r| r.id).collect();
+    let members = state.db.get_breakout_members(&ids).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let views = rooms
+        .into_iter()
+        .map(|room| {
+            let members = members.iter().filter(|m| m.room_id == room.id).cloned().collect();
+            BreakoutRoomView { room, members }
+        })
+        .collect();
+    Ok(AxumJson(views))
+}
+
+// The whole chat of a breakout room, for the teacher to go through
+pub async fn get_breakout_chat(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, room_id)): Path<(Uuid, Uuid)>,
+) -> Result<AxumJson<Vec<LessonBreakoutMessage>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    state.db.get_breakout_room(lesson_id, room_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let messages = state.db.get_all_breakout_messages(room_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(messages))
+}
+
+// The lesson's whiteboard as it is now, as an SVG download
+pub async fn whiteboard_svg(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<Response, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    websocket::authorize_room(&state, &user, Room::Lesson { id: lesson_id }).await?;
+    let (_, elements) = websocket::whiteboard_elements(&state, lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut response = ([(header::CONTENT_TYPE, "image/svg+xml")], whiteboard::to_svg(&elements)).into_response();
+    if let Ok(value) = format!("attachment; filename=\"whiteboard-{lesson_id}.svg\"").parse() {
+        response.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+    }
+    Ok(response)
+}
+
+// Earlier versions of an edited message, oldest first, for the lesson's teacher
+pub async fn get_chat_message_edits(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<AxumJson<Vec<ChatMessageEdit>>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    state.db.get_chat_message(lesson_id, message_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let edits = state.db.get_chat_message_edits(message_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(edits))
+}
+
+// Who is connected to the lesson room right now
+pub async fn get_lesson_participants(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<AxumJson<Vec<Participant>>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let room = Room::Lesson { id };
+    websocket::authorize_room(&state, &user, room).await?;
+    let participants = presence::participants(&state.db, room).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(participants))
+}
+
 pub async fn close_chat(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
     Path(lesson_id): Path<Uuid>,
 ) -> Result<StatusCode, StatusCode> {
-    if claims.user_type != UserType::Teacher {
-        return Err(StatusCode::FORBIDDEN);
+    set_chat_closed(&state, &claims, lesson_id, true).await
+}
+
+pub async fn reopen_chat(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    set_chat_closed(&state, &claims, lesson_id, false).await
+}
+
+async fn set_chat_closed(state: &AppState, claims: &Claims, lesson_id: Uuid, closed: bool) -> Result<StatusCode, StatusCode> {
+    get_moderated_lesson(state, claims, lesson_id).await?;
+    state.db.set_lesson_chat_closed(lesson_id, closed).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let event = if closed { ModerationEvent::ChatClosed } else { ModerationEvent::ChatReopened };
+    publish_moderation(state, claims, lesson_id, event).await;
+    Ok(StatusCode::OK)
+}
+
+// Slow mode and message length cap for students in a lesson's chat
+pub async fn set_chat_settings(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+    AxumJson(settings): AxumJson<ChatSettings>,
+) -> Result<AxumJson<ChatSettings>, StatusCode> {
+    get_moderated_lesson(&state, &claims, lesson_id).await?;
+    let slow_mode_ok = settings.slow_mode_seconds.is_none_or(|s| (1..=throttle::MAX_SLOW_MODE_SECONDS).contains(&s));
+    let max_length_ok = settings.max_length.is_none_or(|n| (1..=MAX_CHAT_LENGTH as i32).contains(&n));
+    if !slow_mode_ok || !max_length_ok {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state.db.set_lesson_chat_settings(lesson_id, &settings).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let event = ModerationEvent::ChatSettingsChanged {
+        slow_mode_seconds: settings.slow_mode_seconds,
+        max_length: settings.max_length,
+    };
+    publish_moderation(&state, &claims, lesson_id, event).await;
+    Ok(AxumJson(settings))
+}
+
+// --- Chat content filter and moderation queue ---
+
+pub async fn list_chat_filter_rules(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<ChatFilterRule>>, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let rules = state.db.get_chat_filter_rules(classroom_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(rules))
+}
+
+pub async fn create_chat_filter_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+    AxumJson(payload): AxumJson<CreateChatFilterRuleRequest>,
+) -> Result<AxumJson<ChatFilterRule>, (StatusCode, String)> {
+    get_owned_classroom(&state, &claims, classroom_id).await.map_err(|status| (status, String::new()))?;
+    let created_by = claims.sub.parse().map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))?;
+    // Only word and regex rules take a pattern; the rest are built in
+    let pattern = match payload.kind {
+        ChatFilterKind::Word | ChatFilterKind::Regex => payload.pattern.map(|p| p.trim().to_string()),
+        _ => None,
+    };
+    if pattern.as_ref().is_some_and(|p| p.chars().count() > content_filter::MAX_PATTERN_LENGTH) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("patterns are limited to {} characters", content_filter::MAX_PATTERN_LENGTH),
+        ));
     }
-    state.db.close_lesson_chat(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    content_filter::compile(payload.kind, pattern.as_deref()).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let rule = ChatFilterRule {
+        id: Uuid::new_v4(),
+        classroom_id,
+        kind: payload.kind,
+        pattern,
+        action: payload.action,
+        created_by,
+        created_at: Utc::now(),
+    };
+    state.db.create_chat_filter_rule(&rule).await.map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, String::new()))?;
+    content_filter::forget(classroom_id);
+    Ok(AxumJson(rule))
+}
+
+pub async fn delete_chat_filter_rule(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((classroom_id, rule_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    if !state.db.delete_chat_filter_rule(classroom_id, rule_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    content_filter::forget(classroom_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Messages flagged by the classroom's filter, oldest first
+pub async fn get_moderation_queue(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+    Query(query): Query<ModerationQueueQuery>,
+) -> Result<AxumJson<Vec<FlaggedChatMessage>>, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let flags = state.db.get_flagged_chat_messages(classroom_id, query.status)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(flags))
+}
+
+// Dismisses a flagged message, or removes it from the chat
+pub async fn review_flagged_message(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    AxumJson(payload): AxumJson<ReviewFlagRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if payload.status == FlagStatus::Pending {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let reviewer = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let flag = state.db.get_flagged_chat_message(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    get_owned_classroom(&state, &claims, flag.classroom_id).await?;
+    if payload.status == FlagStatus::Removed {
+        // Lesson and breakout chat are stored; classroom chat only lives in connected clients
+        let event = ModerationEvent::MessageDeleted { message_id: flag.message_id };
+        match (flag.lesson_id, flag.breakout_room_id) {
+            (Some(lesson_id), Some(room_id)) => {
+                state.db.delete_breakout_message(room_id, flag.message_id, reviewer).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                state.broker.publish(Room::Breakout { lesson_id, id: room_id }, ServerMessage::Moderation(event));
+            }
+            (Some(lesson_id), None) => {
+                state.db.delete_chat_message(lesson_id, flag.message_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                publish_moderation(&state, &claims, lesson_id, event).await;
+            }
+            (None, _) => state.broker.publish(Room::Classroom { id: flag.classroom_id }, ServerMessage::Moderation(event)),
+        }
+    }
+    state.db.review_flagged_chat_message(id, payload.status, reviewer).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::OK)
 }
 
@@ -387,6 +767,8 @@ pub async fn create_classroom(
     if name.is_empty() || description.is_empty() {
         return Err(StatusCode::BAD_REQUEST);
     }
+    let time_zone = payload.get("time_zone").and_then(|v| v.as_str()).unwrap_or(DEFAULT_TIME_ZONE);
+    let time_zone = parse_time_zone(time_zone).ok_or(StatusCode::BAD_REQUEST)?;
     let classroom = Classroom {
         id: uuid::Uuid::new_v4(),
         name: name.to_string(),
@@ -396,11 +778,97 @@ pub async fn create_classroom(
         created_at: chrono::Utc::now(),
         zoom_meeting_id: None,
         zoom_join_url: None,
+        time_zone: time_zone.name().to_string(),
     };
     state.db.create_classroom(&classroom).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(AxumJson(classroom))
 }
 
+pub async fn update_classroom(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+    AxumJson(payload): AxumJson<UpdateClassroomRequest>,
+) -> Result<AxumJson<Classroom>, StatusCode> {
+    let mut classroom = get_owned_classroom(&state, &claims, classroom_id).await?;
+    if let Some(name) = payload.name {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        classroom.name = name.to_string();
+    }
+    if let Some(description) = payload.description {
+        classroom.description = description.trim().to_string();
+    }
+    if let Some(time_zone) = payload.time_zone {
+        let tz = parse_time_zone(&time_zone).ok_or(StatusCode::BAD_REQUEST)?;
+        classroom.time_zone = tz.name().to_string();
+    }
+    state.db.update_classroom(&classroom).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(classroom))
+}
+
+// --- Current user profile ---
+
+pub async fn update_profile(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    AxumJson(payload): AxumJson<UpdateProfileRequest>,
+) -> Result<AxumJson<UserInfo>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Some(time_zone) = payload.time_zone {
+        let tz = parse_time_zone(&time_zone).ok_or(StatusCode::BAD_REQUEST)?;
+        state.db.update_user_time_zone(user_id, tz.name()).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(AxumJson(UserInfo {
+        id: user.id,
+        email: user.email,
+        user_type: user.user_type,
+        first_name: user.first_name,
+        last_name: user.last_name,
+        time_zone: user.time_zone,
+    }))
+}
+
+// Reminders go out at most a week ahead, and at most a handful per lesson
+const MAX_REMINDER_OFFSET_MINUTES: i32 = 7 * 24 * 60;
+const MAX_REMINDER_OFFSETS: usize = 5;
+
+pub async fn get_reminder_preferences(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<AxumJson<ReminderPreferences>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let prefs = state.db.get_reminder_preferences(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .unwrap_or_default();
+    Ok(AxumJson(prefs))
+}
+
+pub async fn set_reminder_preferences(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    AxumJson(mut payload): AxumJson<ReminderPreferences>,
+) -> Result<AxumJson<ReminderPreferences>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    payload.offsets_minutes.sort_unstable_by(|a, b| b.cmp(a));
+    payload.offsets_minutes.dedup();
+    payload.channels.sort();
+    payload.channels.dedup();
+    if payload.offsets_minutes.len() > MAX_REMINDER_OFFSETS
+        || payload.offsets_minutes.iter().any(|m| !(1..=MAX_REMINDER_OFFSET_MINUTES).contains(m))
+        || payload.channels.iter().any(|c| !notifier::KNOWN_CHANNELS.contains(&c.as_str()))
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state.db.set_reminder_preferences(user_id, &payload).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(payload))
+}
+
 pub async fn list_teacher_classrooms(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
@@ -413,55 +881,1178 @@ pub async fn list_teacher_classrooms(
     Ok(AxumJson(classes))
 }
 
-// --- Material upload/list ---
-
-pub async fn upload_material(
+// --- Add missing get_lesson handler ---
+pub async fn get_lesson(
     State(state): State<AppState>,
     Extension(claims): Extension<Claims>,
-    AxumJson(payload): AxumJson<serde_json::Value>,
-) -> Result<AxumJson<DigitalBook>, StatusCode> {
-    if claims.user_type != UserType::Teacher {
+    Path(id): Path<Uuid>,
+    Query(query): Query<TimeZoneQuery>,
+) -> Result<AxumJson<LessonView>, StatusCode> {
+    let lesson = state.db.get_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !can_manage_lesson(&claims, &lesson)
+        && !state.db.is_lesson_member(id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
         return Err(StatusCode::FORBIDDEN);
     }
-    let title = payload.get("title").and_then(|v| v.as_str()).unwrap_or("").trim();
-    let author = payload.get("author").and_then(|v| v.as_str()).unwrap_or("").trim();
-    let description = payload.get("description").and_then(|v| v.as_str()).unwrap_or("").trim();
-    let level = payload.get("level").and_then(|v| v.as_str()).unwrap_or("").trim();
-    let pdf_url = payload.get("pdf_url").and_then(|v| v.as_str()).unwrap_or("").trim();
-    if title.is_empty() || author.is_empty() || description.is_empty() || level.is_empty() || pdf_url.is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
+    let tz = caller_time_zone(&state, &claims, query.tz).await?;
+    Ok(AxumJson(localize_lesson(lesson, tz)))
+}
+
+
+// --- Lesson updates, rescheduling and cancellation ---
+
+// The owning teacher and admins may change a lesson
+fn can_manage_lesson(claims: &Claims, lesson: &Lesson) -> bool {
+    match claims.user_type {
+        UserType::Admin => true,
+        UserType::Teacher => lesson.teacher_id.to_string() == claims.sub,
+        UserType::Student => false,
     }
-    let material = DigitalBook {
-        id: uuid::Uuid::new_v4(),
-        title: title.to_string(),
-        author: author.to_string(),
-        description: description.to_string(),
-        pdf_url: pdf_url.to_string(),
-        level: level.to_string(),
-        created_at: chrono::Utc::now(),
+}
+
+async fn record_lesson_change(
+    state: &AppState,
+    lesson_id: Uuid,
+    changed_by: Uuid,
+    action: &str,
+    details: serde_json::Value,
+) -> Result<(), StatusCode> {
+    let change = LessonChange {
+        id: Uuid::new_v4(),
+        lesson_id,
+        changed_by,
+        action: action.to_string(),
+        details,
+        created_at: Utc::now(),
     };
-    state.db.create_material(&material).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(AxumJson(material))
+    state.db.add_lesson_change(&change).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-pub async fn list_materials(
-    State(state): State<AppState>,
-    Extension(_claims): Extension<Claims>,
-) -> Result<AxumJson<Vec<DigitalBook>>, StatusCode> {
-    // Teachers see all materials, or could be filtered by teacher in future
-    let books = state.db.get_all_books().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    Ok(AxumJson(books))
+// Drop an in-app notification for every student attached to the lesson
+async fn notify_lesson_audience(state: &AppState, lesson: &Lesson, kind: &str, title: String, body: String) {
+    let audience = match state.db.get_lesson_audience(lesson.id).await {
+        Ok(audience) => audience,
+        Err(e) => {
+            tracing::error!("failed to load audience for lesson {}: {e}", lesson.id);
+            return;
+        }
+    };
+    for user_id in audience {
+        let notification = Notification {
+            id: Uuid::new_v4(),
+            user_id,
+            kind: kind.to_string(),
+            title: title.clone(),
+            body: body.clone(),
+            lesson_id: Some(lesson.id),
+            created_at: Utc::now(),
+            read_at: None,
+        };
+        if let Err(e) = state.db.create_notification(&notification).await {
+            tracing::error!("failed to notify {user_id} about lesson {}: {e}", lesson.id);
+        }
+    }
 }
 
-// --- Add missing get_lesson handler ---
-pub async fn get_lesson(
-    State(state): State<AppState>,
-    Extension(_claims): Extension<Claims>,
-    Path(id): Path<Uuid>,
-) -> Result<AxumJson<Lesson>, StatusCode> {
-    let lesson = state.db.get_lesson(id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    match lesson {
-        Some(lesson) => Ok(AxumJson(lesson)),
-        None => Err(StatusCode::NOT_FOUND),
+// Applies an edit to a lesson and records it in the change history. Returns the
+// lesson as it was before, so callers can decide whom to notify and how.
+async fn apply_lesson_update(
+    state: &AppState,
+    changed_by: Uuid,
+    lesson: &mut Lesson,
+    payload: UpdateLessonRequest,
+    allow_conflicts: bool,
+) -> Result<Lesson, StatusCode> {
+    if lesson.status.is_final() {
+        return Err(StatusCode::CONFLICT);
+    }
+    let previous = lesson.clone();
+
+    if let Some(title) = payload.title {
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        lesson.title = title.to_string();
+    }
+    if let Some(description) = payload.description {
+        lesson.description = description.trim().to_string();
+    }
+    if let Some(scheduled_at) = payload.scheduled_at {
+        lesson.scheduled_at = scheduled_at;
+    }
+    if let Some(duration) = payload.duration_minutes {
+        if !is_valid_duration(duration) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        lesson.duration_minutes = duration;
+    }
+    if let Some(status) = payload.status {
+        // Cancellation goes through its own endpoint so a reason can be recorded
+        if status == LessonStatus::Cancelled {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        if status != lesson.status && !lesson.status.can_transition_to(status) {
+            return Err(StatusCode::CONFLICT);
+        }
+        lesson.status = status;
+    }
+    if lesson.scheduled_at != previous.scheduled_at || lesson.duration_minutes != previous.duration_minutes {
+        ensure_no_conflicts(state, lesson, allow_conflicts).await?;
+    }
+
+    state.db.update_lesson(lesson).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for change in lesson_changes(changed_by, &previous, lesson) {
+        state.db.add_lesson_change(&change).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    Ok(previous)
+}
+
+// The change history entries for an edit from `previous` to `lesson`
+fn lesson_changes(changed_by: Uuid, previous: &Lesson, lesson: &Lesson) -> Vec<LessonChange> {
+    let change = |action: &str, details: serde_json::Value| LessonChange {
+        id: Uuid::new_v4(),
+        lesson_id: lesson.id,
+        changed_by,
+        action: action.to_string(),
+        details,
+        created_at: Utc::now(),
+    };
+    let mut changes = Vec::new();
+    if lesson.title != previous.title || lesson.description != previous.description {
+        changes.push(change("updated", serde_json::json!({
+            "from": { "title": previous.title, "description": previous.description },
+            "to": { "title": lesson.title, "description": lesson.description },
+        })));
+    }
+    if lesson.scheduled_at != previous.scheduled_at || lesson.duration_minutes != previous.duration_minutes {
+        changes.push(change("rescheduled", serde_json::json!({
+            "from": { "scheduled_at": previous.scheduled_at, "duration_minutes": previous.duration_minutes },
+            "to": { "scheduled_at": lesson.scheduled_at, "duration_minutes": lesson.duration_minutes },
+        })));
+    }
+    if lesson.status != previous.status {
+        changes.push(change("status", serde_json::json!({
+            "from": previous.status,
+            "to": lesson.status,
+        })));
+    }
+    changes
+}
+
+// Cancels a lesson and records it in the change history; notification is left to the caller
+async fn apply_lesson_cancellation(
+    state: &AppState,
+    changed_by: Uuid,
+    lesson: &mut Lesson,
+    reason: Option<String>,
+) -> Result<(), StatusCode> {
+    if !lesson.status.can_transition_to(LessonStatus::Cancelled) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let previous_status = lesson.status;
+    lesson.status = LessonStatus::Cancelled;
+    lesson.cancellation_reason = reason.clone();
+    state.db.update_lesson(lesson).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    record_lesson_change(state, lesson.id, changed_by, "cancelled", serde_json::json!({
+        "from": previous_status,
+        "reason": reason,
+    })).await
+}
+
+async fn notify_lesson_rescheduled(state: &AppState, lesson: &Lesson, previous: &Lesson) {
+    notify_lesson_audience(
+        state,
+        lesson,
+        "lesson_rescheduled",
+        format!("Lesson rescheduled: {}", lesson.title),
+        format!(
+            "\"{}\" has moved from {} to {}.",
+            lesson.title,
+            previous.scheduled_at.format("%Y-%m-%d %H:%M UTC"),
+            lesson.scheduled_at.format("%Y-%m-%d %H:%M UTC"),
+        ),
+    ).await;
+}
+
+async fn notify_lesson_cancelled(state: &AppState, lesson: &Lesson) {
+    let mut body = format!(
+        "\"{}\" scheduled for {} has been cancelled.",
+        lesson.title,
+        lesson.scheduled_at.format("%Y-%m-%d %H:%M UTC"),
+    );
+    if let Some(reason) = &lesson.cancellation_reason {
+        body.push_str(&format!(" Reason: {reason}"));
+    }
+    notify_lesson_audience(
+        state,
+        lesson,
+        "lesson_cancelled",
+        format!("Lesson cancelled: {}", lesson.title),
+        body,
+    ).await;
+}
+
+fn cancellation_reason(reason: Option<String>) -> Option<String> {
+    reason.map(|r| r.trim().to_string()).filter(|r| !r.is_empty())
+}
+
+pub async fn update_lesson(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    Query(conflicts): Query<ConflictQuery>,
+    AxumJson(payload): AxumJson<UpdateLessonRequest>,
+) -> Result<AxumJson<Lesson>, StatusCode> {
+    let mut lesson = state.db.get_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let changed_by: Uuid = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let previous = apply_lesson_update(&state, changed_by, &mut lesson, payload, conflicts.allow_conflicts).await?;
+    if lesson.scheduled_at != previous.scheduled_at {
+        notify_lesson_rescheduled(&state, &lesson, &previous).await;
+    }
+    Ok(AxumJson(lesson))
+}
+
+pub async fn cancel_lesson(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    payload: Option<AxumJson<CancelLessonRequest>>,
+) -> Result<AxumJson<Lesson>, StatusCode> {
+    let mut lesson = state.db.get_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let changed_by: Uuid = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let reason = cancellation_reason(payload.and_then(|AxumJson(p)| p.reason));
+    apply_lesson_cancellation(&state, changed_by, &mut lesson, reason).await?;
+    notify_lesson_cancelled(&state, &lesson).await;
+    Ok(AxumJson(lesson))
+}
+
+pub async fn get_lesson_history(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<AxumJson<Vec<LessonChange>>, StatusCode> {
+    let lesson = state.db.get_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let changes = state.db.get_lesson_changes(id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(changes))
+}
+
+// --- Recurring lesson series ---
+
+fn can_manage_series(claims: &Claims, series: &LessonSeries) -> bool {
+    match claims.user_type {
+        UserType::Admin => true,
+        UserType::Teacher => series.teacher_id.to_string() == claims.sub,
+        UserType::Student => false,
+    }
+}
+
+fn series_time_zone(series: &LessonSeries) -> chrono_tz::Tz {
+    parse_time_zone(&series.time_zone).unwrap_or(chrono_tz::UTC)
+}
+
+// Exception dates are calendar dates in the series' own time zone
+fn is_exception_date(series: &LessonSeries, t: chrono::DateTime<Utc>) -> bool {
+    let local_date = t.with_timezone(&series_time_zone(series)).date_naive();
+    series.exception_dates.contains(&local_date)
+}
+
+// One lesson per occurrence of the series that doesn't fall on an exception date
+fn series_lessons(series: &LessonSeries, rule: &RecurrenceRule) -> anyhow::Result<Vec<Lesson>> {
+    Ok(rule.occurrences(series.starts_at, series_time_zone(series))?
+        .into_iter()
+        .filter(|t| !is_exception_date(series, *t))
+        .map(|occurrence| Lesson {
+            id: Uuid::new_v4(),
+            classroom_id: series.classroom_id,
+            teacher_id: series.teacher_id,
+            title: series.title.clone(),
+            description: series.description.clone(),
+            scheduled_at: occurrence,
+            duration_minutes: series.duration_minutes,
+            status: LessonStatus::Scheduled,
+            chat_closed: false,
+            chat_slow_mode_seconds: None,
+            chat_max_length: None,
+            whiteboard_students_can_draw: false,
+            created_at: Utc::now(),
+            cancellation_reason: None,
+            series_id: Some(series.id),
+            occurrence_start: Some(occurrence),
+        })
+        .collect())
+}
+
+async fn series_detail(state: &AppState, series: LessonSeries) -> Result<AxumJson<LessonSeriesDetail>, StatusCode> {
+    let tz = series_time_zone(&series);
+    let lessons = state.db.get_series_lessons(series.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let lessons = lessons.into_iter().map(|l| localize_lesson(l, tz)).collect();
+    Ok(AxumJson(LessonSeriesDetail { series, lessons }))
+}
+
+async fn get_managed_series(state: &AppState, claims: &Claims, series_id: Uuid) -> Result<LessonSeries, StatusCode> {
+    let series = state.db.get_lesson_series(series_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_series(claims, &series) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(series)
+}
+
+pub async fn create_lesson_series(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(conflicts): Query<ConflictQuery>,
+    AxumJson(payload): AxumJson<CreateLessonSeriesRequest>,
+) -> Result<AxumJson<LessonSeriesDetail>, StatusCode> {
+    let classroom = get_owned_classroom(&state, &claims, payload.classroom_id).await?;
+    let title = payload.title.trim();
+    if title.is_empty() || !is_valid_duration(payload.duration_minutes) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let rule: RecurrenceRule = payload.rrule.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let time_zone = payload.time_zone.as_deref().unwrap_or(&classroom.time_zone);
+    let time_zone = parse_time_zone(time_zone).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let mut exception_dates = payload.exception_dates;
+    exception_dates.sort();
+    exception_dates.dedup();
+
+    let series = LessonSeries {
+        id: Uuid::new_v4(),
+        classroom_id: classroom.id,
+        teacher_id: classroom.teacher_id,
+        title: title.to_string(),
+        description: payload.description.trim().to_string(),
+        starts_at: payload.starts_at,
+        duration_minutes: payload.duration_minutes,
+        time_zone: time_zone.name().to_string(),
+        rrule: rule.to_string(),
+        exception_dates,
+        created_at: Utc::now(),
+    };
+    // Too many occurrences for an UNTIL rule is the caller's mistake
+    let lessons = series_lessons(&series, &rule).map_err(|_| StatusCode::BAD_REQUEST)?;
+    for lesson in &lessons {
+        ensure_no_conflicts(&state, lesson, conflicts.allow_conflicts).await?;
+    }
+    state.db.create_lesson_series(&series, &lessons).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    series_detail(&state, series).await
+}
+
+pub async fn get_lesson_series(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<AxumJson<LessonSeriesDetail>, StatusCode> {
+    let series = get_managed_series(&state, &claims, id).await?;
+    series_detail(&state, series).await
+}
+
+// Edit "this occurrence" (detaches nothing, just edits the one lesson) or "this
+// and following", which splits the series at the chosen occurrence so the
+// earlier lessons keep their original definition.
+pub async fn update_series_occurrence(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((series_id, lesson_id)): Path<(Uuid, Uuid)>,
+    Query(conflicts): Query<ConflictQuery>,
+    AxumJson(payload): AxumJson<UpdateSeriesOccurrenceRequest>,
+) -> Result<AxumJson<LessonSeriesDetail>, StatusCode> {
+    let mut series = get_managed_series(&state, &claims, series_id).await?;
+    let changed_by: Uuid = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|l| l.series_id == Some(series_id))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if payload.scope == SeriesEditScope::This {
+        let previous = apply_lesson_update(&state, changed_by, &mut lesson, UpdateLessonRequest {
+            title: payload.title,
+            description: payload.description,
+            scheduled_at: payload.scheduled_at,
+            duration_minutes: payload.duration_minutes,
+            status: None,
+        }, conflicts.allow_conflicts).await?;
+        if lesson.scheduled_at != previous.scheduled_at {
+            notify_lesson_rescheduled(&state, &lesson, &previous).await;
+        }
+        return series_detail(&state, series).await;
+    }
+
+    if lesson.status.is_final() {
+        return Err(StatusCode::CONFLICT);
+    }
+    let anchor = lesson.occurrence_start.unwrap_or(lesson.scheduled_at);
+    let shift = payload
+        .scheduled_at
+        .map(|t| t - lesson.scheduled_at)
+        .unwrap_or_else(chrono::Duration::zero);
+    let title = payload.title.as_deref().map(str::trim).unwrap_or(&series.title).to_string();
+    if title.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let description = payload.description.as_deref().map(str::trim).unwrap_or(&series.description).to_string();
+    let duration_minutes = payload.duration_minutes.unwrap_or(series.duration_minutes);
+    if !is_valid_duration(duration_minutes) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let rule: RecurrenceRule = series.rrule.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let tz = series_time_zone(&series);
+    let anchor_date = anchor.with_timezone(&tz).date_naive();
+    // The rule from the anchor on, moved along with its lessons. Moving them to
+    // other weekdays moves BYDAY too, so the rule keeps matching the lessons.
+    let mut moved_rule = rule.clone();
+    moved_rule.until = rule.until.map(|u| u + shift);
+    let day_shift = ((anchor + shift).with_timezone(&tz).date_naive() - anchor_date).num_days();
+    moved_rule.shift_days(day_shift).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    // The series being edited from here on: either the original one (when the
+    // anchor is its first occurrence) or a new series split off at the anchor.
+    // Nothing is written until every moved lesson has been checked.
+    let split_off = if anchor <= series.starts_at {
+        series.title = title;
+        series.description = description;
+        series.duration_minutes = duration_minutes;
+        series.starts_at += shift;
+        series.rrule = moved_rule.to_string();
+        None
+    } else {
+        let before = rule.occurrences(series.starts_at, tz)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .iter()
+            .filter(|t| **t < anchor)
+            .count() as u32;
+
+        // A COUNT rule whose occurrences all fall before the anchor has nothing
+        // left to split off
+        if rule.count.is_some_and(|c| c <= before) {
+            return Err(StatusCode::CONFLICT);
+        }
+        let mut following_rule = moved_rule;
+        following_rule.count = rule.count.map(|c| c - before);
+        let following = LessonSeries {
+            id: Uuid::new_v4(),
+            classroom_id: series.classroom_id,
+            teacher_id: series.teacher_id,
+            title,
+            description,
+            starts_at: anchor + shift,
+            duration_minutes,
+            time_zone: series.time_zone.clone(),
+            rrule: following_rule.to_string(),
+            exception_dates: series.exception_dates.iter().copied().filter(|d| *d >= anchor_date).collect(),
+            created_at: Utc::now(),
+        };
+
+        let mut earlier_rule = rule;
+        earlier_rule.count = None;
+        earlier_rule.until = Some(anchor - chrono::Duration::seconds(1));
+        series.rrule = earlier_rule.to_string();
+        series.exception_dates.retain(|d| *d < anchor_date);
+        Some(following)
+    };
+    let target = split_off.as_ref().unwrap_or(&series);
+
+    let mut moved = Vec::new();
+    let mut rescheduled = Vec::new();
+    let mut changes = Vec::new();
+    for previous in state.db.get_series_lessons(series_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        let start = previous.occurrence_start.unwrap_or(previous.scheduled_at);
+        if start < anchor || previous.status.is_final() {
+            continue;
+        }
+        let mut occurrence = previous.clone();
+        occurrence.series_id = Some(target.id);
+        occurrence.occurrence_start = Some(start + shift);
+        occurrence.scheduled_at += shift;
+        occurrence.title = target.title.clone();
+        occurrence.description = target.description.clone();
+        occurrence.duration_minutes = target.duration_minutes;
+        if occurrence.scheduled_at != previous.scheduled_at || occurrence.duration_minutes != previous.duration_minutes {
+            rescheduled.push(occurrence.clone());
+        }
+        changes.extend(lesson_changes(changed_by, &previous, &occurrence));
+        moved.push(occurrence);
+    }
+
+    // The moved lessons only have to clear everything else, not their own old slots
+    if !conflicts.allow_conflicts {
+        let moved_ids: Vec<Uuid> = moved.iter().map(|l| l.id).collect();
+        for occurrence in &rescheduled {
+            let clashes = state.db
+                .get_teacher_conflicts(occurrence.teacher_id, occurrence.scheduled_at, occurrence.ends_at(), &moved_ids)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if !clashes.is_empty() {
+                return Err(StatusCode::CONFLICT);
+            }
+        }
+    }
+
+    state.db
+        .save_series_edit(&series, split_off.as_ref(), &moved, &changes)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let target = split_off.unwrap_or(series);
+
+    if shift != chrono::Duration::zero() {
+        lesson.scheduled_at += shift;
+        notify_lesson_audience(
+            &state,
+            &lesson,
+            "lesson_rescheduled",
+            format!("Lessons rescheduled: {}", target.title),
+            format!(
+                "\"{}\" from {} onward now starts at {} ({}).",
+                target.title,
+                anchor_date.format("%Y-%m-%d"),
+                lesson.scheduled_at.with_timezone(&tz).format("%H:%M"),
+                tz.name(),
+            ),
+        ).await;
+    }
+
+    series_detail(&state, target).await
+}
+
+// Skip dates (holidays, school closures) in a series, cancelling any lesson
+// already materialized on them
+pub async fn add_series_exceptions(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    AxumJson(payload): AxumJson<AddSeriesExceptionsRequest>,
+) -> Result<AxumJson<LessonSeriesDetail>, StatusCode> {
+    let mut series = get_managed_series(&state, &claims, id).await?;
+    let changed_by: Uuid = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    series.exception_dates.extend(payload.dates.iter().copied());
+    series.exception_dates.sort();
+    series.exception_dates.dedup();
+    state.db.update_lesson_series(&series).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let reason = cancellation_reason(payload.reason).or_else(|| Some("Holiday".to_string()));
+    let lessons = state.db.get_series_lessons(id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    for mut lesson in lessons {
+        let local_date = lesson.scheduled_at.with_timezone(&series_time_zone(&series)).date_naive();
+        if !payload.dates.contains(&local_date)
+            || !lesson.status.can_transition_to(LessonStatus::Cancelled)
+        {
+            continue;
+        }
+        apply_lesson_cancellation(&state, changed_by, &mut lesson, reason.clone()).await?;
+        notify_lesson_cancelled(&state, &lesson).await;
+    }
+
+    series_detail(&state, series).await
+}
+
+// --- Classroom enrollment ---
+
+async fn get_owned_classroom(state: &AppState, claims: &Claims, classroom_id: Uuid) -> Result<Classroom, StatusCode> {
+    let classroom = state.db.get_classroom(classroom_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    match claims.user_type {
+        UserType::Admin => Ok(classroom),
+        UserType::Teacher if classroom.teacher_id.to_string() == claims.sub => Ok(classroom),
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+pub async fn enroll_student(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((classroom_id, student_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let student = state.db.get_user_by_id(student_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if student.user_type != UserType::Student {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state.db.enroll_student(classroom_id, student_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn unenroll_student(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((classroom_id, student_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    state.db.unenroll_student(classroom_id, student_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_co_teachers(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<ClassroomCoTeacher>>, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let co_teachers = state.db.get_co_teachers(classroom_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(co_teachers))
+}
+
+pub async fn add_co_teacher(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((classroom_id, teacher_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    let classroom = get_owned_classroom(&state, &claims, classroom_id).await?;
+    let teacher = state.db.get_user_by_id(teacher_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if teacher.user_type != UserType::Teacher || teacher.id == classroom.teacher_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state.db.add_co_teacher(classroom_id, teacher_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn remove_co_teacher(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((classroom_id, teacher_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    state.db.remove_co_teacher(classroom_id, teacher_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Speaking turns per student across the classroom's lessons, quietest first
+pub async fn get_classroom_participation(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<ParticipationSummary>>, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let summary = state.db.get_classroom_participation(classroom_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(summary))
+}
+
+pub async fn list_classroom_students(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+) -> Result<AxumJson<Vec<ClassroomEnrollment>>, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let enrollments = state.db.get_classroom_enrollments(classroom_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(enrollments))
+}
+
+// --- Calendar feeds ---
+
+// Feeds keep a week of past lessons so recent events don't vanish from calendars
+const CALENDAR_FEED_LOOKBACK_DAYS: i64 = 7;
+
+fn calendar_feed_info(state: &AppState, token: &CalendarFeedToken) -> CalendarFeedInfo {
+    CalendarFeedInfo {
+        url: format!("{}/calendar/{}.ics", state.public_url.trim_end_matches('/'), token.token),
+        created_at: token.created_at,
+    }
+}
+
+fn ics_response(body: String, filename: Option<&str>) -> Response {
+    let mut response = ([(header::CONTENT_TYPE, "text/calendar; charset=utf-8")], body).into_response();
+    if let Some(filename) = filename {
+        if let Ok(value) = format!("attachment; filename=\"{filename}\"").parse() {
+            response.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+    response
+}
+
+pub async fn get_calendar_feed(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<AxumJson<CalendarFeedInfo>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = state.db.get_active_feed_token(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(AxumJson(calendar_feed_info(&state, &token)))
+}
+
+// Issues a new subscription URL, revoking any previous one
+pub async fn create_calendar_feed(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<AxumJson<CalendarFeedInfo>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.db.revoke_feed_tokens(user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = CalendarFeedToken {
+        id: Uuid::new_v4(),
+        user_id,
+        token: format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+        created_at: Utc::now(),
+        revoked_at: None,
+    };
+    state.db.create_feed_token(&token).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(calendar_feed_info(&state, &token)))
+}
+
+pub async fn revoke_calendar_feed(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.db.revoke_feed_tokens(user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Public subscription endpoint polled by calendar apps
+pub async fn calendar_feed(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Response, StatusCode> {
+    let token = token.strip_suffix(".ics").unwrap_or(&token);
+    let user_id = state.db.get_feed_token_owner(token).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let since = Utc::now() - chrono::Duration::days(CALENDAR_FEED_LOOKBACK_DAYS);
+    let entries = state.db.get_calendar_entries_for_user(user_id, since).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(ics_response(ical::render_calendar("ESL Lessons", &entries, &state.public_url), None))
+}
+
+pub async fn lesson_ics(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<Response, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if claims.user_type != UserType::Admin
+        && !state.db.is_lesson_member(id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let entry = state.db.get_calendar_entry(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let name = entry.lesson.title.clone();
+    let body = ical::render_calendar(&name, std::slice::from_ref(&entry), &state.public_url);
+    Ok(ics_response(body, Some(&format!("lesson-{id}.ics"))))
+}
+
+// --- Attendance ---
+
+async fn lesson_attendance(state: &AppState, lesson: &Lesson) -> Result<Vec<AttendanceRecord>, StatusCode> {
+    let members = state.db.get_lesson_members(lesson.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let students: Vec<_> = members.into_iter().filter(|m| m.user_type == UserType::Student).collect();
+    let sessions = state.db.get_attendance_sessions(lesson.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let overrides = state.db.get_attendance_overrides(lesson.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(attendance::compute_attendance(lesson, &students, &sessions, &overrides, Utc::now()))
+}
+
+fn attendance_response(records: Vec<AttendanceRecord>, format: Option<String>, filename: &str) -> Result<Response, StatusCode> {
+    match format.as_deref() {
+        None | Some("json") => Ok(AxumJson(records).into_response()),
+        Some("csv") => {
+            let mut response = ([(header::CONTENT_TYPE, "text/csv; charset=utf-8")], attendance::to_csv(&records)).into_response();
+            if let Ok(value) = format!("attachment; filename=\"{filename}\"").parse() {
+                response.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+            }
+            Ok(response)
+        }
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+pub async fn get_lesson_attendance(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<ReportFormatQuery>,
+) -> Result<Response, StatusCode> {
+    let lesson = state.db.get_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let records = lesson_attendance(&state, &lesson).await?;
+    attendance_response(records, query.format, &format!("attendance-lesson-{id}.csv"))
+}
+
+// Attendance across every lesson of a classroom that has started and wasn't cancelled
+pub async fn get_classroom_attendance(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(classroom_id): Path<Uuid>,
+    Query(query): Query<ReportFormatQuery>,
+) -> Result<Response, StatusCode> {
+    get_owned_classroom(&state, &claims, classroom_id).await?;
+    let lessons = state.db.get_lessons_by_classroom(classroom_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let now = Utc::now();
+    let mut records = Vec::new();
+    for lesson in lessons {
+        if lesson.status == LessonStatus::Cancelled || lesson.scheduled_at > now {
+            continue;
+        }
+        records.extend(lesson_attendance(&state, &lesson).await?);
+    }
+    attendance_response(records, query.format, &format!("attendance-classroom-{classroom_id}.csv"))
+}
+
+// --- Transcripts ---
+
+// Chat, polls and moderation of a lesson in order, in the lesson teacher's
+// time zone. Anyone who can read the lesson's chat can export it; see
+// transcript::build for what students don't get.
+pub async fn get_lesson_transcript(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(lesson_id): Path<Uuid>,
+    Query(query): Query<ReportFormatQuery>,
+) -> Result<Response, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let user = state.db.get_user_by_id(user_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    websocket::authorize_room(&state, &user, Room::Lesson { id: lesson_id }).await?;
+    let lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let teachers = state.db.get_lesson_teacher_ids(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let teacher_view = user.user_type == UserType::Admin || teachers.contains(&user.id);
+    let teacher = state.db.get_user_by_id(lesson.teacher_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let tz = teacher.as_ref()
+        .and_then(|t| parse_time_zone(&t.time_zone))
+        .unwrap_or(chrono_tz::UTC);
+
+    let attendees = state.db.get_lesson_attendees(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let messages = state.db.get_all_chat_messages(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut polls = Vec::new();
+    for poll in state.db.get_polls(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        let counts = state.db.get_poll_counts(poll.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        polls.push((poll, counts));
+    }
+    let moderation = if teacher_view {
+        state.db.get_moderation_log(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        Vec::new()
+    };
+    let sources = transcript::Sources {
+        lesson,
+        teacher: teacher.map(|t| t.display_name()).unwrap_or_default(),
+        attendees,
+        messages,
+        polls,
+        moderation,
+    };
+    let transcript = transcript::build(sources, tz, teacher_view, Utc::now());
+
+    let (content_type, extension, body) = match query.format.as_deref() {
+        None | Some("json") => return Ok(AxumJson(transcript).into_response()),
+        Some("markdown") => ("text/markdown; charset=utf-8", "md", transcript::to_markdown(&transcript).into_bytes()),
+        Some("html") => ("text/html; charset=utf-8", "html", transcript::to_html(&transcript).into_bytes()),
+        Some("pdf") => ("application/pdf", "pdf", transcript::to_pdf(&transcript)),
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let mut response = ([(header::CONTENT_TYPE, content_type)], body).into_response();
+    if let Ok(value) = format!("attachment; filename=\"transcript-lesson-{lesson_id}.{extension}\"").parse() {
+        response.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+    }
+    Ok(response)
+}
+
+pub async fn set_attendance(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, user_id)): Path<(Uuid, Uuid)>,
+    AxumJson(payload): AxumJson<SetAttendanceRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    ensure_enrolled(&state, &lesson, user_id).await?;
+    let record = AttendanceOverride {
+        lesson_id,
+        user_id,
+        status: payload.status,
+        note: payload.note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty()),
+        set_by: claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        set_at: Utc::now(),
+    };
+    // Students marked manually still need to show up in the report
+    state.db.add_lesson_participant(lesson_id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.db.set_attendance_override(&record).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn clear_attendance(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path((lesson_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, StatusCode> {
+    let lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(&claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state.db.clear_attendance_override(lesson_id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// --- Lesson plans ---
+
+const MAX_PLAN_ITEMS: usize = 50;
+
+fn can_manage_plan(claims: &Claims, plan: &LessonPlan) -> bool {
+    match claims.user_type {
+        UserType::Admin => true,
+        UserType::Teacher => plan.teacher_id.to_string() == claims.sub,
+        UserType::Student => false,
+    }
+}
+
+// Drops blank entries from a free-text list
+fn clean_list(items: Vec<String>) -> Vec<String> {
+    items.into_iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+// Validates a plan payload and copies its content onto `plan`
+async fn apply_plan_request(state: &AppState, plan: &mut LessonPlan, payload: LessonPlanRequest) -> Result<(), StatusCode> {
+    let title = payload.title.trim();
+    if title.is_empty() || payload.activities.len() > MAX_PLAN_ITEMS || payload.materials.len() > MAX_PLAN_ITEMS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    for activity in &payload.activities {
+        if activity.title.trim().is_empty() || !(1..=480).contains(&activity.duration_minutes) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    for material in &payload.materials {
+        match (material.book_id, &material.url) {
+            (Some(book_id), _) => {
+                state.db.get_book(book_id).await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                    .ok_or(StatusCode::BAD_REQUEST)?;
+            }
+            (None, Some(url)) if url.starts_with("http://") || url.starts_with("https://") => {}
+            _ => return Err(StatusCode::BAD_REQUEST),
+        }
+    }
+    plan.title = title.to_string();
+    plan.level = payload.level.trim().to_string();
+    plan.objectives = clean_list(payload.objectives);
+    plan.vocabulary = clean_list(payload.vocabulary);
+    plan.grammar_points = clean_list(payload.grammar_points);
+    plan.activities = sqlx::types::Json(payload.activities);
+    plan.materials = sqlx::types::Json(payload.materials);
+    plan.updated_at = Utc::now();
+    Ok(())
+}
+
+// The lesson a plan is being attached to: must be the caller's and not have a plan yet
+async fn plan_target_lesson(state: &AppState, claims: &Claims, lesson_id: Uuid) -> Result<Lesson, StatusCode> {
+    let lesson = state.db.get_lesson(lesson_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_lesson(claims, &lesson) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if state.db.get_lesson_plan_for_lesson(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(lesson)
+}
+
+async fn get_managed_plan(state: &AppState, claims: &Claims, plan_id: Uuid) -> Result<LessonPlan, StatusCode> {
+    let plan = state.db.get_lesson_plan(plan_id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !can_manage_plan(claims, &plan) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(plan)
+}
+
+fn plan_view(plan: LessonPlan) -> LessonPlanView {
+    LessonPlanView { total_minutes: plan.total_minutes(), plan }
+}
+
+pub async fn create_lesson_plan(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    AxumJson(payload): AxumJson<LessonPlanRequest>,
+) -> Result<AxumJson<LessonPlanView>, StatusCode> {
+    if claims.user_type == UserType::Student {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let teacher_id = match payload.lesson_id {
+        Some(lesson_id) => plan_target_lesson(&state, &claims, lesson_id).await?.teacher_id,
+        None => claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    };
+    let now = Utc::now();
+    let mut plan = LessonPlan {
+        id: Uuid::new_v4(),
+        teacher_id,
+        lesson_id: payload.lesson_id,
+        source_plan_id: None,
+        title: String::new(),
+        level: String::new(),
+        objectives: Vec::new(),
+        vocabulary: Vec::new(),
+        grammar_points: Vec::new(),
+        activities: sqlx::types::Json(Vec::new()),
+        materials: sqlx::types::Json(Vec::new()),
+        created_at: now,
+        updated_at: now,
+    };
+    apply_plan_request(&state, &mut plan, payload).await?;
+    state.db.create_lesson_plan(&plan).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(plan_view(plan)))
+}
+
+// The caller's plans; `?templates=true` lists only reusable templates
+pub async fn list_lesson_plans(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Query(query): Query<LessonPlanListQuery>,
+) -> Result<AxumJson<Vec<LessonPlanView>>, StatusCode> {
+    if claims.user_type == UserType::Student {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let teacher_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let plans = state.db.get_lesson_plans_by_teacher(teacher_id, query.templates).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(plans.into_iter().map(plan_view).collect()))
+}
+
+pub async fn get_lesson_plan(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<AxumJson<LessonPlanView>, StatusCode> {
+    let plan = get_managed_plan(&state, &claims, id).await?;
+    Ok(AxumJson(plan_view(plan)))
+}
+
+pub async fn update_lesson_plan(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    AxumJson(payload): AxumJson<LessonPlanRequest>,
+) -> Result<AxumJson<LessonPlanView>, StatusCode> {
+    let mut plan = get_managed_plan(&state, &claims, id).await?;
+    apply_plan_request(&state, &mut plan, payload).await?;
+    state.db.update_lesson_plan(&plan).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(plan_view(plan)))
+}
+
+pub async fn delete_lesson_plan(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let plan = get_managed_plan(&state, &claims, id).await?;
+    state.db.delete_lesson_plan(plan.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Copies a plan (typically a template) onto a lesson, or into a new template
+pub async fn copy_lesson_plan(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+    AxumJson(payload): AxumJson<CopyLessonPlanRequest>,
+) -> Result<AxumJson<LessonPlanView>, StatusCode> {
+    let source = get_managed_plan(&state, &claims, id).await?;
+    let teacher_id = match payload.lesson_id {
+        Some(lesson_id) => plan_target_lesson(&state, &claims, lesson_id).await?.teacher_id,
+        None => source.teacher_id,
+    };
+    let now = Utc::now();
+    let plan = LessonPlan {
+        id: Uuid::new_v4(),
+        teacher_id,
+        lesson_id: payload.lesson_id,
+        source_plan_id: Some(source.id),
+        created_at: now,
+        updated_at: now,
+        ..source
+    };
+    state.db.create_lesson_plan(&plan).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(plan_view(plan)))
+}
+
+// The plan of a lesson, visible to everyone taking part in it
+pub async fn get_plan_for_lesson(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<AxumJson<LessonPlanView>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if claims.user_type != UserType::Admin
+        && !state.db.is_lesson_member(id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let plan = state.db.get_lesson_plan_for_lesson(id).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(AxumJson(plan_view(plan)))
+}
+
+// --- Notifications ---
+
+pub async fn list_notifications(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<AxumJson<Vec<Notification>>, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let notifications = state.db.get_notifications_for_user(user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(AxumJson(notifications))
+}
+
+pub async fn mark_notification_read(
+    State(state): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Path(id): Path<Uuid>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = claims.sub.parse().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.db.mark_notification_read(id, user_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+// --- Admin ---
+
+// Live WebSocket room counts and how many events slow connections have dropped
+pub async fn get_realtime_metrics(
+    Extension(claims): Extension<Claims>,
+) -> Result<AxumJson<rooms::RoomMetrics>, StatusCode> {
+    if claims.user_type != UserType::Admin {
+        return Err(StatusCode::FORBIDDEN);
     }
+    Ok(AxumJson(rooms::metrics()))
 }
@@ -0,0 +1,90 @@
+use chrono::{DateTime, Utc};
+
+use crate::models::{CalendarEntry, LessonStatus};
+
+const PRODID: &str = "-//ESL Learning Platform//Lessons//EN";
+
+// TEXT values must escape backslashes, semicolons, commas and newlines (RFC 5545 3.3.11)
+fn escape_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn format_utc(t: DateTime<Utc>) -> String {
+    t.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// Content lines are folded at 75 octets without splitting a UTF-8 character
+fn push_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        let len = c.len_utf8();
+        if width + len > 75 {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += len;
+    }
+    out.push_str("\r\n");
+}
+
+// Zoom link when the classroom has one, otherwise the classroom page in the app
+pub fn join_url(entry: &CalendarEntry, public_url: &str) -> String {
+    entry
+        .zoom_join_url
+        .clone()
+        .unwrap_or_else(|| format!("{}/api/classroom/{}", public_url.trim_end_matches('/'), entry.lesson.classroom_id))
+}
+
+fn push_event(out: &mut String, entry: &CalendarEntry, public_url: &str, now: DateTime<Utc>) {
+    let lesson = &entry.lesson;
+    let join_url = join_url(entry, public_url);
+    let mut description = lesson.description.clone();
+    if lesson.status == LessonStatus::Cancelled {
+        if let Some(reason) = &lesson.cancellation_reason {
+            description = format!("Cancelled: {reason}\n\n{description}");
+        }
+    }
+    description = format!("{description}\n\nJoin: {join_url}");
+
+    push_line(out, "BEGIN:VEVENT");
+    push_line(out, &format!("UID:lesson-{}@esl-learning-platform", lesson.id));
+    push_line(out, &format!("DTSTAMP:{}", format_utc(now)));
+    push_line(out, &format!("DTSTART:{}", format_utc(lesson.scheduled_at)));
+    push_line(out, &format!("DTEND:{}", format_utc(lesson.ends_at())));
+    push_line(out, &format!("SUMMARY:{}", escape_text(&lesson.title)));
+    push_line(out, &format!("DESCRIPTION:{}", escape_text(description.trim())));
+    push_line(out, &format!("URL:{join_url}"));
+    push_line(out, &format!("LOCATION:{}", escape_text(&join_url)));
+    let status = if lesson.status == LessonStatus::Cancelled { "CANCELLED" } else { "CONFIRMED" };
+    push_line(out, &format!("STATUS:{status}"));
+    push_line(out, "END:VEVENT");
+}
+
+// Renders a VCALENDAR with one VEVENT per entry
+pub fn render_calendar(name: &str, entries: &[CalendarEntry], public_url: &str) -> String {
+    let now = Utc::now();
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, &format!("PRODID:{PRODID}"));
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    push_line(&mut out, "METHOD:PUBLISH");
+    push_line(&mut out, &format!("X-WR-CALNAME:{}", escape_text(name)));
+    for entry in entries {
+        push_event(&mut out, entry, public_url, now);
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    out
+}
@@ -1,23 +1,40 @@
 use axum::{
-    routing::{get, post, delete},
+    routing::{get, post, delete, patch, put},
     Router,
     middleware,
 };
 use std::sync::Arc;
 use tower_http::{services::ServeDir, cors::CorsLayer};
-use tracing_subscriber;
 
+mod attendance;
 mod auth;
+mod content_filter;
 mod database;
 mod handlers;
+mod ical;
 mod models;
+mod notifier;
+mod pdf;
+mod presence;
+mod protocol;
+mod recurrence;
+mod rooms;
+mod scheduler;
+mod throttle;
+mod timezone;
+mod transcript;
 mod websocket;
+mod whiteboard;
 
 use database::Database;
 
 #[derive(Clone)]
 pub struct AppState {
     db: Arc<Database>,
+    // Externally reachable base URL, used in links we hand out (calendar feeds)
+    public_url: String,
+    // Fans WebSocket room events out to every instance
+    broker: Arc<dyn rooms::RoomBroker>,
 }
 
 #[tokio::main]
@@ -31,12 +48,34 @@ async fn main() -> anyhow::Result<()> {
     let db = Database::new(&database_url).await?;
     db.migrate().await?;
 
-    let state = AppState { db: Arc::new(db) };
+    let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+    let public_url = std::env::var("PUBLIC_URL")
+        .unwrap_or_else(|_| format!("http://localhost:{}", port));
+
+    let db = Arc::new(db);
+    let notifiers = Arc::new(notifier::NotifierRegistry::from_env(db.clone())?);
+    scheduler::spawn(db.clone(), notifiers);
+    let broker = rooms::broker_from_env(db.clone()).await?;
+
+    let state = AppState { db, public_url, broker };
+    websocket::spawn_breakout_timer(state.clone());
 
     // Protected routes that require authentication
     let protected_routes = Router::new()
         .route("/api/dashboard", get(handlers::dashboard))
+        // --- Classrooms ---
+        .route("/api/classroom", post(handlers::create_classroom))
+        .route("/api/classroom", get(handlers::list_teacher_classrooms))
         .route("/api/classroom/:id", get(handlers::classroom))
+        .route("/api/classroom/:id", patch(handlers::update_classroom))
+        // --- Classroom enrollment ---
+        .route("/api/classroom/:classroom_id/students", get(handlers::list_classroom_students))
+        .route("/api/classroom/:classroom_id/students/:student_id", post(handlers::enroll_student))
+        .route("/api/classroom/:classroom_id/students/:student_id", delete(handlers::unenroll_student))
+        .route("/api/classroom/:classroom_id/co-teachers", get(handlers::list_co_teachers))
+        .route("/api/classroom/:classroom_id/participation", get(handlers::get_classroom_participation))
+        .route("/api/classroom/:classroom_id/co-teachers/:teacher_id", post(handlers::add_co_teacher))
+        .route("/api/classroom/:classroom_id/co-teachers/:teacher_id", delete(handlers::remove_co_teacher))
         // --- Zoom meeting management ---
         .route("/api/classroom/:classroom_id/zoom", post(handlers::create_zoom_meeting))
         .route("/api/classroom/:classroom_id/zoom", delete(handlers::delete_zoom_meeting))
@@ -48,24 +87,80 @@ async fn main() -> anyhow::Result<()> {
         // Lesson and chat endpoints
         .route("/api/lesson", post(handlers::create_lesson))
         .route("/api/lesson", get(handlers::list_teacher_lessons))
+        .route("/api/lesson/conflicts", get(handlers::check_lesson_conflicts))
         .route("/api/lesson/:id", get(handlers::get_lesson))
+        .route("/api/lesson/:id", patch(handlers::update_lesson))
+        .route("/api/lesson/:id/cancel", post(handlers::cancel_lesson))
+        .route("/api/lesson/:id/history", get(handlers::get_lesson_history))
+        // Recurring lesson series
+        .route("/api/lesson-series", post(handlers::create_lesson_series))
+        .route("/api/lesson-series/:id", get(handlers::get_lesson_series))
+        .route("/api/lesson-series/:id/lessons/:lesson_id", patch(handlers::update_series_occurrence))
+        .route("/api/lesson-series/:id/exceptions", post(handlers::add_series_exceptions))
         .route("/api/lesson/:lesson_id/chat/:message_id/delete", post(handlers::delete_chat_message))
+        .route("/api/lesson/:lesson_id/chat/:message_id/edits", get(handlers::get_chat_message_edits))
+        .route("/api/lesson/:lesson_id/private-messages", get(handlers::get_private_messages))
+        .route("/api/lesson/:lesson_id/speaking-turns", get(handlers::get_speaking_turns))
+        .route("/api/lesson/:lesson_id/polls", get(handlers::list_polls))
+        .route("/api/lesson/:lesson_id/whiteboard.svg", get(handlers::whiteboard_svg))
+        .route("/api/lesson/:lesson_id/polls/:poll_id/responses", get(handlers::get_poll_responses))
+        .route("/api/lesson/:lesson_id/breakouts", get(handlers::list_breakouts))
+        .route("/api/lesson/:lesson_id/breakouts/:room_id/chat", get(handlers::get_breakout_chat))
+        .route("/api/lesson/:id/chat", get(handlers::get_chat_history))
+        .route("/api/lesson/:id/participants", get(handlers::get_lesson_participants))
         .route("/api/lesson/:lesson_id/chat/close", post(handlers::close_chat))
+        .route("/api/lesson/:lesson_id/chat/reopen", post(handlers::reopen_chat))
+        .route("/api/lesson/:lesson_id/chat/settings", put(handlers::set_chat_settings))
+        .route("/api/classroom/:classroom_id/chat-filters", get(handlers::list_chat_filter_rules))
+        .route("/api/classroom/:classroom_id/chat-filters", post(handlers::create_chat_filter_rule))
+        .route("/api/classroom/:classroom_id/chat-filters/:rule_id", delete(handlers::delete_chat_filter_rule))
+        .route("/api/classroom/:classroom_id/moderation-queue", get(handlers::get_moderation_queue))
+        .route("/api/moderation-queue/:id/review", post(handlers::review_flagged_message))
         .route("/api/lesson/:lesson_id/participant/:user_id/mute", post(handlers::mute_participant))
         .route("/api/lesson/:lesson_id/participant/:user_id/unmute", post(handlers::unmute_participant))
+        .route("/api/me", patch(handlers::update_profile))
+        .route("/api/me/reminders", get(handlers::get_reminder_preferences))
+        .route("/api/me/reminders", put(handlers::set_reminder_preferences))
+        // Calendar subscriptions
+        .route("/api/calendar/feed", get(handlers::get_calendar_feed))
+        .route("/api/calendar/feed", post(handlers::create_calendar_feed))
+        .route("/api/calendar/feed", delete(handlers::revoke_calendar_feed))
+        .route("/api/lesson/:id/ics", get(handlers::lesson_ics))
+        // Attendance
+        .route("/api/lesson/:id/attendance", get(handlers::get_lesson_attendance))
+        .route("/api/lesson/:id/attendance/:user_id", put(handlers::set_attendance))
+        .route("/api/lesson/:id/attendance/:user_id", delete(handlers::clear_attendance))
+        .route("/api/classroom/:classroom_id/attendance", get(handlers::get_classroom_attendance))
+        // Transcripts
+        .route("/api/lesson/:lesson_id/transcript", get(handlers::get_lesson_transcript))
+        // Lesson plans and templates
+        .route("/api/lesson-plans", post(handlers::create_lesson_plan))
+        .route("/api/lesson-plans", get(handlers::list_lesson_plans))
+        .route("/api/lesson-plans/:id", get(handlers::get_lesson_plan))
+        .route("/api/lesson-plans/:id", put(handlers::update_lesson_plan))
+        .route("/api/lesson-plans/:id", delete(handlers::delete_lesson_plan))
+        .route("/api/lesson-plans/:id/copy", post(handlers::copy_lesson_plan))
+        .route("/api/lesson/:id/plan", get(handlers::get_plan_for_lesson))
+        // --- Notifications ---
+        .route("/api/notifications", get(handlers::list_notifications))
+        .route("/api/notifications/:id/read", post(handlers::mark_notification_read))
+        // Admin
+        .route("/api/admin/realtime", get(handlers::get_realtime_metrics))
         .layer(middleware::from_fn_with_state(state.clone(), auth::auth_middleware));
 
     let app = Router::new()
         .route("/", get(handlers::home))
         .route("/api/auth/register", post(handlers::register))
         .route("/api/auth/login", post(handlers::login))
+        // Authenticated by the secret feed token in the URL, not the JWT
+        .route("/calendar/:token", get(handlers::calendar_feed))
         .merge(protected_routes)
         .route("/ws", get(websocket::websocket_handler))
+        .route("/ws/schema", get(websocket::protocol_schema))
         .nest_service("/static", ServeDir::new("static"))
         .layer(CorsLayer::permissive())
         .with_state(state);
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
     let listener = tokio::net::TcpListener::bind(&format!("0.0.0.0:{}", port)).await?;
     
     println!("🚀 ESL Learning Platform running on port {}", port);
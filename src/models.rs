@@ -1,4 +1,4 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use uuid::Uuid;
@@ -18,6 +18,14 @@ pub struct User {
     pub zoom_access_token: Option<String>,
     pub zoom_refresh_token: Option<String>,
     pub zoom_token_expiry: Option<chrono::DateTime<chrono::Utc>>,
+    pub time_zone: String, // IANA name, e.g. "Europe/Istanbul"
+}
+
+impl User {
+    // Name shown to others in chat and rosters
+    pub fn display_name(&self) -> String {
+        format!("{} {}", self.first_name, self.last_name).trim().to_string()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type, PartialEq)]
@@ -47,6 +55,8 @@ pub struct RegisterRequest {
     pub user_type: UserType,
     pub first_name: String,
     pub last_name: String,
+    #[serde(default)]
+    pub time_zone: Option<String>,
 }
 
 // Helper for deserializing user_type from string
@@ -82,6 +92,12 @@ pub struct UserInfo {
     pub user_type: UserType,
     pub first_name: String,
     pub last_name: String,
+    pub time_zone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub time_zone: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -95,6 +111,14 @@ pub struct Classroom {
     // --- Zoom integration fields ---
     pub zoom_meeting_id: Option<String>,
     pub zoom_join_url: Option<String>,
+    pub time_zone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateClassroomRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub time_zone: Option<String>,
 }
 
 // For student meeting requests
@@ -126,16 +150,360 @@ pub struct Lesson {
     pub title: String,
     pub description: String,
     pub scheduled_at: DateTime<Utc>,
-    pub is_active: bool,
+    #[serde(default = "default_lesson_duration")]
+    pub duration_minutes: i32,
+    #[serde(default)]
+    pub status: LessonStatus,
     pub chat_closed: bool,
+    // Chat limits for students: at most one message per this many seconds, and this many characters
+    #[serde(default)]
+    pub chat_slow_mode_seconds: Option<i32>,
+    #[serde(default)]
+    pub chat_max_length: Option<i32>,
+    #[serde(default)]
+    pub whiteboard_students_can_draw: bool,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub cancellation_reason: Option<String>,
+    // Set when the lesson was materialized from a LessonSeries
+    #[serde(default)]
+    pub series_id: Option<Uuid>,
+    #[serde(default)]
+    pub occurrence_start: Option<DateTime<Utc>>,
+}
+
+pub const DEFAULT_LESSON_DURATION: i32 = 60;
+
+fn default_lesson_duration() -> i32 {
+    DEFAULT_LESSON_DURATION
+}
+
+impl Lesson {
+    pub fn ends_at(&self) -> DateTime<Utc> {
+        self.scheduled_at + chrono::Duration::minutes(self.duration_minutes as i64)
+    }
+}
+
+// A lesson with its end time and both times rendered in a particular time zone
+#[derive(Debug, Serialize)]
+pub struct LessonView {
+    #[serde(flatten)]
+    pub lesson: Lesson,
+    pub ends_at: DateTime<Utc>,
+    pub time_zone: String,
+    pub local_scheduled_at: String,
+    pub local_ends_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeZoneQuery {
+    pub tz: Option<String>,
+}
+
+// Overlapping lessons for the same teacher are rejected unless this is set
+#[derive(Debug, Default, Deserialize)]
+pub struct ConflictQuery {
+    #[serde(default)]
+    pub allow_conflicts: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConflictCheckQuery {
+    pub start: DateTime<Utc>,
+    #[serde(default = "default_lesson_duration")]
+    pub duration_minutes: i32,
+    pub exclude: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "lesson_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum LessonStatus {
+    #[default]
+    Scheduled,
+    Live,
+    Ended,
+    Cancelled,
+}
+
+impl LessonStatus {
+    // scheduled -> live -> ended, and anything not yet finished may be cancelled
+    pub fn can_transition_to(self, next: LessonStatus) -> bool {
+        matches!(
+            (self, next),
+            (LessonStatus::Scheduled, LessonStatus::Live)
+                | (LessonStatus::Live, LessonStatus::Ended)
+                | (LessonStatus::Scheduled, LessonStatus::Cancelled)
+                | (LessonStatus::Live, LessonStatus::Cancelled)
+        )
+    }
+
+    pub fn is_final(self) -> bool {
+        matches!(self, LessonStatus::Ended | LessonStatus::Cancelled)
+    }
+}
+
+impl fmt::Display for LessonStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            LessonStatus::Scheduled => "scheduled",
+            LessonStatus::Live => "live",
+            LessonStatus::Ended => "ended",
+            LessonStatus::Cancelled => "cancelled",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateLessonRequest {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub scheduled_at: Option<DateTime<Utc>>,
+    pub duration_minutes: Option<i32>,
+    pub status: Option<LessonStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelLessonRequest {
+    pub reason: Option<String>,
+}
+
+// One row per edit, reschedule, status change or cancellation of a lesson
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonChange {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub changed_by: Uuid,
+    pub action: String, // "updated", "rescheduled", "status", "cancelled"
+    pub details: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+// A recurring lesson; individual occurrences live in `lessons` with series_id set
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonSeries {
+    pub id: Uuid,
+    pub classroom_id: Uuid,
+    pub teacher_id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub starts_at: DateTime<Utc>,
+    pub duration_minutes: i32,
+    pub time_zone: String, // recurrence is expanded in this zone so DST doesn't shift lessons
+    pub rrule: String,
+    pub exception_dates: Vec<NaiveDate>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateLessonSeriesRequest {
+    pub classroom_id: Uuid,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub starts_at: DateTime<Utc>,
+    #[serde(default = "default_lesson_duration")]
+    pub duration_minutes: i32,
+    pub time_zone: Option<String>,
+    pub rrule: String,
+    #[serde(default)]
+    pub exception_dates: Vec<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesEditScope {
+    This,
+    Following,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateSeriesOccurrenceRequest {
+    pub scope: SeriesEditScope,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub scheduled_at: Option<DateTime<Utc>>,
+    pub duration_minutes: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddSeriesExceptionsRequest {
+    pub dates: Vec<NaiveDate>,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LessonSeriesDetail {
+    pub series: LessonSeries,
+    pub lessons: Vec<LessonView>,
+}
+
+// A timed block within a lesson plan, e.g. "Warm-up, 10 minutes"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanActivity {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub duration_minutes: i32,
+}
+
+// Either pages of a book from the library or any other link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlanMaterial {
+    #[serde(default)]
+    pub book_id: Option<Uuid>,
+    #[serde(default)]
+    pub pages: Option<String>, // "12-15"
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub label: String,
+}
+
+// A lesson plan is attached to a lesson, or is a reusable template when lesson_id is NULL
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonPlan {
+    pub id: Uuid,
+    pub teacher_id: Uuid,
+    pub lesson_id: Option<Uuid>,
+    pub source_plan_id: Option<Uuid>,
+    pub title: String,
+    pub level: String,
+    pub objectives: Vec<String>,
+    pub vocabulary: Vec<String>,
+    pub grammar_points: Vec<String>,
+    pub activities: sqlx::types::Json<Vec<PlanActivity>>,
+    pub materials: sqlx::types::Json<Vec<PlanMaterial>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl LessonPlan {
+    pub fn total_minutes(&self) -> i32 {
+        self.activities.iter().map(|a| a.duration_minutes).sum()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LessonPlanRequest {
+    pub title: String,
+    #[serde(default)]
+    pub level: String,
+    #[serde(default)]
+    pub objectives: Vec<String>,
+    #[serde(default)]
+    pub vocabulary: Vec<String>,
+    #[serde(default)]
+    pub grammar_points: Vec<String>,
+    #[serde(default)]
+    pub activities: Vec<PlanActivity>,
+    #[serde(default)]
+    pub materials: Vec<PlanMaterial>,
+    // Only used on create; omit to save a template
+    #[serde(default)]
+    pub lesson_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LessonPlanView {
+    #[serde(flatten)]
+    pub plan: LessonPlan,
+    pub total_minutes: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LessonPlanListQuery {
+    #[serde(default)]
+    pub templates: bool,
+}
+
+// Copy a plan onto a lesson, or into a new template when lesson_id is omitted
+#[derive(Debug, Deserialize)]
+pub struct CopyLessonPlanRequest {
+    #[serde(default)]
+    pub lesson_id: Option<Uuid>,
+}
+
+// A lesson as it appears in a calendar feed, with its classroom's Zoom link
+#[derive(Debug, Clone, FromRow)]
+pub struct CalendarEntry {
+    #[sqlx(flatten)]
+    pub lesson: Lesson,
+    pub zoom_join_url: Option<String>,
+}
+
+// Secret token for an iCal subscription URL; independent of the JWT so it can
+// be revoked without logging the user out
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct CalendarFeedToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalendarFeedInfo {
+    pub url: String,
     pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
-pub struct LessonParticipant {
+pub struct ClassroomEnrollment {
+    pub classroom_id: Uuid,
+    pub student_id: Uuid,
+    pub enrolled_at: DateTime<Utc>,
+}
+
+// A teacher who helps run another teacher's classroom: they can join its rooms
+// and read private messages shared with the lesson's teachers
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ClassroomCoTeacher {
+    pub classroom_id: Uuid,
+    pub teacher_id: Uuid,
+    pub added_at: DateTime<Utc>,
+}
+
+// In-app notification shown on the dashboard
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Notification {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub kind: String, // "lesson_rescheduled", "lesson_cancelled", ...
+    pub title: String,
+    pub body: String,
+    pub lesson_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub read_at: Option<DateTime<Utc>>,
+}
+
+// Minutes before a lesson at which reminders go out, and where they are delivered
+pub const DEFAULT_REMINDER_OFFSETS: [i32; 2] = [24 * 60, 15];
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ReminderPreferences {
+    pub offsets_minutes: Vec<i32>,
+    pub channels: Vec<String>, // "in_app", "email", "webhook"
+}
+
+impl Default for ReminderPreferences {
+    fn default() -> Self {
+        ReminderPreferences {
+            offsets_minutes: DEFAULT_REMINDER_OFFSETS.to_vec(),
+            channels: vec![crate::notifier::CHANNEL_IN_APP.to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DueReminder {
     pub lesson_id: Uuid,
     pub user_id: Uuid,
-    pub is_muted: bool,
+    pub offset_minutes: i32,
+    pub scheduled_at: DateTime<Utc>,
+    pub channels: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -143,8 +511,386 @@ pub struct LessonChatMessage {
     pub id: Uuid,
     pub lesson_id: Uuid,
     pub user_id: Uuid,
-    pub username: String,
+    pub username: String, // sender's name from `users` at the time of sending
+    pub user_type: UserType,
     pub message: String,
     pub timestamp: DateTime<Utc>,
     pub deleted: bool,
+    pub seq: i64, // assigned by the database on insert
+    pub edited_at: Option<DateTime<Utc>>,
+    pub reply_to: Option<Uuid>,
+}
+
+// A previous version of an edited chat message
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ChatMessageEdit {
+    pub previous_message: String,
+    pub edited_at: DateTime<Utc>,
+}
+
+// A message between a teacher and a student during a lesson, outside the
+// public chat. `include_co_teachers` shares it with everyone teaching the lesson.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonPrivateMessage {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub sender_id: Uuid,
+    pub sender_name: String,
+    pub sender_type: UserType,
+    pub recipient_id: Uuid,
+    pub recipient_name: String,
+    pub message: String,
+    pub include_co_teachers: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+// A hand up in a lesson's queue; `turns` counts the speaking turns the person
+// has already had in the lesson
+#[derive(Debug, Clone, FromRow)]
+pub struct RaisedHand {
+    pub user_id: Uuid,
+    pub username: String,
+    pub raised_at: DateTime<Utc>,
+    pub turns: i64,
+}
+
+// The teacher called on someone; `raised_at` is when their hand went up, if it was
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct SpeakingTurn {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub called_by: Uuid,
+    pub raised_at: Option<DateTime<Utc>>,
+    pub called_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ParticipationSummary {
+    pub user_id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub turns: i64,
+    pub turns_from_raised_hand: i64,
+    pub last_called_at: Option<DateTime<Utc>>,
+}
+
+// A multiple-choice question the teacher put to a lesson; with a
+// `correct_option` it's a quiz. Options are numbered from 0.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonPoll {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub created_by: Uuid,
+    pub question: String,
+    pub options: Vec<String>,
+    pub correct_option: Option<i32>,
+    pub revealed: bool, // results (and the answer) shown to the class
+    pub created_at: DateTime<Utc>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct PollResponse {
+    pub user_id: Uuid,
+    pub username: String,
+    pub option: i32,
+    pub answered_at: DateTime<Utc>,
+    pub correct: Option<bool>,
+}
+
+// A moderation action taken in a lesson; `event` is a protocol::ModerationEvent
+#[derive(Debug, Clone, FromRow)]
+pub struct ModerationLogEntry {
+    pub moderator_id: Uuid,
+    pub moderator_name: String,
+    pub event: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+}
+
+// One entry of a lesson's whiteboard log; `op` is a protocol::WhiteboardOp
+#[derive(Debug, Clone, FromRow)]
+pub struct WhiteboardOpRecord {
+    pub seq: i64,
+    pub user_id: Uuid,
+    pub op: serde_json::Value,
+}
+
+// A breakout room split from a lesson, open until `closed_at`. Rooms opened
+// together share `ends_at`, the time the teacher gave them.
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonBreakoutRoom {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub name: String,
+    pub position: i32,
+    pub opened_at: DateTime<Utc>,
+    pub ends_at: Option<DateTime<Utc>>,
+    pub closed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct BreakoutRoomMember {
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String, // name from `users` when the rooms were opened
+}
+
+// A breakout room with who was put in it, for reviewing after the lesson
+#[derive(Debug, Serialize)]
+pub struct BreakoutRoomView {
+    #[serde(flatten)]
+    pub room: LessonBreakoutRoom,
+    pub members: Vec<BreakoutRoomMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonBreakoutMessage {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub user_type: UserType,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+    // Set when a teacher removed the message; students no longer see it
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub deleted_by: Option<Uuid>,
+}
+
+// The lesson's shared video; `position` is where it was at `updated_at`
+#[derive(Debug, Clone, FromRow)]
+pub struct LessonPlayback {
+    pub lesson_id: Uuid,
+    pub video_url: String,
+    pub playing: bool,
+    pub position: f64,
+    pub rate: f64,
+    pub updated_by: Uuid,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl LessonPlayback {
+    // Seconds into the video at `now`
+    pub fn position_at(&self, now: DateTime<Utc>) -> f64 {
+        if !self.playing {
+            return self.position;
+        }
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.position + elapsed * self.rate
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct ChatReaction {
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub emoji: String,
+}
+
+// Pages through a lesson's chat by sequence number
+#[derive(Debug, Deserialize)]
+pub struct ChatHistoryQuery {
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// Chat limits a teacher sets on a lesson; null turns a limit off
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ChatSettings {
+    pub slow_mode_seconds: Option<i32>,
+    pub max_length: Option<i32>,
+}
+
+// What a student's chat message is checked against
+#[derive(Debug, Clone, FromRow)]
+pub struct ChatPolicy {
+    pub classroom_id: Uuid,
+    pub chat_closed: bool,
+    pub is_muted: bool,
+    pub slow_mode_seconds: Option<i32>,
+    pub max_length: Option<i32>,
+    // The classroom's chat filter rules, to tell whether cached ones are current
+    pub filter_rule_ids: Vec<Uuid>,
+}
+
+// What a chat filter rule matches. `word` and `regex` rules carry a pattern;
+// the others are built in.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "chat_filter_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatFilterKind {
+    Word,
+    Regex,
+    Link,
+    Phone,
+    Email,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "chat_filter_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatFilterAction {
+    // Replace the match with asterisks
+    Mask,
+    // Reject the whole message
+    Block,
+    // Post it, but queue it for the teacher to review
+    Flag,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ChatFilterRule {
+    pub id: Uuid,
+    pub classroom_id: Uuid,
+    pub kind: ChatFilterKind,
+    pub pattern: Option<String>,
+    pub action: ChatFilterAction,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateChatFilterRuleRequest {
+    pub kind: ChatFilterKind,
+    pub pattern: Option<String>,
+    pub action: ChatFilterAction,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "chat_flag_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum FlagStatus {
+    Pending,
+    // Reviewed and left in the chat
+    Dismissed,
+    // Reviewed and deleted from the chat
+    Removed,
+}
+
+// A chat message a `flag` rule matched, waiting in the classroom's moderation queue
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct FlaggedChatMessage {
+    pub id: Uuid,
+    pub classroom_id: Uuid,
+    pub lesson_id: Option<Uuid>,
+    // Set when the message was said in one of the lesson's breakout rooms
+    pub breakout_room_id: Option<Uuid>,
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub message: String,
+    // Which rules matched, e.g. "word: stupid"
+    pub reasons: Vec<String>,
+    pub status: FlagStatus,
+    pub created_at: DateTime<Utc>,
+    pub reviewed_by: Option<Uuid>,
+    pub reviewed_at: Option<DateTime<Utc>>,
+    // Whether the message has since been deleted from its chat
+    pub message_deleted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationQueueQuery {
+    pub status: Option<FlagStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReviewFlagRequest {
+    pub status: FlagStatus,
+}
+
+// Someone who belongs to a lesson (teacher, enrolled student or participant)
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct LessonMember {
+    pub user_id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub user_type: UserType,
+}
+
+// One WebSocket connection to a room, on whichever instance holds it
+#[derive(Debug, Clone, FromRow)]
+pub struct RoomConnection {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub user_type: UserType,
+    pub joined_at: DateTime<Utc>,
+    pub idle: bool,
+}
+
+// One continuous WebSocket connection of a user to a lesson room
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AttendanceSession {
+    pub id: Uuid,
+    pub lesson_id: Uuid,
+    pub user_id: Uuid,
+    pub joined_at: DateTime<Utc>,
+    pub left_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, sqlx::Type, PartialEq)]
+#[sqlx(type_name = "attendance_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AttendanceStatus {
+    Present,
+    Late,
+    LeftEarly,
+    Absent,
+}
+
+impl fmt::Display for AttendanceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            AttendanceStatus::Present => "present",
+            AttendanceStatus::Late => "late",
+            AttendanceStatus::LeftEarly => "left_early",
+            AttendanceStatus::Absent => "absent",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+// Teacher's manual correction of a computed attendance status
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct AttendanceOverride {
+    pub lesson_id: Uuid,
+    pub user_id: Uuid,
+    pub status: AttendanceStatus,
+    pub note: Option<String>,
+    pub set_by: Uuid,
+    pub set_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAttendanceRequest {
+    pub status: AttendanceStatus,
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportFormatQuery {
+    pub format: Option<String>, // "json" (default) or "csv"; transcripts take "markdown", "html" or "pdf"
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AttendanceRecord {
+    pub lesson_id: Uuid,
+    pub lesson_title: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub status: AttendanceStatus,
+    pub computed_status: AttendanceStatus,
+    pub overridden: bool,
+    pub note: Option<String>,
+    pub first_joined_at: Option<DateTime<Utc>>,
+    pub last_left_at: Option<DateTime<Utc>>,
+    pub minutes_present: i64,
 }
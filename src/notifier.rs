@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport,
+    Message, Tokio1Executor,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::models::Notification;
+
+pub const CHANNEL_IN_APP: &str = "in_app";
+pub const CHANNEL_EMAIL: &str = "email";
+pub const CHANNEL_WEBHOOK: &str = "webhook";
+pub const KNOWN_CHANNELS: [&str; 3] = [CHANNEL_IN_APP, CHANNEL_EMAIL, CHANNEL_WEBHOOK];
+
+// What gets delivered, independent of the channel
+#[derive(Debug, Clone, Serialize)]
+pub struct OutgoingNotification {
+    pub user_id: Uuid,
+    pub email: String,
+    pub first_name: String,
+    pub kind: String,
+    pub title: String,
+    pub body: String,
+    pub lesson_id: Option<Uuid>,
+}
+
+// Unset and empty variables are treated the same, so compose files can pass `${VAR:-}`
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+pub trait Notifier: Send + Sync {
+    fn send<'a>(&'a self, notification: &'a OutgoingNotification) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+// Stores the notification for the dashboard
+pub struct InAppNotifier {
+    db: Arc<Database>,
+}
+
+impl Notifier for InAppNotifier {
+    fn send<'a>(&'a self, n: &'a OutgoingNotification) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.db
+                .create_notification(&Notification {
+                    id: Uuid::new_v4(),
+                    user_id: n.user_id,
+                    kind: n.kind.clone(),
+                    title: n.title.clone(),
+                    body: n.body.clone(),
+                    lesson_id: n.lesson_id,
+                    created_at: Utc::now(),
+                    read_at: None,
+                })
+                .await
+        })
+    }
+}
+
+// Sends over SMTP; configured with SMTP_HOST, SMTP_USERNAME, SMTP_PASSWORD and SMTP_FROM
+pub struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl EmailNotifier {
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(host) = env_var("SMTP_HOST") else {
+            return Ok(None);
+        };
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?;
+        if let (Some(username), Some(password)) = (env_var("SMTP_USERNAME"), env_var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        let from = env_var("SMTP_FROM")
+            .unwrap_or_else(|| "ESL Learning Platform <no-reply@localhost>".to_string())
+            .parse()?;
+        Ok(Some(EmailNotifier { transport: builder.build(), from }))
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn send<'a>(&'a self, n: &'a OutgoingNotification) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let to = Mailbox::new(Some(n.first_name.clone()), n.email.parse()?);
+            let email = Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(&n.title)
+                .body(n.body.clone())?;
+            self.transport.send(email).await?;
+            Ok(())
+        })
+    }
+}
+
+// POSTs the notification as JSON to NOTIFY_WEBHOOK_URL (SMS gateways, chat bots, ...)
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Notifier for WebhookNotifier {
+    fn send<'a>(&'a self, n: &'a OutgoingNotification) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.client.post(&self.url).json(n).send().await?.error_for_status()?;
+            Ok(())
+        })
+    }
+}
+
+// The channels this instance can deliver on. In-app is always available; email
+// and webhook only when configured.
+pub struct NotifierRegistry {
+    notifiers: HashMap<&'static str, Arc<dyn Notifier>>,
+}
+
+impl NotifierRegistry {
+    pub fn from_env(db: Arc<Database>) -> anyhow::Result<Self> {
+        let mut notifiers: HashMap<&'static str, Arc<dyn Notifier>> = HashMap::new();
+        notifiers.insert(CHANNEL_IN_APP, Arc::new(InAppNotifier { db }));
+        if let Some(email) = EmailNotifier::from_env()? {
+            notifiers.insert(CHANNEL_EMAIL, Arc::new(email));
+        }
+        if let Some(url) = env_var("NOTIFY_WEBHOOK_URL") {
+            notifiers.insert(CHANNEL_WEBHOOK, Arc::new(WebhookNotifier { client: reqwest::Client::new(), url }));
+        }
+        Ok(NotifierRegistry { notifiers })
+    }
+
+    pub fn get(&self, channel: &str) -> Option<Arc<dyn Notifier>> {
+        self.notifiers.get(channel).cloned()
+    }
+}
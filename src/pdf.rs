@@ -0,0 +1,236 @@
+// Just enough PDF to print a text document: A4 pages of Helvetica, a bold
+// title and lines wrapped to the page width. The standard fonts only cover
+// WinAnsiEncoding (Latin-1 plus typographic quotes, dashes, the euro sign and
+// a few more), so anything else, e.g. Cyrillic, CJK or emoji, is printed as "?".
+use std::fmt::Write;
+
+const PAGE_WIDTH: u32 = 595;
+const PAGE_HEIGHT: u32 = 842;
+const MARGIN: u32 = 50;
+const FONT_SIZE: u32 = 10;
+const TITLE_SIZE: u32 = 16;
+const LEADING: u32 = 14;
+// Helvetica averages about half its size per character
+const WRAP_AT: usize = ((PAGE_WIDTH - 2 * MARGIN) / (FONT_SIZE / 2)) as usize - 4;
+const LINES_PER_PAGE: usize = ((PAGE_HEIGHT - 2 * MARGIN) / LEADING) as usize;
+
+// WinAnsiEncoding's characters in 0x80-0x9F, where it differs from Latin-1
+const WIN_ANSI_EXTRAS: [(char, u8); 27] = [
+    ('€', 0x80), ('‚', 0x82), ('ƒ', 0x83), ('„', 0x84), ('…', 0x85), ('†', 0x86), ('‡', 0x87),
+    ('ˆ', 0x88), ('‰', 0x89), ('Š', 0x8a), ('‹', 0x8b), ('Œ', 0x8c), ('Ž', 0x8e), ('‘', 0x91),
+    ('’', 0x92), ('“', 0x93), ('”', 0x94), ('•', 0x95), ('–', 0x96), ('—', 0x97), ('˜', 0x98),
+    ('™', 0x99), ('š', 0x9a), ('›', 0x9b), ('œ', 0x9c), ('ž', 0x9e), ('Ÿ', 0x9f),
+];
+
+// A string literal in WinAnsiEncoding, which matches Latin-1 above 0xA0
+fn literal(text: &str) -> Vec<u8> {
+    let mut out = vec![b'('];
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => out.extend([b'\\', c as u8]),
+            ' '..='~' | '\u{a0}'..='\u{ff}' => out.push(c as u32 as u8),
+            _ => out.push(WIN_ANSI_EXTRAS.iter().find(|&&(e, _)| e == c).map_or(b'?', |&(_, b)| b)),
+        }
+    }
+    out.push(b')');
+    out
+}
+
+// Splits a line at spaces so each piece fits the page, breaking words that don't
+fn wrap(line: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in line.split(' ') {
+        let mut word = word.to_string();
+        while word.chars().count() > WRAP_AT {
+            if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+            let rest = word.chars().skip(WRAP_AT).collect();
+            lines.push(word.chars().take(WRAP_AT).collect());
+            word = rest;
+        }
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > WRAP_AT {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    lines.push(current);
+    lines
+}
+
+// The content stream of one page; the title only goes on the first
+fn page_content(title: Option<&str>, lines: &[String]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let top = PAGE_HEIGHT - MARGIN;
+    content.extend(format!("BT\n{LEADING} TL\n{MARGIN} {top} Td\n").bytes());
+    if let Some(title) = title {
+        content.extend(format!("/F2 {TITLE_SIZE} Tf\n").bytes());
+        content.extend(literal(title));
+        content.extend(b" Tj\nT* T*\n");
+    }
+    content.extend(format!("/F1 {FONT_SIZE} Tf\n").bytes());
+    for line in lines {
+        content.extend(literal(line));
+        content.extend(b" Tj T*\n");
+    }
+    content.extend(b"ET\n");
+    content
+}
+
+pub fn text_document(title: &str, lines: &[String]) -> Vec<u8> {
+    let wrapped: Vec<String> = lines.iter().flat_map(|l| wrap(l)).collect();
+    // The title and the blank line after it take two lines of the first page
+    let mut pages: Vec<&[String]> = Vec::new();
+    let mut rest = wrapped.as_slice();
+    let mut room = LINES_PER_PAGE - 2;
+    loop {
+        let (page, more) = rest.split_at(room.min(rest.len()));
+        pages.push(page);
+        rest = more;
+        room = LINES_PER_PAGE;
+        if rest.is_empty() {
+            break;
+        }
+    }
+
+    // Objects 1-4 are the catalog, page tree and fonts; each page is then a
+    // page object followed by its content stream
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    let kids: Vec<String> = (0..pages.len()).map(|i| format!("{} 0 R", 5 + 2 * i)).collect();
+    objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+    objects.push(format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).into_bytes());
+    objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec());
+    objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec());
+    for (i, page) in pages.iter().enumerate() {
+        let content = page_content((i == 0).then_some(title), page);
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {PAGE_WIDTH} {PAGE_HEIGHT}] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                6 + 2 * i
+            )
+            .into_bytes(),
+        );
+        let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+        stream.extend(content);
+        stream.extend(b"endstream");
+        objects.push(stream);
+    }
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend(format!("{} 0 obj\n", i + 1).bytes());
+        pdf.extend(object);
+        pdf.extend(b"\nendobj\n");
+    }
+    let xref = pdf.len();
+    let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        let _ = writeln!(trailer, "{offset:010} 00000 n ");
+    }
+    let _ = write!(trailer, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n", objects.len() + 1);
+    pdf.extend(trailer.bytes());
+    pdf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the cross-reference table against the objects it points at and
+    // returns the page count
+    fn check_structure(pdf: &[u8]) -> usize {
+        let text = String::from_utf8_lossy(pdf);
+        assert!(text.starts_with("%PDF-1.4\n"));
+        assert!(text.ends_with("%%EOF\n"));
+        let startxref: usize = text.rsplit("startxref\n").next().unwrap().lines().next().unwrap().parse().unwrap();
+        assert!(pdf[startxref..].starts_with(b"xref\n"));
+        let mut lines = text[startxref..].lines().skip(1);
+        let size: usize = lines.next().unwrap().strip_prefix("0 ").unwrap().parse().unwrap();
+        assert_eq!(lines.next(), Some("0000000000 65535 f "));
+        for object in 1..size {
+            let entry = lines.next().unwrap();
+            assert_eq!(entry.len(), 19);
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{object} 0 obj\n").as_bytes()), "object {object}");
+        }
+        assert!(text.contains(&format!("trailer\n<< /Size {size} /Root 1 0 R >>")));
+        let count = text.split("/Count ").nth(1).unwrap();
+        let pages = count[..count.find(' ').unwrap()].parse().unwrap();
+        assert_eq!(text.matches("/Type /Page ").count(), pages);
+        pages
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        assert_eq!(wrap(""), vec![""]);
+        assert_eq!(wrap("a short line"), vec!["a short line"]);
+        let words = vec!["word"; 40].join(" ");
+        let lines = wrap(&words);
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.chars().count() <= WRAP_AT && !l.starts_with(' ') && !l.ends_with(' ')));
+        assert_eq!(lines.join(" "), words);
+    }
+
+    #[test]
+    fn breaks_words_longer_than_a_line() {
+        let long = "é".repeat(WRAP_AT * 2 + 3);
+        let lines = wrap(&format!("before {long} after"));
+        assert_eq!(lines[0], "before");
+        assert_eq!(lines[1].chars().count(), WRAP_AT);
+        assert_eq!(lines[2].chars().count(), WRAP_AT);
+        assert_eq!(lines[3], "ééé after");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn literals_escape_delimiters() {
+        assert_eq!(literal(r"a (b) \c"), br"(a \(b\) \\c)".to_vec());
+    }
+
+    #[test]
+    fn literals_use_win_ansi() {
+        assert_eq!(literal("café"), b"(caf\xe9)".to_vec());
+        assert_eq!(literal("€5 – “ok” …"), b"(\x805 \x96 \x93ok\x94 \x85)".to_vec());
+        assert_eq!(literal("\u{a0}ÿ"), b"(\xa0\xff)".to_vec());
+    }
+
+    #[test]
+    fn characters_outside_win_ansi_become_question_marks() {
+        assert_eq!(literal("Жук 日本 👍 ł"), b"(??? ?? ? ?)".to_vec());
+        // C1 controls and tabs aren't printable either
+        assert_eq!(literal("\u{81}\t"), b"(??)".to_vec());
+    }
+
+    #[test]
+    fn documents_have_a_valid_xref_and_page_count() {
+        assert_eq!(check_structure(&text_document("Empty", &[])), 1);
+        let lines: Vec<String> = (0..LINES_PER_PAGE - 2).map(|i| format!("line {i}")).collect();
+        assert_eq!(check_structure(&text_document("Full", &lines)), 1);
+        let lines: Vec<String> = (0..LINES_PER_PAGE - 1).map(|i| format!("line {i}")).collect();
+        assert_eq!(check_structure(&text_document("Spills over", &lines)), 2);
+        let lines: Vec<String> = (0..LINES_PER_PAGE * 2).map(|i| format!("line {i}")).collect();
+        assert_eq!(check_structure(&text_document("Three pages", &lines)), 3);
+    }
+
+    #[test]
+    fn wrapped_lines_count_towards_pages() {
+        let long = vec!["word"; 40].join(" ");
+        let lines = vec![long; LINES_PER_PAGE / 2];
+        assert_eq!(check_structure(&text_document("Wrapped (long)", &lines)), 2);
+    }
+
+    #[test]
+    fn only_the_first_page_has_the_title() {
+        let lines: Vec<String> = (0..LINES_PER_PAGE).map(|i| format!("line {i}")).collect();
+        let pdf = String::from_utf8_lossy(&text_document("Title (1)", &lines)).into_owned();
+        assert_eq!(pdf.matches(r"(Title \(1\)) Tj").count(), 1);
+        assert_eq!(pdf.matches("/F2 16 Tf").count(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+// Who is connected to each room right now. A user may have several connections
+// (tabs) open; they join with the first and leave with the last. A connection
+// whose client sends no frames (heartbeats included) for IDLE_AFTER is idle, and
+// a user is idle once all their connections are. Separately, a connection that
+// doesn't even answer WebSocket pings for DEAD_AFTER is dropped.
+//
+// Connections are recorded in the database so every instance sees the whole
+// room; each instance refreshes its own every REFRESH_EVERY, and one that stops
+// (e.g. it crashed) has its connections ignored after DEAD_AFTER. Activity is
+// tracked here in memory and only written when a connection changes state, so
+// chat and heartbeats don't cost a write each.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::models::{RoomConnection, UserType};
+use crate::protocol::{Participant, PresenceStatus, Role, Room};
+
+pub const IDLE_AFTER: Duration = Duration::from_secs(90);
+pub const DEAD_AFTER: Duration = Duration::from_secs(120);
+// Comfortably inside DEAD_AFTER, even with a heartbeat's delay on top
+const REFRESH_EVERY: Duration = Duration::from_secs(60);
+
+struct Connection {
+    active_at: Instant,    // last client frame
+    seen_at: Instant,      // last frame of any kind, pongs included
+    refreshed_at: Instant, // last written to the database
+    idle: bool,
+}
+
+// This instance's connections, by id
+static CONNECTIONS: Lazy<Mutex<HashMap<Uuid, Connection>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn key(room: Room) -> anyhow::Result<serde_json::Value> {
+    Ok(serde_json::to_value(room)?)
+}
+
+fn stale_after() -> f64 {
+    DEAD_AFTER.as_secs_f64()
+}
+
+// Folds one user's connections (oldest first) into a participant
+fn participant(connections: &[RoomConnection]) -> Option<Participant> {
+    let first = connections.first()?;
+    Some(Participant {
+        user_id: first.user_id,
+        username: first.username.clone(),
+        role: Role::from(&first.user_type),
+        status: if connections.iter().all(|c| c.idle) { PresenceStatus::Idle } else { PresenceStatus::Active },
+        connections: connections.len(),
+        joined_at: first.joined_at,
+    })
+}
+
+async fn user_connections(db: &Database, room: Room, user_id: Uuid) -> anyhow::Result<Vec<RoomConnection>> {
+    db.get_room_connections(&key(room)?, Some(user_id), stale_after()).await
+}
+
+// Registers a connection; returns the participant if this is the user's first
+// connection to the room (i.e. they just joined)
+pub async fn join(
+    db: &Database,
+    room: Room,
+    conn_id: Uuid,
+    user_id: Uuid,
+    username: &str,
+    user_type: UserType,
+) -> anyhow::Result<Option<Participant>> {
+    let now = Instant::now();
+    CONNECTIONS.lock().unwrap().insert(conn_id, Connection { active_at: now, seen_at: now, refreshed_at: now, idle: false });
+    let connection = RoomConnection {
+        id: conn_id,
+        user_id,
+        username: username.to_string(),
+        user_type,
+        joined_at: Utc::now(),
+        idle: false,
+    };
+    db.add_room_connection(&key(room)?, &connection, stale_after()).await?;
+    let connections = user_connections(db, room, user_id).await?;
+    Ok(if connections.len() == 1 { participant(&connections) } else { None })
+}
+
+// Removes a connection; returns the participant if it was the user's last one
+// (they left)
+pub async fn leave(db: &Database, room: Room, conn_id: Uuid, user_id: Uuid) -> anyhow::Result<Option<Participant>> {
+    CONNECTIONS.lock().unwrap().remove(&conn_id);
+    let Some(removed) = db.remove_room_connection(conn_id).await? else {
+        return Ok(None);
+    };
+    if !user_connections(db, room, user_id).await?.is_empty() {
+        return Ok(None);
+    }
+    Ok(participant(&[removed]).map(|p| Participant { connections: 0, ..p }))
+}
+
+// Records a frame received on a connection. Only client frames (`active`) count
+// as activity; returns the participant if the user was idle and is now active
+pub async fn touch(db: &Database, room: Room, conn_id: Uuid, user_id: Uuid, active: bool) -> anyhow::Result<Option<Participant>> {
+    let woke = {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let Some(conn) = connections.get_mut(&conn_id) else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        conn.seen_at = now;
+        if !active {
+            return Ok(None);
+        }
+        conn.active_at = now;
+        let woke = std::mem::replace(&mut conn.idle, false);
+        if woke {
+            conn.refreshed_at = now;
+        }
+        woke
+    };
+    if !woke {
+        return Ok(None);
+    }
+    db.refresh_room_connection(conn_id, false).await?;
+    // The user was idle if this was their only active connection now
+    let connections = user_connections(db, room, user_id).await?;
+    let active = connections.iter().filter(|c| !c.idle).count();
+    Ok(if active == 1 { participant(&connections) } else { None })
+}
+
+// Keeps the connection listed for other instances and checks it for idleness;
+// returns the participant if the user just became idle. Call on every heartbeat.
+pub async fn check_idle(db: &Database, room: Room, conn_id: Uuid, user_id: Uuid) -> anyhow::Result<Option<Participant>> {
+    let (dozed, idle, refresh) = {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        let Some(conn) = connections.get_mut(&conn_id) else {
+            return Ok(None);
+        };
+        let dozed = !conn.idle && conn.active_at.elapsed() >= IDLE_AFTER;
+        conn.idle |= dozed;
+        let refresh = dozed || conn.refreshed_at.elapsed() >= REFRESH_EVERY;
+        if refresh {
+            conn.refreshed_at = Instant::now();
+        }
+        (dozed, conn.idle, refresh)
+    };
+    if refresh {
+        db.refresh_room_connection(conn_id, idle).await?;
+    }
+    if !dozed {
+        return Ok(None);
+    }
+    let connections = user_connections(db, room, user_id).await?;
+    Ok(if connections.iter().all(|c| c.idle) { participant(&connections) } else { None })
+}
+
+// Whether nothing at all has been received on this connection for DEAD_AFTER
+pub fn is_dead(conn_id: Uuid) -> bool {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .get(&conn_id)
+        .map(|conn| conn.seen_at.elapsed() >= DEAD_AFTER)
+        .unwrap_or(true)
+}
+
+// Everyone in the room across all instances, in the order they joined
+pub async fn participants(db: &Database, room: Room) -> anyhow::Result<Vec<Participant>> {
+    let mut by_user: Vec<(Uuid, Vec<RoomConnection>)> = Vec::new();
+    for connection in db.get_room_connections(&key(room)?, None, stale_after()).await? {
+        match by_user.iter_mut().find(|(id, _)| *id == connection.user_id) {
+            Some((_, connections)) => connections.push(connection),
+            None => by_user.push((connection.user_id, vec![connection])),
+        }
+    }
+    Ok(by_user.iter().filter_map(|(_, connections)| participant(connections)).collect())
+}
@@ -0,0 +1,704 @@
+// WebSocket protocol spoken on /ws. Every frame is a JSON object carrying the
+// protocol version `v`, a `type` tag and the fields of that type. The JSON
+// Schema served at /ws/schema is generated from these types, so the doc
+// comments below end up in it.
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::{
+    self, ChatReaction, LessonBreakoutMessage, LessonChatMessage, LessonPlayback, LessonPoll, LessonPrivateMessage, UserType,
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Longest chat message accepted, in characters
+pub const MAX_CHAT_LENGTH: usize = 2000;
+
+/// A frame sent by the client.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ClientFrame {
+    /// Protocol version; must be 1.
+    pub v: u32,
+    /// Client-generated correlation id, echoed back on the `ack` or `error` reply.
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Post a chat message to the room. Acked with the stored message id. The
+    /// sender's name and role are filled in by the server. In lesson rooms,
+    /// `reply_to` makes it a reply to an earlier message.
+    Chat {
+        message: String,
+        #[serde(default)]
+        reply_to: Option<Uuid>,
+    },
+    /// Change the text of one of your own messages (lesson rooms). Everyone
+    /// gets an `edited` frame; earlier versions are kept for the teacher.
+    Edit { message_id: Uuid, message: String },
+    /// React to a message with an emoji (lesson rooms).
+    React { message_id: Uuid, emoji: String },
+    /// Take back a reaction.
+    Unreact { message_id: Uuid, emoji: String },
+    /// Message one person in the lesson privately. Teachers can message anyone
+    /// in the lesson, students only its teachers. `include_co_teachers` also
+    /// shows it to everyone teaching the lesson. Acked with the message id.
+    Private {
+        to: Uuid,
+        message: String,
+        #[serde(default)]
+        include_co_teachers: bool,
+    },
+    /// Join the lesson's queue of raised hands (students).
+    RaiseHand,
+    /// Take your hand down, or as a teacher someone else's.
+    LowerHand {
+        #[serde(default)]
+        user_id: Option<Uuid>,
+    },
+    /// Give someone the floor (teachers). Their hand comes down if it was up;
+    /// anyone in the lesson can be called on, hand raised or not.
+    CallOn { user_id: Uuid },
+    /// Take every hand down (teachers).
+    ClearHands,
+    /// Put a multiple-choice question to the lesson (teachers). Setting
+    /// `correct_option` (counted from 0) makes it a quiz. Acked with the poll id.
+    LaunchPoll {
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        correct_option: Option<usize>,
+    },
+    /// Answer a poll (students); each student answers once.
+    Answer { poll_id: Uuid, option: usize },
+    /// Stop taking answers (teachers).
+    ClosePoll { poll_id: Uuid },
+    /// Show the results, and a quiz's answer, to the class (teachers). Closes
+    /// the poll if it's still open.
+    RevealPoll { poll_id: Uuid },
+    /// Draw on the lesson's whiteboard. Teachers can always draw, students when
+    /// the teacher lets them; students can only erase their own elements, and
+    /// only teachers can clear the board.
+    Draw { op: WhiteboardOp },
+    /// Let students draw on the whiteboard, or stop them (teachers).
+    SetWhiteboardAccess { students_can_draw: bool },
+    /// Put a video in front of the lesson to watch together (teachers). It
+    /// starts paused at the beginning and replaces any video already loaded.
+    LoadVideo { url: String },
+    /// Start the video, from `position` seconds if given (teachers).
+    Play {
+        #[serde(default)]
+        position: Option<f64>,
+    },
+    /// Stop the video, at `position` seconds if given (teachers).
+    Pause {
+        #[serde(default)]
+        position: Option<f64>,
+    },
+    /// Jump to `position` seconds, playing or not (teachers).
+    Seek { position: f64 },
+    /// Change the playback speed, between 0.25 and 4 (teachers).
+    SetRate { rate: f64 },
+    /// Take the video away (teachers).
+    CloseVideo,
+    /// Ask where the video should be now; answered with a `playback` frame.
+    /// Clients playing along send this now and then to correct drift.
+    SyncPlayback,
+    /// Split the students in the lesson room into breakout rooms (teachers),
+    /// optionally for `minutes`. Each room has its own chat, joined with
+    /// `lesson:{lesson_id}/breakout:{room_id}`.
+    StartBreakouts {
+        split: BreakoutSplit,
+        #[serde(default)]
+        minutes: Option<u32>,
+    },
+    /// Post a message to every open breakout room (teachers).
+    BroadcastToBreakouts { message: String },
+    /// Give the breakout rooms `minutes` from now, or no time limit (teachers).
+    SetBreakoutTimer {
+        #[serde(default)]
+        minutes: Option<u32>,
+    },
+    /// Close the breakout rooms and call everyone back (teachers).
+    EndBreakouts,
+    /// Heartbeat; answered with `pong`. Any frame counts as activity, and a
+    /// user whose connections send nothing for 90 seconds is shown as idle.
+    Ping,
+}
+
+/// How `start_breakouts` puts students into rooms.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum BreakoutSplit {
+    /// Shuffle the students in the lesson room into `rooms` rooms of even size.
+    Random { rooms: usize },
+    /// One room per group of students.
+    Manual { groups: Vec<Vec<Uuid>> },
+}
+
+/// The room a connection is in, chosen with the `room` query parameter on /ws:
+/// `lesson:{lesson_id}`, `lesson:{lesson_id}/breakout:{room_id}`,
+/// `classroom:{classroom_id}` or `direct:{other_user_id}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Room {
+    Lesson { id: Uuid },
+    Classroom { id: Uuid },
+    /// One-to-one conversation; the two user ids in ascending order.
+    Direct { users: [Uuid; 2] },
+    /// A breakout room of a lesson, open to its students and the lesson's teachers.
+    Breakout { lesson_id: Uuid, id: Uuid },
+}
+
+impl Room {
+    // Parses the `room` query parameter on behalf of `user_id`. The legacy
+    // "lesson-{id}" form is still accepted.
+    pub fn parse(s: &str, user_id: Uuid) -> Option<Room> {
+        if let Some(id) = s.strip_prefix("lesson-") {
+            return Uuid::parse_str(id).ok().map(|id| Room::Lesson { id });
+        }
+        if let Some((lesson, breakout)) = s.split_once("/breakout:") {
+            let lesson_id = Uuid::parse_str(lesson.strip_prefix("lesson:")?).ok()?;
+            let id = Uuid::parse_str(breakout).ok()?;
+            return Some(Room::Breakout { lesson_id, id });
+        }
+        let (kind, id) = s.split_once(':')?;
+        let id = Uuid::parse_str(id).ok()?;
+        match kind {
+            "lesson" => Some(Room::Lesson { id }),
+            "classroom" => Some(Room::Classroom { id }),
+            "direct" if id != user_id => {
+                let mut users = [user_id, id];
+                users.sort();
+                Some(Room::Direct { users })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn lesson_id(&self) -> Option<Uuid> {
+        match self {
+            Room::Lesson { id } => Some(*id),
+            _ => None,
+        }
+    }
+
+    // The lesson whose chat rules apply: the lesson room's own, or the lesson a
+    // breakout room was split from
+    pub fn parent_lesson_id(&self) -> Option<Uuid> {
+        match self {
+            Room::Lesson { id } | Room::Breakout { lesson_id: id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+}
+
+/// A frame sent by the server.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServerFrame {
+    /// Protocol version of this frame.
+    pub v: u32,
+    /// Correlation id of the client frame this replies to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+impl ServerFrame {
+    pub fn new(message: ServerMessage) -> Self {
+        ServerFrame { v: PROTOCOL_VERSION, id: None, message }
+    }
+
+    pub fn reply(id: Option<String>, message: ServerMessage) -> Self {
+        ServerFrame { v: PROTOCOL_VERSION, id, message }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// First frame on every connection.
+    Welcome {
+        protocol_version: u32,
+        user_id: Uuid,
+        room: Room,
+    },
+    /// A chat message posted to the room.
+    Chat(Box<ChatMessage>),
+    /// Stored messages sent right after `welcome` in lesson rooms: the latest
+    /// ones, or those after `since` when resuming. Live `chat` frames follow
+    /// without gaps or repeats. When `has_more` is set, page through the rest
+    /// with GET /api/lesson/{id}/chat. Breakout rooms get their latest messages.
+    History { messages: Vec<ChatMessage>, has_more: bool },
+    /// Informational notice from the server, e.g. that chat is closed.
+    System { text: String },
+    /// A client frame was rejected.
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// For `rate_limited` and `slow_mode`: how long to wait before sending again.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
+    /// A client frame was accepted.
+    Ack {
+        /// Id of the chat message created by the acked frame.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<Uuid>,
+    },
+    /// A teacher moderated the room.
+    Moderation(ModerationEvent),
+    /// Everyone currently in the room; sent right after `welcome`.
+    Roster { participants: Vec<Participant> },
+    /// Someone joined, left, went idle or came back.
+    Presence { event: PresenceEvent, participant: Participant },
+    /// The author edited a chat message.
+    Edited {
+        message_id: Uuid,
+        message: String,
+        edited_at: DateTime<Utc>,
+    },
+    /// The reactions to a message changed; replaces the previous list.
+    Reactions { message_id: Uuid, reactions: Vec<Reaction> },
+    /// A private message you sent (from any of your connections), received,
+    /// or that was shared with you as a teacher of the lesson.
+    Private(Box<PrivateMessage>),
+    /// The latest private messages you can read, sent after `history`.
+    PrivateHistory { messages: Vec<PrivateMessage> },
+    /// The lesson's raised hands in the order they went up; sent on join and
+    /// whenever the queue changes.
+    Hands { queue: Vec<RaisedHand> },
+    /// The teacher gave someone the floor.
+    CalledOn { user_id: Uuid, username: String },
+    /// A poll was launched, closed or revealed; replaces what you had for it.
+    Poll(Box<Poll>),
+    /// Every poll of the lesson so far, sent on join.
+    Polls { polls: Vec<Poll> },
+    /// Live tallies for a poll, sent to the lesson's teachers as answers come
+    /// in; the class sees them on the `poll` frame once revealed.
+    PollResults { poll_id: Uuid, results: PollResults },
+    /// Someone drew on the whiteboard. Skip ops with a `seq` no greater than
+    /// the snapshot's; the snapshot already includes them.
+    Whiteboard { seq: i64, user_id: Uuid, op: WhiteboardOp },
+    /// What's on the whiteboard now, sent on join: the strokes and shapes in
+    /// drawing order, up to op `seq`.
+    WhiteboardSnapshot {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<i64>,
+        elements: Vec<WhiteboardElement>,
+        students_can_draw: bool,
+    },
+    /// The teacher let students draw on the whiteboard, or stopped them.
+    WhiteboardAccess { students_can_draw: bool },
+    /// The lesson's shared video, sent on join, when a teacher changes it and
+    /// in reply to `sync_playback`. `video` is absent when there is none.
+    Playback {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        video: Option<VideoPlayback>,
+        /// When the server sent the frame; the video's `position` is as of this time.
+        server_time: DateTime<Utc>,
+    },
+    /// The lesson's open breakout rooms, sent on join to lesson and breakout
+    /// rooms and whenever they change. An empty list means everyone has been
+    /// called back to the lesson.
+    Breakouts {
+        rooms: Vec<BreakoutRoom>,
+        /// When the teacher wants everyone back.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ends_at: Option<DateTime<Utc>>,
+    },
+    /// Reply to `ping`.
+    Pong,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChatMessage {
+    pub id: Uuid,
+    /// Position in the lesson's chat, increasing; pass the last one seen as
+    /// `since` when reconnecting. Absent outside lesson rooms, where chat is
+    /// not stored or, in breakout rooms, not numbered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    pub user_id: Uuid,
+    /// Sender's name as registered, never client-supplied.
+    pub username: String,
+    pub role: Role,
+    pub message: String,
+    pub timestamp: DateTime<Utc>,
+    /// Id of the message this one replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Uuid>,
+    /// When the author last edited the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Emoji reactions, in the order they were first used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+}
+
+impl From<LessonChatMessage> for ChatMessage {
+    fn from(m: LessonChatMessage) -> Self {
+        ChatMessage {
+            id: m.id,
+            seq: Some(m.seq),
+            user_id: m.user_id,
+            role: Role::from(&m.user_type),
+            username: m.username,
+            message: m.message,
+            timestamp: m.timestamp,
+            reply_to: m.reply_to,
+            edited_at: m.edited_at,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+impl From<LessonBreakoutMessage> for ChatMessage {
+    fn from(m: LessonBreakoutMessage) -> Self {
+        ChatMessage {
+            id: m.id,
+            seq: None,
+            user_id: m.user_id,
+            role: Role::from(&m.user_type),
+            username: m.username,
+            message: m.message,
+            timestamp: m.created_at,
+            reply_to: None,
+            edited_at: None,
+            reactions: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PrivateMessage {
+    pub id: Uuid,
+    pub from: Uuid,
+    pub from_name: String,
+    pub from_role: Role,
+    pub to: Uuid,
+    pub to_name: String,
+    pub message: String,
+    pub include_co_teachers: bool,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl From<LessonPrivateMessage> for PrivateMessage {
+    fn from(m: LessonPrivateMessage) -> Self {
+        PrivateMessage {
+            id: m.id,
+            from: m.sender_id,
+            from_role: Role::from(&m.sender_type),
+            from_name: m.sender_name,
+            to: m.recipient_id,
+            to_name: m.recipient_name,
+            message: m.message,
+            include_co_teachers: m.include_co_teachers,
+            timestamp: m.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct RaisedHand {
+    pub user_id: Uuid,
+    pub username: String,
+    pub raised_at: DateTime<Utc>,
+    /// Speaking turns the person has already had in this lesson.
+    pub turns: i64,
+}
+
+impl From<models::RaisedHand> for RaisedHand {
+    fn from(h: models::RaisedHand) -> Self {
+        RaisedHand { user_id: h.user_id, username: h.username, raised_at: h.raised_at, turns: h.turns }
+    }
+}
+
+/// A poll or quiz as one person sees it: students only get the results and a
+/// quiz's answer once the teacher reveals them.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Poll {
+    pub id: Uuid,
+    pub question: String,
+    pub options: Vec<String>,
+    pub quiz: bool,
+    pub closed: bool,
+    pub revealed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correct_option: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<PollResults>,
+    /// Your own answer; only on `polls`, since `poll` frames go to everyone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub your_answer: Option<usize>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Poll {
+    // The poll with nothing that depends on who's looking
+    pub fn new(p: LessonPoll) -> Self {
+        Poll {
+            id: p.id,
+            question: p.question,
+            options: p.options,
+            quiz: p.correct_option.is_some(),
+            closed: p.closed_at.is_some(),
+            revealed: p.revealed,
+            correct_option: None,
+            results: None,
+            your_answer: None,
+            created_at: p.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PollResults {
+    /// Answers per option, in option order.
+    pub counts: Vec<i64>,
+    pub responses: i64,
+}
+
+impl PollResults {
+    pub fn new(options: usize, counts: &[(i32, i64)]) -> Self {
+        let mut results = PollResults { counts: vec![0; options], responses: 0 };
+        for &(option, count) in counts {
+            if let Some(c) = results.counts.get_mut(option as usize) {
+                *c += count;
+            }
+            results.responses += count;
+        }
+        results
+    }
+}
+
+/// One change to the whiteboard. Coordinates are in board units, 1600 across
+/// and 900 down from the top left; colors are `#rrggbb`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WhiteboardOp {
+    /// A freehand line through `points`; the client picks the id.
+    Stroke {
+        id: Uuid,
+        points: Vec<[f32; 2]>,
+        color: String,
+        width: f32,
+    },
+    Shape {
+        id: Uuid,
+        shape: ShapeKind,
+        from: [f32; 2],
+        to: [f32; 2],
+        color: String,
+        width: f32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fill: Option<String>,
+    },
+    /// Remove a stroke or shape.
+    Erase { id: Uuid },
+    /// Wipe the board.
+    Clear,
+}
+
+impl WhiteboardOp {
+    // The stroke or shape the op draws
+    pub fn element_id(&self) -> Option<Uuid> {
+        match self {
+            WhiteboardOp::Stroke { id, .. } | WhiteboardOp::Shape { id, .. } => Some(*id),
+            WhiteboardOp::Erase { .. } | WhiteboardOp::Clear => None,
+        }
+    }
+}
+
+/// Shapes span the box from `from` to `to`; lines and arrows go from one to the other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ShapeKind {
+    Line,
+    Arrow,
+    Rect,
+    Ellipse,
+}
+
+/// A stroke or shape on the whiteboard and who drew it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WhiteboardElement {
+    pub user_id: Uuid,
+    pub op: WhiteboardOp,
+}
+
+/// Where the lesson's video is. While `playing`, it moves `rate` seconds per
+/// second from `position`, counted from the frame's `server_time`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct VideoPlayback {
+    pub url: String,
+    pub playing: bool,
+    /// Seconds into the video.
+    pub position: f64,
+    pub rate: f64,
+    pub updated_by: Uuid,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl VideoPlayback {
+    // The stored state carried forward to `now`
+    pub fn at(p: LessonPlayback, now: DateTime<Utc>) -> Self {
+        VideoPlayback {
+            position: p.position_at(now),
+            url: p.video_url,
+            playing: p.playing,
+            rate: p.rate,
+            updated_by: p.updated_by,
+            updated_at: p.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BreakoutRoom {
+    pub id: Uuid,
+    pub name: String,
+    pub members: Vec<BreakoutMember>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BreakoutMember {
+    pub user_id: Uuid,
+    pub username: String,
+}
+
+/// Everyone who reacted to a message with one emoji.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<Uuid>,
+}
+
+impl Reaction {
+    // Groups reaction rows (oldest first) by message, then by emoji
+    pub fn group(rows: Vec<ChatReaction>) -> HashMap<Uuid, Vec<Reaction>> {
+        let mut grouped: HashMap<Uuid, Vec<Reaction>> = HashMap::new();
+        for row in rows {
+            let reactions = grouped.entry(row.message_id).or_default();
+            match reactions.iter_mut().find(|r| r.emoji == row.emoji) {
+                Some(reaction) => {
+                    reaction.count += 1;
+                    reaction.user_ids.push(row.user_id);
+                }
+                None => reactions.push(Reaction { emoji: row.emoji, count: 1, user_ids: vec![row.user_id] }),
+            }
+        }
+        grouped
+    }
+}
+
+/// Role of a user, as registered.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Student,
+    Teacher,
+    Admin,
+}
+
+impl From<&UserType> for Role {
+    fn from(user_type: &UserType) -> Self {
+        match user_type {
+            UserType::Student => Role::Student,
+            UserType::Teacher => Role::Teacher,
+            UserType::Admin => Role::Admin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ModerationEvent {
+    /// Remove this message from the chat.
+    MessageDeleted { message_id: Uuid },
+    UserMuted { user_id: Uuid },
+    UserUnmuted { user_id: Uuid },
+    ChatClosed,
+    ChatReopened,
+    /// The teacher changed the chat limits for students; null means no limit.
+    ChatSettingsChanged {
+        slow_mode_seconds: Option<i32>,
+        max_length: Option<i32>,
+    },
+}
+
+/// A user connected to a room.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Participant {
+    pub user_id: Uuid,
+    pub username: String,
+    pub role: Role,
+    pub status: PresenceStatus,
+    /// Open connections (e.g. browser tabs) the user has in the room.
+    pub connections: usize,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Active,
+    /// No heartbeat from any of the user's connections for a while.
+    Idle,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceEvent {
+    Joined,
+    Left,
+    Idle,
+    Active,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not valid JSON, or not a known message type.
+    InvalidFrame,
+    /// `v` is not a supported protocol version.
+    UnsupportedVersion,
+    /// Empty or over-long chat message; the lesson may set a lower limit than 2000.
+    InvalidMessage,
+    /// The sender is muted in this lesson.
+    Muted,
+    /// The teacher has closed the chat.
+    ChatClosed,
+    /// Too many messages in a short time.
+    RateLimited,
+    /// Slow mode is on and the sender posted too recently.
+    SlowMode,
+    /// The classroom's chat filter rejected the message.
+    Blocked,
+    /// The message, poll or person the frame refers to doesn't exist or was deleted.
+    NotFound,
+    /// Not allowed here, e.g. editing someone else's message, or editing outside a lesson room.
+    NotAllowed,
+    /// Something went wrong on the server; the frame may be retried.
+    Internal,
+}
+
+// JSON Schemas for both directions, for client code generation
+pub fn schema() -> serde_json::Value {
+    serde_json::json!({
+        "title": "ESL Learning Platform WebSocket protocol",
+        "version": PROTOCOL_VERSION,
+        "client": schemars::schema_for!(ClientFrame),
+        "server": schemars::schema_for!(ServerFrame),
+    })
+}
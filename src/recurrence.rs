@@ -0,0 +1,353 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+// Hard cap on how many lessons a single series may materialize
+pub const MAX_OCCURRENCES: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+}
+
+// Subset of an RFC 5545 RRULE: FREQ, INTERVAL, BYDAY, UNTIL and COUNT.
+// e.g. "FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20250630" or "FREQ=WEEKLY;COUNT=10"
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurrenceRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub by_day: Vec<Weekday>,
+    pub until: Option<DateTime<Utc>>,
+    pub count: Option<u32>,
+}
+
+fn parse_weekday(s: &str) -> anyhow::Result<Weekday> {
+    Ok(match s {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => anyhow::bail!("invalid BYDAY value: {s}"),
+    })
+}
+
+fn weekday_code(day: Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "MO",
+        Weekday::Tue => "TU",
+        Weekday::Wed => "WE",
+        Weekday::Thu => "TH",
+        Weekday::Fri => "FR",
+        Weekday::Sat => "SA",
+        Weekday::Sun => "SU",
+    }
+}
+
+// UNTIL is either a date (inclusive, end of day UTC) or a UTC date-time
+fn parse_until(s: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(dt) = NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%SZ") {
+        return Ok(Utc.from_utc_datetime(&dt));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y%m%d")
+        .map_err(|_| anyhow::anyhow!("invalid UNTIL value: {s}"))?;
+    Ok(Utc.from_utc_datetime(&date.and_hms_opt(23, 59, 59).expect("valid time")))
+}
+
+impl FromStr for RecurrenceRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("RRULE:").unwrap_or(s);
+        let mut freq = None;
+        let mut interval = 1;
+        let mut by_day = Vec::new();
+        let mut until = None;
+        let mut count = None;
+
+        for part in s.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid RRULE part: {part}"))?;
+            match key.to_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        other => anyhow::bail!("unsupported FREQ: {other}"),
+                    })
+                }
+                "INTERVAL" => {
+                    interval = value.parse()?;
+                    if interval == 0 {
+                        anyhow::bail!("INTERVAL must be at least 1");
+                    }
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let day = parse_weekday(&day.trim().to_uppercase())?;
+                        if !by_day.contains(&day) {
+                            by_day.push(day);
+                        }
+                    }
+                    by_day.sort_by_key(|d: &Weekday| d.num_days_from_monday());
+                }
+                "UNTIL" => until = Some(parse_until(value)?),
+                "COUNT" => {
+                    let n: u32 = value.parse()?;
+                    if n == 0 || n as usize > MAX_OCCURRENCES {
+                        anyhow::bail!("COUNT must be between 1 and {MAX_OCCURRENCES}");
+                    }
+                    count = Some(n);
+                }
+                "WKST" => {}
+                other => anyhow::bail!("unsupported RRULE part: {other}"),
+            }
+        }
+
+        let freq = freq.ok_or_else(|| anyhow::anyhow!("RRULE is missing FREQ"))?;
+        if until.is_none() && count.is_none() {
+            anyhow::bail!("RRULE needs either UNTIL or COUNT");
+        }
+        if until.is_some() && count.is_some() {
+            anyhow::bail!("RRULE cannot have both UNTIL and COUNT");
+        }
+        Ok(RecurrenceRule { freq, interval, by_day, until, count })
+    }
+}
+
+impl fmt::Display for RecurrenceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let freq = match self.freq {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+        };
+        write!(f, "FREQ={freq}")?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<&str> = self.by_day.iter().map(|d| weekday_code(*d)).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until.format("%Y%m%dT%H%M%SZ"))?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={count}")?;
+        }
+        Ok(())
+    }
+}
+
+// Local wall-clock time to UTC. Times inside a DST gap are pushed forward an hour.
+fn resolve_local(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&local))
+}
+
+impl RecurrenceRule {
+    // Moves every BYDAY weekday by `days`, for when all occurrences are shifted
+    // by that many calendar days. Without BYDAY the weekday follows DTSTART anyway.
+    // Every-N-weeks rules can't express some days crossing into the next (or
+    // previous) week while others don't, so that's an error.
+    pub fn shift_days(&mut self, days: i64) -> anyhow::Result<()> {
+        let weeks = |d: &Weekday| (d.num_days_from_monday() as i64 + days).div_euclid(7);
+        if self.interval > 1 && self.by_day.iter().any(|d| weeks(d) != weeks(&self.by_day[0])) {
+            anyhow::bail!("cannot move an every-{}-weeks rule across a week boundary", self.interval);
+        }
+        for day in &mut self.by_day {
+            for _ in 0..days.rem_euclid(7) {
+                *day = day.succ();
+            }
+        }
+        self.by_day.sort_by_key(|d| d.num_days_from_monday());
+        Ok(())
+    }
+
+    // Start times generated by this rule from `dtstart`, before exception dates
+    // are removed (COUNT counts those too, as in RFC 5545). Occurrences keep the
+    // same wall-clock time in `tz` across DST changes. Fails when an UNTIL rule
+    // would make more than MAX_OCCURRENCES.
+    pub fn occurrences(&self, dtstart: DateTime<Utc>, tz: Tz) -> anyhow::Result<Vec<DateTime<Utc>>> {
+        let mut out = Vec::new();
+        // One past the cap, so an UNTIL rule that goes over it can be told apart
+        let limit = self.count.map(|c| c as usize).unwrap_or(MAX_OCCURRENCES + 1);
+        let past_until = |t: &DateTime<Utc>| self.until.map(|u| *t > u).unwrap_or(false);
+        let start = dtstart.with_timezone(&tz).naive_local();
+
+        match self.freq {
+            Frequency::Daily => {
+                let mut local = start;
+                loop {
+                    let t = resolve_local(tz, local);
+                    if out.len() >= limit || past_until(&t) {
+                        break;
+                    }
+                    out.push(t);
+                    local += Duration::days(self.interval as i64);
+                }
+            }
+            Frequency::Weekly => {
+                let days = if self.by_day.is_empty() {
+                    vec![start.weekday()]
+                } else {
+                    self.by_day.clone()
+                };
+                let mut week_start =
+                    start - Duration::days(start.weekday().num_days_from_monday() as i64);
+                'weeks: loop {
+                    for day in &days {
+                        let local = week_start + Duration::days(day.num_days_from_monday() as i64);
+                        if local < start {
+                            continue;
+                        }
+                        let t = resolve_local(tz, local);
+                        if out.len() >= limit || past_until(&t) {
+                            break 'weeks;
+                        }
+                        out.push(t);
+                    }
+                    week_start += Duration::weeks(self.interval as i64);
+                }
+            }
+        }
+        if out.len() > MAX_OCCURRENCES {
+            anyhow::bail!("RRULE makes more than {MAX_OCCURRENCES} occurrences");
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn rule(s: &str) -> RecurrenceRule {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_display() {
+        let r = rule("RRULE:FREQ=weekly;INTERVAL=2;BYDAY=WE,MO,WE;COUNT=10");
+        assert_eq!(r.by_day, vec![Weekday::Mon, Weekday::Wed]);
+        assert_eq!(r.to_string(), "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE;COUNT=10");
+        assert_eq!(rule(&r.to_string()), r);
+        let r = rule("FREQ=DAILY;UNTIL=20250630");
+        assert_eq!(r.until, Some(utc("2025-06-30T23:59:59Z")));
+        assert_eq!(r.to_string(), "FREQ=DAILY;UNTIL=20250630T235959Z");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        for s in [
+            "FREQ=WEEKLY",
+            "COUNT=3",
+            "FREQ=MONTHLY;COUNT=3",
+            "FREQ=WEEKLY;COUNT=3;UNTIL=20250630",
+            "FREQ=WEEKLY;INTERVAL=0;COUNT=3",
+            "FREQ=WEEKLY;BYDAY=XX;COUNT=3",
+            "FREQ=WEEKLY;COUNT=0",
+            "FREQ=WEEKLY;COUNT=201",
+            "FREQ=WEEKLY;BYMONTH=1;COUNT=3",
+        ] {
+            assert!(s.parse::<RecurrenceRule>().is_err(), "{s}");
+        }
+        assert!("FREQ=WEEKLY;COUNT=200".parse::<RecurrenceRule>().is_ok());
+    }
+
+    #[test]
+    fn expands_byday_from_the_start_date() {
+        // Wednesday start: Monday of the first week is before DTSTART and skipped
+        let got = rule("FREQ=WEEKLY;BYDAY=MO,WE;COUNT=4")
+            .occurrences(utc("2025-01-08T10:00:00Z"), chrono_tz::UTC)
+            .unwrap();
+        let want = ["2025-01-08T10:00:00Z", "2025-01-13T10:00:00Z", "2025-01-15T10:00:00Z", "2025-01-20T10:00:00Z"];
+        assert_eq!(got, want.map(utc));
+    }
+
+    #[test]
+    fn weekly_interval_skips_weeks() {
+        let got = rule("FREQ=WEEKLY;INTERVAL=2;COUNT=3")
+            .occurrences(utc("2025-01-06T10:00:00Z"), chrono_tz::UTC)
+            .unwrap();
+        assert_eq!(got, ["2025-01-06T10:00:00Z", "2025-01-20T10:00:00Z", "2025-02-03T10:00:00Z"].map(utc));
+    }
+
+    #[test]
+    fn until_is_inclusive() {
+        let got = rule("FREQ=DAILY;INTERVAL=2;UNTIL=20250105")
+            .occurrences(utc("2025-01-01T10:00:00Z"), chrono_tz::UTC)
+            .unwrap();
+        assert_eq!(got, ["2025-01-01T10:00:00Z", "2025-01-03T10:00:00Z", "2025-01-05T10:00:00Z"].map(utc));
+    }
+
+    #[test]
+    fn until_rules_over_the_cap_fail() {
+        let start = utc("2025-01-01T10:00:00Z");
+        assert!(rule("FREQ=DAILY;UNTIL=20260101").occurrences(start, chrono_tz::UTC).is_err());
+        let got = rule("FREQ=DAILY;UNTIL=20250719").occurrences(start, chrono_tz::UTC).unwrap();
+        assert_eq!(got.len(), MAX_OCCURRENCES);
+    }
+
+    #[test]
+    fn keeps_wall_clock_time_across_dst() {
+        // London moves to BST on 30 March 2025
+        let got = rule("FREQ=WEEKLY;COUNT=2")
+            .occurrences(utc("2025-03-24T09:00:00Z"), chrono_tz::Europe::London)
+            .unwrap();
+        assert_eq!(got, ["2025-03-24T09:00:00Z", "2025-03-31T08:00:00Z"].map(utc));
+    }
+
+    #[test]
+    fn times_in_a_dst_gap_move_forward() {
+        // 02:30 doesn't exist in New York on 9 March 2025
+        let got = rule("FREQ=DAILY;COUNT=3")
+            .occurrences(utc("2025-03-08T07:30:00Z"), chrono_tz::America::New_York)
+            .unwrap();
+        assert_eq!(got, ["2025-03-08T07:30:00Z", "2025-03-09T07:30:00Z", "2025-03-10T06:30:00Z"].map(utc));
+    }
+
+    #[test]
+    fn shifting_days_rotates_byday() {
+        let mut r = rule("FREQ=WEEKLY;BYDAY=MO,WE;COUNT=4");
+        r.shift_days(1).unwrap();
+        assert_eq!(r.by_day, vec![Weekday::Tue, Weekday::Thu]);
+        let mut r = rule("FREQ=WEEKLY;BYDAY=MO,FR;COUNT=4");
+        r.shift_days(-1).unwrap();
+        assert_eq!(r.by_day, vec![Weekday::Thu, Weekday::Sun]);
+        r.shift_days(7).unwrap();
+        assert_eq!(r.by_day, vec![Weekday::Thu, Weekday::Sun]);
+    }
+
+    #[test]
+    fn shifted_rule_matches_shifted_lessons() {
+        let start = utc("2025-01-06T10:00:00Z");
+        let r = rule("FREQ=WEEKLY;BYDAY=MO,TH;COUNT=6");
+        let mut moved = r.clone();
+        moved.shift_days(2).unwrap();
+        let shifted: Vec<_> = r.occurrences(start, chrono_tz::UTC).unwrap().iter().map(|t| *t + Duration::days(2)).collect();
+        assert_eq!(moved.occurrences(start + Duration::days(2), chrono_tz::UTC).unwrap(), shifted);
+    }
+
+    #[test]
+    fn every_other_week_rules_cannot_split_across_weeks() {
+        let mut r = rule("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR;COUNT=4");
+        assert!(r.shift_days(3).is_err());
+        assert!(r.shift_days(2).is_ok());
+        assert_eq!(r.by_day, vec![Weekday::Wed, Weekday::Sun]);
+    }
+}
@@ -0,0 +1,259 @@
+// Broadcast channels behind WebSocket rooms. A room's channel only exists while
+// someone is subscribed: the first subscriber opens it and the last one to drop
+// its Subscription closes it, so rooms nobody is in cost nothing.
+//
+// Channels are local to the process. Events are published through a
+// RoomBroker, which hands them to the channels of every instance: directly when
+// there is only one, over Postgres LISTEN/NOTIFY when there are several.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::database::Database;
+use crate::protocol::{Room, ServerMessage};
+
+// Events a subscriber may fall behind by before it starts missing them
+const CHANNEL_CAPACITY: usize = 200;
+
+// What travels over a room's channel: a message for everyone in the room, or
+// only for one user's connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomEvent {
+    pub to: Option<Uuid>,
+    pub message: ServerMessage,
+}
+
+impl RoomEvent {
+    pub fn all(message: ServerMessage) -> Self {
+        RoomEvent { to: None, message }
+    }
+}
+
+static CHANNELS: Lazy<Mutex<HashMap<Room, broadcast::Sender<RoomEvent>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ROOMS_OPENED: AtomicU64 = AtomicU64::new(0);
+static ROOMS_CLOSED: AtomicU64 = AtomicU64::new(0);
+static LAGGED: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// A subscriber fell behind and the channel discarded this many events for it
+#[derive(Debug)]
+pub struct Lagged(pub u64);
+
+pub struct Subscription {
+    room: Room,
+    rx: Option<broadcast::Receiver<RoomEvent>>,
+}
+
+// Subscribes to `room`, opening its channel if nobody else is in it
+pub fn subscribe(room: Room) -> Subscription {
+    let mut channels = CHANNELS.lock().unwrap();
+    let tx = channels.entry(room).or_insert_with(|| {
+        ROOMS_OPENED.fetch_add(1, Ordering::Relaxed);
+        broadcast::channel(CHANNEL_CAPACITY).0
+    });
+    // Subscribing under the lock means a concurrent close can't remove the
+    // channel between our lookup and our subscription
+    Subscription { room, rx: Some(tx.subscribe()) }
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<RoomEvent, Lagged> {
+        let rx = self.rx.as_mut().expect("receiver is only taken on drop");
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Ok(event),
+                Err(RecvError::Lagged(missed)) => {
+                    LAGGED.fetch_add(1, Ordering::Relaxed);
+                    DROPPED.fetch_add(missed, Ordering::Relaxed);
+                    return Err(Lagged(missed));
+                }
+                // The channel is only removed once it has no receivers, and
+                // this is one, so it can't close under us; wait for the next event
+                Err(RecvError::Closed) => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        drop(self.rx.take());
+        let mut channels = CHANNELS.lock().unwrap();
+        if channels.get(&self.room).is_some_and(|tx| tx.receiver_count() == 0) {
+            channels.remove(&self.room);
+            ROOMS_CLOSED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Hands an event to this instance's subscribers of `room`; a no-op when there are none
+fn deliver(room: Room, event: RoomEvent) {
+    let channels = CHANNELS.lock().unwrap();
+    if let Some(tx) = channels.get(&room) {
+        let _ = tx.send(event);
+    }
+}
+
+// Gets room events to subscribers on every instance. Publishing never blocks;
+// events from one instance arrive everywhere in the order they were published.
+pub trait RoomBroker: Send + Sync {
+    fn publish_event(&self, room: Room, event: RoomEvent);
+
+    fn publish(&self, room: Room, message: ServerMessage) {
+        self.publish_event(room, RoomEvent::all(message));
+    }
+
+    // Sends a message only to `user_id`'s connections in `room`
+    fn publish_to(&self, room: Room, user_id: Uuid, message: ServerMessage) {
+        self.publish_event(room, RoomEvent { to: Some(user_id), message });
+    }
+}
+
+// For a single instance: events go straight to the local channels
+pub struct MemoryBroker;
+
+impl RoomBroker for MemoryBroker {
+    fn publish_event(&self, room: Room, event: RoomEvent) {
+        deliver(room, event);
+    }
+}
+
+const NOTIFY_CHANNEL: &str = "room_events";
+
+// Postgres rejects NOTIFY payloads of 8000 bytes or more
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "notice", rename_all = "snake_case")]
+enum Notice {
+    Event { room: Room, event: RoomEvent },
+    // Too large to send inline; the Event notice is in room_event_payloads
+    Stored { id: Uuid },
+}
+
+// For several instances sharing a database: every instance LISTENs on one
+// channel, and events are NOTIFYd there, including back to the publisher,
+// which delivers to its own subscribers the same way as everyone else
+pub struct PostgresBroker {
+    queue: mpsc::UnboundedSender<(Room, RoomEvent)>,
+}
+
+impl PostgresBroker {
+    pub async fn start(db: Arc<Database>) -> anyhow::Result<Self> {
+        let mut listener = PgListener::connect_with(db.get_pool()).await?;
+        listener.listen(NOTIFY_CHANNEL).await?;
+        tokio::spawn(listen(db.clone(), listener));
+
+        // One task sends everything, which keeps this instance's events in order
+        let (queue, mut events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((room, event)) = events.recv().await {
+                if let Err(e) = notify(&db, room, event).await {
+                    tracing::error!("failed to publish event to {room:?}: {e:?}");
+                }
+            }
+        });
+        Ok(PostgresBroker { queue })
+    }
+}
+
+impl RoomBroker for PostgresBroker {
+    fn publish_event(&self, room: Room, event: RoomEvent) {
+        let _ = self.queue.send((room, event));
+    }
+}
+
+async fn notify(db: &Database, room: Room, event: RoomEvent) -> anyhow::Result<()> {
+    let notice = serde_json::to_value(Notice::Event { room, event })?;
+    let mut payload = notice.to_string();
+    if payload.len() > MAX_NOTIFY_PAYLOAD {
+        let id = db.store_room_event(&notice).await?;
+        payload = serde_json::to_string(&Notice::Stored { id })?;
+        if let Err(e) = db.purge_room_events().await {
+            tracing::warn!("failed to purge delivered room events: {e:?}");
+        }
+    }
+    db.notify(NOTIFY_CHANNEL, &payload).await
+}
+
+async fn listen(db: Arc<Database>, mut listener: PgListener) {
+    loop {
+        // PgListener reconnects by itself; anything notified while it was
+        // disconnected is lost, as with a lagging subscriber
+        match listener.recv().await {
+            Ok(notification) => {
+                if let Err(e) = receive(&db, notification.payload()).await {
+                    tracing::error!("failed to deliver room event: {e:?}");
+                }
+            }
+            Err(e) => {
+                tracing::error!("room event listener failed: {e:?}");
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn receive(db: &Database, payload: &str) -> anyhow::Result<()> {
+    let notice = match serde_json::from_str(payload)? {
+        Notice::Stored { id } => {
+            let stored = db.get_room_event(id).await?.ok_or_else(|| anyhow::anyhow!("room event {id} was purged"))?;
+            serde_json::from_value(stored)?
+        }
+        notice => notice,
+    };
+    if let Notice::Event { room, event } = notice {
+        deliver(room, event);
+    }
+    Ok(())
+}
+
+// Picks the broker with ROOM_BROKER: "memory" (the default) or "postgres" when
+// running more than one instance
+pub async fn broker_from_env(db: Arc<Database>) -> anyhow::Result<Arc<dyn RoomBroker>> {
+    match std::env::var("ROOM_BROKER").unwrap_or_default().trim() {
+        "" | "memory" => Ok(Arc::new(MemoryBroker)),
+        "postgres" => Ok(Arc::new(PostgresBroker::start(db).await?)),
+        other => anyhow::bail!("unknown ROOM_BROKER {other:?}; expected \"memory\" or \"postgres\""),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomMetrics {
+    pub open_rooms: usize,
+    pub lesson_rooms: usize,
+    pub classroom_rooms: usize,
+    pub direct_rooms: usize,
+    pub breakout_rooms: usize,
+    pub subscribers: usize,
+    // Counters since the server started
+    pub rooms_opened: u64,
+    pub rooms_closed: u64,
+    pub lagged_receivers: u64,
+    pub dropped_events: u64,
+}
+
+pub fn metrics() -> RoomMetrics {
+    let channels = CHANNELS.lock().unwrap();
+    let count = |f: fn(&Room) -> bool| channels.keys().filter(|room| f(room)).count();
+    RoomMetrics {
+        open_rooms: channels.len(),
+        lesson_rooms: count(|room| matches!(room, Room::Lesson { .. })),
+        classroom_rooms: count(|room| matches!(room, Room::Classroom { .. })),
+        direct_rooms: count(|room| matches!(room, Room::Direct { .. })),
+        breakout_rooms: count(|room| matches!(room, Room::Breakout { .. })),
+        subscribers: channels.values().map(|tx| tx.receiver_count()).sum(),
+        rooms_opened: ROOMS_OPENED.load(Ordering::Relaxed),
+        rooms_closed: ROOMS_CLOSED.load(Ordering::Relaxed),
+        lagged_receivers: LAGGED.load(Ordering::Relaxed),
+        dropped_events: DROPPED.load(Ordering::Relaxed),
+    }
+}
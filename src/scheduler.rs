@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::models::{DueReminder, ReminderPreferences};
+use crate::notifier::{NotifierRegistry, OutgoingNotification};
+use crate::timezone::parse_time_zone;
+
+// How often the reminder scan runs
+const TICK: Duration = Duration::from_secs(60);
+
+pub fn spawn(db: Arc<Database>, notifiers: Arc<NotifierRegistry>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = send_due_reminders(&db, &notifiers).await {
+                tracing::error!("reminder scan failed: {e:?}");
+            }
+        }
+    });
+}
+
+fn plural(n: i64, unit: &str) -> String {
+    if n == 1 {
+        format!("1 {unit}")
+    } else {
+        format!("{n} {unit}s")
+    }
+}
+
+// "15 minutes", "1 hour 5 minutes", "1 day". Past a day only whole hours are
+// worth mentioning.
+fn describe_time_left(minutes: i64) -> String {
+    if minutes < 60 {
+        return plural(minutes, "minute");
+    }
+    if minutes < 24 * 60 {
+        let (hours, minutes) = (minutes / 60, minutes % 60);
+        return if minutes == 0 {
+            plural(hours, "hour")
+        } else {
+            format!("{} {}", plural(hours, "hour"), plural(minutes, "minute"))
+        };
+    }
+    let hours = (minutes + 30) / 60;
+    let (days, hours) = (hours / 24, hours % 24);
+    if hours == 0 {
+        plural(days, "day")
+    } else {
+        format!("{} {}", plural(days, "day"), plural(hours, "hour"))
+    }
+}
+
+// Each reminder is claimed in lesson_reminders before it is sent, so a restart
+// (or a second server instance) never sends it twice. When several offsets for
+// the same lesson are due at once, e.g. a lesson created ten minutes before it
+// starts, only the closest one is sent and the rest are claimed silently.
+async fn send_due_reminders(db: &Database, notifiers: &NotifierRegistry) -> anyhow::Result<()> {
+    let defaults = ReminderPreferences::default();
+    let due = db.get_due_reminders(&defaults.offsets_minutes, &defaults.channels).await?;
+
+    let mut rest = due.as_slice();
+    while let Some(first) = rest.first() {
+        let len = rest
+            .iter()
+            .take_while(|r| r.lesson_id == first.lesson_id && r.user_id == first.user_id)
+            .count();
+        let (group, tail) = rest.split_at(len);
+        rest = tail;
+
+        let mut to_send = None;
+        for reminder in group {
+            if db
+                .claim_reminder(reminder.lesson_id, reminder.user_id, reminder.offset_minutes, reminder.scheduled_at)
+                .await?
+            {
+                // Rows come ordered by offset, so the first claimed one is the closest
+                to_send.get_or_insert(reminder);
+            }
+        }
+        if let Some(reminder) = to_send {
+            if let Err(e) = deliver(db, notifiers, reminder).await {
+                tracing::warn!("failed to send reminder for lesson {}: {e:?}", reminder.lesson_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn deliver(db: &Database, notifiers: &NotifierRegistry, reminder: &DueReminder) -> anyhow::Result<()> {
+    let (Some(lesson), Some(user)) = (
+        db.get_lesson(reminder.lesson_id).await?,
+        db.get_user_by_id(reminder.user_id).await?,
+    ) else {
+        return Ok(());
+    };
+    let tz = parse_time_zone(&user.time_zone).unwrap_or(chrono_tz::UTC);
+    let starts = lesson.scheduled_at.with_timezone(&tz).format("%A %e %B, %H:%M %Z");
+    // From the actual start, not the offset: a reminder can go out late (a missed
+    // tick, or a lesson created inside the window)
+    let minutes_left = ((lesson.scheduled_at - chrono::Utc::now()).num_seconds() + 30) / 60;
+    let title = if minutes_left > 0 {
+        format!("Reminder: \"{}\" starts in {}", lesson.title, describe_time_left(minutes_left))
+    } else {
+        format!("Reminder: \"{}\" is starting now", lesson.title)
+    };
+    let notification = OutgoingNotification {
+        user_id: user.id,
+        email: user.email,
+        first_name: user.first_name,
+        kind: "lesson_reminder".to_string(),
+        title,
+        body: format!("Your lesson \"{}\" starts {starts} and runs {} minutes.", lesson.title, lesson.duration_minutes),
+        lesson_id: Some(lesson.id),
+    };
+
+    for channel in &reminder.channels {
+        match notifiers.get(channel) {
+            Some(notifier) => {
+                if let Err(e) = notifier.send(&notification).await {
+                    tracing::warn!("{channel} reminder to {} failed: {e:?}", notification.user_id);
+                }
+            }
+            None => tracing::warn!("reminder channel {channel} is not configured"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+// Chat flood control, per user per room. Every sender has a token bucket that
+// allows a burst of BURST messages and refills at REFILL_PER_SECOND. A teacher
+// can additionally put a lesson in slow mode: one message per N seconds.
+// Teachers and admins are never throttled; the caller decides who is exempt.
+// Buckets are kept in memory on the instance that holds the connection: this
+// runs for every message and every whiteboard stroke, so it must not cost a
+// query. A sender with tabs on several instances gets a bucket on each.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use crate::protocol::Room;
+
+const BURST: f64 = 5.0;
+const REFILL_PER_SECOND: f64 = 1.0;
+
+// Longest slow mode a teacher can set; a sender quiet for longer than this (and
+// long enough to refill their bucket) has nothing worth remembering
+pub const MAX_SLOW_MODE_SECONDS: i32 = 300;
+const FORGET_AFTER: Duration = Duration::from_secs(MAX_SLOW_MODE_SECONDS as u64);
+
+struct Sender {
+    tokens: f64,
+    refilled_at: Instant,
+    last_message: Option<Instant>,
+}
+
+static SENDERS: Lazy<Mutex<HashMap<(Room, Uuid), Sender>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub enum Throttled {
+    // Out of tokens; the next one is available after this long
+    RateLimited(Duration),
+    // Slow mode is on and the sender's last message was too recent
+    SlowMode(Duration),
+}
+
+impl Throttled {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Throttled::RateLimited(d) | Throttled::SlowMode(d) => *d,
+        }
+    }
+}
+
+// Spends one of the sender's tokens for a message about to be posted
+pub fn take_token(room: Room, user_id: Uuid) -> Result<(), Throttled> {
+    let mut senders = SENDERS.lock().unwrap();
+    let now = Instant::now();
+    let sender = senders.entry((room, user_id)).or_insert(Sender { tokens: BURST, refilled_at: now, last_message: None });
+    let elapsed = now.duration_since(sender.refilled_at).as_secs_f64();
+    sender.tokens = (sender.tokens + elapsed * REFILL_PER_SECOND).min(BURST);
+    sender.refilled_at = now;
+    if sender.tokens < 1.0 {
+        let wait = (1.0 - sender.tokens) / REFILL_PER_SECOND;
+        return Err(Throttled::RateLimited(Duration::from_secs_f64(wait)));
+    }
+    sender.tokens -= 1.0;
+    Ok(())
+}
+
+// Checks slow mode (if `interval` is set) and records the message as sent
+pub fn check_slow_mode(room: Room, user_id: Uuid, interval: Option<Duration>) -> Result<(), Throttled> {
+    let mut senders = SENDERS.lock().unwrap();
+    let now = Instant::now();
+    let sender = senders.entry((room, user_id)).or_insert(Sender { tokens: BURST, refilled_at: now, last_message: None });
+    if let (Some(interval), Some(last)) = (interval, sender.last_message) {
+        let since = now.duration_since(last);
+        if since < interval {
+            return Err(Throttled::SlowMode(interval - since));
+        }
+    }
+    sender.last_message = Some(now);
+    Ok(())
+}
+
+// Drops state for senders who have been quiet long enough that it no longer
+// affects them. Reconnecting doesn't reset a sender, so this is safe to call
+// whenever someone leaves a room.
+pub fn forget_quiet_senders() {
+    let mut senders = SENDERS.lock().unwrap();
+    let now = Instant::now();
+    senders.retain(|_, s| {
+        let last = s.last_message.unwrap_or(s.refilled_at).max(s.refilled_at);
+        now.duration_since(last) < FORGET_AFTER
+    });
+}
@@ -0,0 +1,26 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+
+use crate::models::{Lesson, LessonView};
+
+pub const DEFAULT_TIME_ZONE: &str = "UTC";
+
+// Accepts IANA names only ("America/New_York"), not abbreviations like "EST"
+pub fn parse_time_zone(name: &str) -> Option<Tz> {
+    name.trim().parse().ok()
+}
+
+pub fn format_local(t: DateTime<Utc>, tz: Tz) -> String {
+    t.with_timezone(&tz).to_rfc3339()
+}
+
+pub fn localize_lesson(lesson: Lesson, tz: Tz) -> LessonView {
+    let ends_at = lesson.ends_at();
+    LessonView {
+        local_scheduled_at: format_local(lesson.scheduled_at, tz),
+        local_ends_at: format_local(ends_at, tz),
+        time_zone: tz.name().to_string(),
+        ends_at,
+        lesson,
+    }
+}
@@ -0,0 +1,546 @@
+// Lesson transcripts: the chat, polls and moderation of a lesson in the order
+// they happened, for review and reports after the lesson. A transcript is built
+// for one reader and then rendered as JSON, Markdown, HTML or PDF. Teachers see
+// deleted messages (marked as such) and moderation; everyone else gets deleted
+// messages redacted, no moderation, and poll results only once revealed.
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::models::{Lesson, LessonChatMessage, LessonMember, LessonPoll, ModerationLogEntry};
+use crate::pdf;
+use crate::protocol::{ModerationEvent, PollResults, Role};
+use crate::timezone::format_local;
+
+#[derive(Debug, Serialize)]
+pub struct Transcript {
+    pub lesson_id: Uuid,
+    pub title: String,
+    pub teacher: String,
+    /// The teacher's time zone, which every local time is in.
+    pub time_zone: String,
+    pub scheduled_at: String,
+    pub generated_at: String,
+    pub participants: Vec<TranscriptParticipant>,
+    pub entries: Vec<TranscriptEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TranscriptParticipant {
+    pub user_id: Uuid,
+    pub name: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TranscriptEntry {
+    pub at: DateTime<Utc>,
+    pub local_time: String,
+    #[serde(flatten)]
+    pub event: TranscriptEvent,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TranscriptEvent {
+    Chat {
+        message_id: Uuid,
+        user_id: Uuid,
+        username: String,
+        /// Absent when the message was deleted and the reader isn't a teacher.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+        deleted: bool,
+        edited: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<Uuid>,
+    },
+    Poll {
+        poll_id: Uuid,
+        question: String,
+        options: Vec<String>,
+        quiz: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        correct_option: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        results: Option<PollResults>,
+    },
+    Moderation {
+        moderator_id: Uuid,
+        moderator: String,
+        /// What was done, in words.
+        description: String,
+        event: ModerationEvent,
+    },
+}
+
+// Everything a transcript is made from, as stored
+pub struct Sources {
+    pub lesson: Lesson,
+    pub teacher: String,
+    pub attendees: Vec<LessonMember>,
+    pub messages: Vec<LessonChatMessage>,
+    pub polls: Vec<(LessonPoll, Vec<(i32, i64)>)>,
+    pub moderation: Vec<ModerationLogEntry>,
+}
+
+fn member_name(m: &LessonMember) -> String {
+    format!("{} {}", m.first_name, m.last_name).trim().to_string()
+}
+
+fn describe_moderation(event: &ModerationEvent, names: &HashMap<Uuid, String>, authors: &HashMap<Uuid, String>) -> String {
+    let name = |id: &Uuid| names.get(id).cloned().unwrap_or_else(|| "a participant".to_string());
+    match event {
+        ModerationEvent::MessageDeleted { message_id } => match authors.get(message_id) {
+            Some(author) => format!("deleted a message from {author}"),
+            None => "deleted a message".to_string(),
+        },
+        ModerationEvent::UserMuted { user_id } => format!("muted {}", name(user_id)),
+        ModerationEvent::UserUnmuted { user_id } => format!("unmuted {}", name(user_id)),
+        ModerationEvent::ChatClosed => "closed the chat".to_string(),
+        ModerationEvent::ChatReopened => "reopened the chat".to_string(),
+        ModerationEvent::ChatSettingsChanged { slow_mode_seconds, max_length } => {
+            let slow_mode = match slow_mode_seconds {
+                Some(s) => format!("slow mode to one message every {s} seconds"),
+                None => "no slow mode".to_string(),
+            };
+            let length = match max_length {
+                Some(n) => format!("a limit of {n} characters"),
+                None => "no length limit".to_string(),
+            };
+            format!("set {slow_mode} and {length}")
+        }
+    }
+}
+
+pub fn build(sources: Sources, tz: Tz, teacher_view: bool, now: DateTime<Utc>) -> Transcript {
+    let Sources { lesson, teacher, attendees, messages, polls, moderation } = sources;
+    let names: HashMap<Uuid, String> = attendees.iter().map(|m| (m.user_id, member_name(m))).collect();
+    let authors: HashMap<Uuid, String> = messages.iter().map(|m| (m.id, m.username.clone())).collect();
+    let mut entries = Vec::new();
+    for m in messages {
+        let message = (!m.deleted || teacher_view).then_some(m.message);
+        entries.push((m.timestamp, TranscriptEvent::Chat {
+            message_id: m.id,
+            user_id: m.user_id,
+            username: m.username,
+            message,
+            deleted: m.deleted,
+            edited: m.edited_at.is_some(),
+            reply_to: m.reply_to,
+        }));
+    }
+    for (poll, counts) in polls {
+        let shown = teacher_view || poll.revealed;
+        let results = shown.then(|| PollResults::new(poll.options.len(), &counts));
+        entries.push((poll.created_at, TranscriptEvent::Poll {
+            poll_id: poll.id,
+            question: poll.question,
+            quiz: poll.correct_option.is_some(),
+            correct_option: poll.correct_option.filter(|_| shown).map(|o| o as usize),
+            options: poll.options,
+            results,
+        }));
+    }
+    if teacher_view {
+        for entry in moderation {
+            // Skip anything logged in a shape this version doesn't know
+            let Ok(event) = serde_json::from_value::<ModerationEvent>(entry.event) else {
+                continue;
+            };
+            entries.push((entry.created_at, TranscriptEvent::Moderation {
+                moderator_id: entry.moderator_id,
+                moderator: entry.moderator_name,
+                description: describe_moderation(&event, &names, &authors),
+                event,
+            }));
+        }
+    }
+    // Stable, so a poll and the chat around it keep their order on a tie
+    entries.sort_by_key(|(at, _)| *at);
+
+    Transcript {
+        lesson_id: lesson.id,
+        title: lesson.title,
+        teacher,
+        time_zone: tz.name().to_string(),
+        scheduled_at: format_local(lesson.scheduled_at, tz),
+        generated_at: format_local(now, tz),
+        participants: attendees
+            .iter()
+            .map(|m| TranscriptParticipant { user_id: m.user_id, name: member_name(m), role: Role::from(&m.user_type) })
+            .collect(),
+        entries: entries
+            .into_iter()
+            .map(|(at, event)| TranscriptEntry { at, local_time: format_local(at, tz), event })
+            .collect(),
+    }
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Student => "student",
+        Role::Teacher => "teacher",
+        Role::Admin => "admin",
+    }
+}
+
+// The header lines every format shows before the entries
+fn header(t: &Transcript) -> Vec<(&'static str, String)> {
+    vec![
+        ("Teacher", t.teacher.clone()),
+        ("Scheduled", local_time(&t.scheduled_at, "%Y-%m-%d %H:%M")),
+        ("Time zone", t.time_zone.clone()),
+        ("Exported", local_time(&t.generated_at, "%Y-%m-%d %H:%M")),
+    ]
+}
+
+// An RFC 3339 local time from format_local, reformatted for reading
+fn local_time(rfc3339: &str, format: &str) -> String {
+    DateTime::parse_from_rfc3339(rfc3339)
+        .map(|t| t.format(format).to_string())
+        .unwrap_or_else(|_| rfc3339.to_string())
+}
+
+// An entry as "who" and "what", in plain text
+fn entry_text(entry: &TranscriptEntry, authors: &HashMap<Uuid, &str>) -> (Option<String>, String) {
+    match &entry.event {
+        TranscriptEvent::Chat { username, message, deleted, edited, reply_to, .. } => {
+            let mut text = String::new();
+            if let Some(author) = reply_to.and_then(|id| authors.get(&id)) {
+                let _ = write!(text, "(reply to {author}) ");
+            }
+            match (message, deleted) {
+                (Some(message), true) => {
+                    let _ = write!(text, "{message} [deleted]");
+                }
+                (Some(message), false) => text.push_str(message),
+                (None, _) => text.push_str("[message deleted]"),
+            }
+            if *edited {
+                text.push_str(" (edited)");
+            }
+            (Some(username.clone()), text)
+        }
+        TranscriptEvent::Poll { question, options, quiz, correct_option, results, .. } => {
+            let mut text = format!("{}: {question} -", if *quiz { "Quiz" } else { "Poll" });
+            for (i, option) in options.iter().enumerate() {
+                let _ = write!(text, " {}. {option}", i + 1);
+                if let Some(results) = results {
+                    let _ = write!(text, " ({})", results.counts.get(i).copied().unwrap_or(0));
+                }
+                if *correct_option == Some(i) {
+                    text.push_str(" [correct]");
+                }
+                text.push(';');
+            }
+            text.pop();
+            if let Some(results) = results {
+                let _ = write!(text, " - {} answered", results.responses);
+            }
+            (None, text)
+        }
+        TranscriptEvent::Moderation { moderator, description, .. } => (None, format!("{moderator} {description}")),
+    }
+}
+
+// When the entry happened, with the date only if it's not the lesson's
+fn entry_time(t: &Transcript, entry: &TranscriptEntry) -> String {
+    let day = local_time(&t.scheduled_at, "%Y-%m-%d");
+    if local_time(&entry.local_time, "%Y-%m-%d") == day {
+        local_time(&entry.local_time, "%H:%M:%S")
+    } else {
+        local_time(&entry.local_time, "%Y-%m-%d %H:%M:%S")
+    }
+}
+
+fn chat_authors(t: &Transcript) -> HashMap<Uuid, &str> {
+    t.entries
+        .iter()
+        .filter_map(|e| match &e.event {
+            TranscriptEvent::Chat { message_id, username, .. } => Some((*message_id, username.as_str())),
+            _ => None,
+        })
+        .collect()
+}
+
+// Backslash-escapes anything Markdown could read as formatting
+fn escape_markdown(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\`*_[]<>|~".contains(c) {
+            out.push('\\');
+        }
+        out.push(if c == '\n' { ' ' } else { c });
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+pub fn to_markdown(t: &Transcript) -> String {
+    let authors = chat_authors(t);
+    let mut out = format!("# {}\n\n", escape_markdown(&t.title));
+    for (label, value) in header(t) {
+        let _ = writeln!(out, "- **{label}:** {}", escape_markdown(&value));
+    }
+    out.push_str("\n## Participants\n\n");
+    for p in &t.participants {
+        let _ = writeln!(out, "- {} ({})", escape_markdown(&p.name), role_name(p.role));
+    }
+    out.push_str("\n## Transcript\n\n");
+    for entry in &t.entries {
+        let time = entry_time(t, entry);
+        match entry_text(entry, &authors) {
+            (Some(who), what) => {
+                let _ = writeln!(out, "- `{time}` **{}:** {}", escape_markdown(&who), escape_markdown(&what));
+            }
+            (None, what) => {
+                let _ = writeln!(out, "- `{time}` _{}_", escape_markdown(&what));
+            }
+        }
+    }
+    out
+}
+
+pub fn to_html(t: &Transcript) -> String {
+    let authors = chat_authors(t);
+    let title = escape_html(&t.title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n\
+         body {{ font-family: sans-serif; max-width: 50rem; margin: 2rem auto; color: #1e293b; }}\n\
+         .time {{ color: #64748b; font-family: monospace; margin-right: 0.5rem; }}\n\
+         .event {{ font-style: italic; color: #475569; }}\n\
+         li {{ margin: 0.25rem 0; }}\n</style>\n</head>\n<body>\n<h1>{title}</h1>\n<ul>\n"
+    );
+    for (label, value) in header(t) {
+        let _ = writeln!(out, "<li><strong>{label}:</strong> {}</li>", escape_html(&value));
+    }
+    out.push_str("</ul>\n<h2>Participants</h2>\n<ul>\n");
+    for p in &t.participants {
+        let _ = writeln!(out, "<li>{} ({})</li>", escape_html(&p.name), role_name(p.role));
+    }
+    out.push_str("</ul>\n<h2>Transcript</h2>\n<ul>\n");
+    for entry in &t.entries {
+        let time = entry_time(t, entry);
+        match entry_text(entry, &authors) {
+            (Some(who), what) => {
+                let _ = writeln!(
+                    out,
+                    "<li><span class=\"time\">{time}</span><strong>{}:</strong> {}</li>",
+                    escape_html(&who),
+                    escape_html(&what)
+                );
+            }
+            (None, what) => {
+                let _ = writeln!(out, "<li class=\"event\"><span class=\"time\">{time}</span>{}</li>", escape_html(&what));
+            }
+        }
+    }
+    out.push_str("</ul>\n</body>\n</html>\n");
+    out
+}
+
+pub fn to_pdf(t: &Transcript) -> Vec<u8> {
+    let authors = chat_authors(t);
+    let mut lines: Vec<String> = header(t).into_iter().map(|(label, value)| format!("{label}: {value}")).collect();
+    lines.push(String::new());
+    lines.push("Participants".to_string());
+    lines.extend(t.participants.iter().map(|p| format!("  {} ({})", p.name, role_name(p.role))));
+    lines.push(String::new());
+    lines.push("Transcript".to_string());
+    for entry in &t.entries {
+        let time = entry_time(t, entry);
+        lines.push(match entry_text(entry, &authors) {
+            (Some(who), what) => format!("[{time}] {who}: {what}"),
+            (None, what) => format!("[{time}] * {what}"),
+        });
+    }
+    pdf::text_document(&t.title, &lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{LessonStatus, UserType};
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn lesson() -> Lesson {
+        Lesson {
+            id: Uuid::new_v4(),
+            classroom_id: Uuid::new_v4(),
+            teacher_id: Uuid::new_v4(),
+            title: "Phrasal verbs <1>".to_string(),
+            description: String::new(),
+            scheduled_at: utc("2025-03-03T10:00:00Z"),
+            duration_minutes: 60,
+            status: LessonStatus::Ended,
+            chat_closed: false,
+            chat_slow_mode_seconds: None,
+            chat_max_length: None,
+            whiteboard_students_can_draw: false,
+            created_at: utc("2025-03-01T09:00:00Z"),
+            cancellation_reason: None,
+            series_id: None,
+            occurrence_start: None,
+        }
+    }
+
+    fn message(lesson: &Lesson, text: &str, at: &str, deleted: bool) -> LessonChatMessage {
+        LessonChatMessage {
+            id: Uuid::new_v4(),
+            lesson_id: lesson.id,
+            user_id: Uuid::new_v4(),
+            username: "ana_s".to_string(),
+            user_type: UserType::Student,
+            message: text.to_string(),
+            timestamp: utc(at),
+            deleted,
+            seq: 0,
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+
+    // A lesson with a kept and a deleted message, the deletion logged, and an
+    // unrevealed poll in between
+    fn sources() -> Sources {
+        let lesson = lesson();
+        let kept = message(&lesson, "I *give up* <b>", "2025-03-03T10:01:00Z", false);
+        let deleted = message(&lesson, "something rude", "2025-03-03T10:02:00Z", true);
+        let poll = LessonPoll {
+            id: Uuid::new_v4(),
+            lesson_id: lesson.id,
+            created_by: lesson.teacher_id,
+            question: "Which means 'stop'?".to_string(),
+            options: vec!["give up".to_string(), "give in".to_string()],
+            correct_option: Some(0),
+            revealed: false,
+            created_at: utc("2025-03-03T10:01:30Z"),
+            closed_at: None,
+        };
+        let moderation = ModerationLogEntry {
+            moderator_id: lesson.teacher_id,
+            moderator_name: "Ms Jones".to_string(),
+            event: serde_json::to_value(ModerationEvent::MessageDeleted { message_id: deleted.id }).unwrap(),
+            created_at: utc("2025-03-03T10:03:00Z"),
+        };
+        Sources {
+            teacher: "Ms Jones".to_string(),
+            attendees: vec![LessonMember {
+                user_id: kept.user_id,
+                first_name: "Ana".to_string(),
+                last_name: "Silva".to_string(),
+                user_type: UserType::Student,
+            }],
+            messages: vec![kept, deleted],
+            polls: vec![(poll, vec![(0, 3), (1, 1)])],
+            moderation: vec![moderation],
+            lesson,
+        }
+    }
+
+    fn build_for(teacher_view: bool) -> Transcript {
+        build(sources(), chrono_tz::Europe::London, teacher_view, utc("2025-03-03T12:00:00Z"))
+    }
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(escape_markdown(r"*a* _b_ [c](d) <e> f|g `h` ~i~ \j"), r"\*a\* \_b\_ \[c\](d) \<e\> f\|g \`h\` \~i\~ \\j");
+        assert_eq!(escape_markdown("two\nlines"), "two lines");
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(escape_html(r#"<a href="x">'&'</a>"#), "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;");
+    }
+
+    #[test]
+    fn students_get_deleted_messages_redacted_and_no_moderation() {
+        let t = build_for(false);
+        let chats: Vec<_> = t
+            .entries
+            .iter()
+            .filter_map(|e| match &e.event {
+                TranscriptEvent::Chat { message, deleted, .. } => Some((message.clone(), *deleted)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(chats, vec![(Some("I *give up* <b>".to_string()), false), (None, true)]);
+        assert!(!t.entries.iter().any(|e| matches!(e.event, TranscriptEvent::Moderation { .. })));
+        let markdown = to_markdown(&t);
+        assert!(!markdown.contains("something rude"));
+        assert!(markdown.contains("\\[message deleted\\]"));
+        assert!(!to_html(&t).contains("something rude"));
+        assert!(!String::from_utf8_lossy(&to_pdf(&t)).contains("something rude"));
+    }
+
+    #[test]
+    fn teachers_see_deleted_messages_and_moderation() {
+        let t = build_for(true);
+        let markdown = to_markdown(&t);
+        assert!(markdown.contains("something rude \\[deleted\\]"));
+        assert!(markdown.contains("_Ms Jones deleted a message from ana\\_s_"));
+    }
+
+    #[test]
+    fn poll_results_wait_until_revealed_for_students() {
+        let poll = |t: &Transcript| {
+            t.entries.iter().find_map(|e| match &e.event {
+                TranscriptEvent::Poll { results, correct_option, .. } => Some((results.is_some(), *correct_option)),
+                _ => None,
+            })
+        };
+        assert_eq!(poll(&build_for(false)), Some((false, None)));
+        assert_eq!(poll(&build_for(true)), Some((true, Some(0))));
+    }
+
+    #[test]
+    fn entries_are_in_time_order_in_the_teachers_time_zone() {
+        let t = build_for(true);
+        let kinds: Vec<_> = t
+            .entries
+            .iter()
+            .map(|e| match e.event {
+                TranscriptEvent::Chat { .. } => "chat",
+                TranscriptEvent::Poll { .. } => "poll",
+                TranscriptEvent::Moderation { .. } => "moderation",
+            })
+            .collect();
+        assert_eq!(kinds, vec!["chat", "poll", "chat", "moderation"]);
+        assert_eq!(t.entries[0].local_time, "2025-03-03T10:01:00+00:00");
+        assert_eq!(t.participants[0].name, "Ana Silva");
+    }
+
+    #[test]
+    fn formats_escape_what_users_wrote() {
+        let t = build_for(false);
+        let markdown = to_markdown(&t);
+        assert!(markdown.starts_with("# Phrasal verbs \\<1\\>\n"));
+        assert!(markdown.contains("**ana\\_s:** I \\*give up\\* \\<b\\>"));
+        let html = to_html(&t);
+        assert!(html.contains("<h1>Phrasal verbs &lt;1&gt;</h1>"));
+        assert!(html.contains("I *give up* &lt;b&gt;"));
+        assert!(!html.contains("<b>"));
+    }
+}
@@ -3,33 +3,60 @@ use axum::{
         ws::{Message, WebSocket, WebSocketUpgrade},
         Query, State,
     },
-    response::Response,
+    response::{IntoResponse, Json, Response},
 };
 use futures_util::{SinkExt, StreamExt};
-use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
-use tokio::sync::broadcast;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
-use crate::{AppState, auth::verify_token};
-use crate::auth::Claims;
+use crate::{AppState, auth::verify_token, content_filter, presence, throttle::{self, Throttled}, whiteboard};
+use crate::rooms::{self, Lagged, RoomEvent};
+use crate::models::{
+    ChatPolicy, FlagStatus, FlaggedChatMessage, LessonBreakoutMessage, LessonChatMessage, LessonPlayback, LessonPoll, LessonPrivateMessage, User,
+    UserType,
+};
+use crate::protocol::{
+    self, BreakoutMember, BreakoutRoom, BreakoutSplit, ChatMessage, ClientFrame, ClientMessage, ErrorCode, ModerationEvent, Poll, PollResults, PresenceEvent, PrivateMessage, Reaction, Role,
+    Room, ServerFrame, ServerMessage, VideoPlayback, WhiteboardElement, WhiteboardOp,
+    MAX_CHAT_LENGTH, PROTOCOL_VERSION,
+};
 use axum::http::StatusCode;
 
 #[derive(Debug, Deserialize)]
 pub struct WsQuery {
     token: String,
-    room: String, // now: "lesson-{lesson_id}"
+    room: String, // "lesson:{id}", "classroom:{id}" or "direct:{user_id}"; see protocol::Room
+    // Seq of the last chat message the client saw, when reconnecting
+    since: Option<i64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatMessage {
-    pub user_id: String,
-    pub username: String,
-    pub message: String,
-    pub timestamp: String,
-}
+// How often the server pings each connection and checks for idle users
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+// Messages replayed on a fresh join, and the most replayed when resuming
+const HISTORY_ON_JOIN: i64 = 50;
+const MAX_RESUME: i64 = 500;
+
+const MAX_POLL_QUESTION_LENGTH: usize = 500;
+const MAX_POLL_OPTIONS: usize = 10;
+const MAX_POLL_OPTION_LENGTH: usize = 200;
+
+const MAX_VIDEO_URL_LENGTH: usize = 2000;
+const MAX_VIDEO_POSITION: f64 = 24.0 * 60.0 * 60.0;
+const VIDEO_RATES: std::ops::RangeInclusive<f64> = 0.25..=4.0;
 
-static LESSON_CHAT_TX: Lazy<std::sync::Mutex<std::collections::HashMap<String, broadcast::Sender<ChatMessage>>>> =
-    Lazy::new(|| std::sync::Mutex::new(std::collections::HashMap::new()));
+const MAX_BREAKOUT_ROOMS: usize = 50;
+const MAX_BREAKOUT_MINUTES: u32 = 240;
+// How often breakout rooms whose timer ran out are closed
+const BREAKOUT_TIMER_TICK: Duration = Duration::from_secs(5);
+
+// Machine-readable description of the protocol, for client code generation
+pub async fn protocol_schema() -> impl IntoResponse {
+    Json(protocol::schema())
+}
 
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
@@ -47,88 +74,1513 @@ pub async fn websocket_handler(
                 .unwrap();
         }
     };
-    let lesson_id = params.room.strip_prefix("lesson-").map(|s| s.to_string());
-    ws.on_upgrade(move |socket| handle_socket(socket, params, claims, state, lesson_id))
+    let Ok(user_id) = Uuid::parse_str(&claims.sub) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    // Identity shown to the room comes from the users table, never from the client
+    let user = match state.db.get_user_by_id(user_id).await {
+        Ok(Some(user)) if user.is_active => user,
+        Ok(_) => return StatusCode::UNAUTHORIZED.into_response(),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let Some(room) = Room::parse(&params.room, user_id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid room").into_response();
+    };
+    if let Err(status) = authorize_room(&state, &user, room).await {
+        return status.into_response();
+    }
+    let conn = Connection {
+        state,
+        id: Uuid::new_v4(),
+        user_id,
+        // lesson_chat_messages.username is VARCHAR(100)
+        username: user.display_name().chars().take(100).collect(),
+        user_type: user.user_type,
+        lesson_id: room.lesson_id(),
+        room,
+    };
+    let since = params.since;
+    ws.on_upgrade(move |socket| handle_socket(socket, conn, since))
 }
 
-async fn handle_socket(
-    socket: WebSocket,
-    params: WsQuery,
-    claims: Claims,
+// Lesson rooms are open to the lesson's teacher and the co-teachers and students
+// of its classroom, classroom rooms to the classroom's teachers and students, and
+// direct rooms to a teacher and someone in a classroom they teach (direct chat
+// isn't moderated, so two students never get one). Open breakout rooms are for
+// the students put in them and anyone teaching the lesson. Admins may join any
+// room.
+pub async fn authorize_room(state: &AppState, user: &User, room: Room) -> Result<(), StatusCode> {
+    let db = &state.db;
+    let allowed = match room {
+        _ if user.user_type == UserType::Admin => true,
+        Room::Lesson { id } => {
+            let lesson = db.get_lesson(id).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            lesson.teacher_id == user.id
+                || db.is_co_teacher(lesson.classroom_id, user.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                || db.is_enrolled(lesson.classroom_id, user.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+        Room::Classroom { id } => {
+            let classroom = db.get_classroom(id).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            classroom.teacher_id == user.id
+                || db.is_co_teacher(id, user.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                || db.is_enrolled(id, user.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+        Room::Direct { users } => {
+            let other = if users[0] == user.id { users[1] } else { users[0] };
+            db.get_user_by_id(other).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            db.share_classroom(user.id, other).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+        Room::Breakout { lesson_id, id } => {
+            db.get_breakout_room(lesson_id, id).await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .filter(|r| r.closed_at.is_none())
+                .ok_or(StatusCode::NOT_FOUND)?;
+            db.get_lesson_teacher_ids(lesson_id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.contains(&user.id)
+                || db.is_breakout_member(id, user.id).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        }
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+// Everything a connection needs to handle the frames it receives
+struct Connection {
     state: AppState,
-    lesson_id: Option<String>,
-) {
+    id: Uuid,
+    user_id: Uuid,
+    username: String,
+    user_type: UserType,
+    lesson_id: Option<Uuid>,
+    room: Room,
+}
+
+// Stored chat to send on join: the latest messages, or everything after `since`
+// (up to MAX_RESUME). Returns the frame and the ids of the replayed messages.
+async fn load_history(state: &AppState, lesson_id: Uuid, since: Option<i64>) -> anyhow::Result<(ServerMessage, HashSet<Uuid>)> {
+    let (messages, has_more) = match since {
+        Some(since) => {
+            let mut messages = state.db.get_chat_messages(lesson_id, Some(since), None, MAX_RESUME + 1).await?;
+            let has_more = messages.len() as i64 > MAX_RESUME;
+            messages.truncate(MAX_RESUME as usize);
+            (messages, has_more)
+        }
+        None => {
+            let mut messages = state.db.get_chat_messages(lesson_id, None, None, HISTORY_ON_JOIN + 1).await?;
+            let has_more = messages.len() as i64 > HISTORY_ON_JOIN;
+            if has_more {
+                messages.remove(0);
+            }
+            (messages, has_more)
+        }
+    };
+    let ids = messages.iter().map(|m| m.id).collect();
+    let messages = chat_messages(state, messages).await?;
+    Ok((ServerMessage::History { messages, has_more }, ids))
+}
+
+// Deletes and edits to messages the client saw before `since`, as the events it
+// would have received had it stayed connected
+async fn load_changes(state: &AppState, lesson_id: Uuid, since: Option<i64>) -> anyhow::Result<Vec<ServerMessage>> {
+    let Some(since) = since else {
+        return Ok(Vec::new());
+    };
+    let changed = state.db.get_chat_changes(lesson_id, since, MAX_RESUME).await?;
+    Ok(changed
+        .into_iter()
+        .filter_map(|m| match (m.deleted, m.edited_at) {
+            (true, _) => Some(ServerMessage::Moderation(ModerationEvent::MessageDeleted { message_id: m.id })),
+            (false, Some(edited_at)) => Some(ServerMessage::Edited { message_id: m.id, message: m.message, edited_at }),
+            (false, None) => None,
+        })
+        .collect())
+}
+
+// The latest private messages `user_id` can read in the lesson
+async fn load_private_history(state: &AppState, lesson_id: Uuid, user_id: Uuid) -> anyhow::Result<ServerMessage> {
+    let is_teacher = state.db.get_lesson_teacher_ids(lesson_id).await?.contains(&user_id);
+    let messages = state.db.get_private_messages(lesson_id, user_id, is_teacher, HISTORY_ON_JOIN).await?;
+    Ok(ServerMessage::PrivateHistory { messages: messages.into_iter().map(PrivateMessage::from).collect() })
+}
+
+// Stored messages as sent to clients, with their reactions
+pub async fn chat_messages(state: &AppState, messages: Vec<LessonChatMessage>) -> anyhow::Result<Vec<ChatMessage>> {
+    let ids: Vec<Uuid> = messages.iter().map(|m| m.id).collect();
+    let mut reactions = Reaction::group(state.db.get_chat_reactions(&ids).await?);
+    Ok(messages
+        .into_iter()
+        .map(|m| {
+            let reactions = reactions.remove(&m.id).unwrap_or_default();
+            ChatMessage { reactions, ..ChatMessage::from(m) }
+        })
+        .collect())
+}
+
+async fn handle_socket(socket: WebSocket, conn: Connection, since: Option<i64>) {
     let (mut sender, mut receiver) = socket.split();
 
-    // Per-lesson chat channel
-    let tx = {
-        let mut map = LESSON_CHAT_TX.lock().unwrap();
-        map.entry(params.room.clone())
-            .or_insert_with(|| {
-                let (tx, _rx) = broadcast::channel(200);
-                tx
-            })
-            .clone()
-    };
-    let mut rx = tx.subscribe();
-
-    // Spawn a task to handle incoming messages
-    let tx_clone = tx.clone();
-    let state_clone = state.clone();
-    let user_id = claims.sub.clone();
-    let _username = claims.user_type.to_string();
-    let lesson_id_uuid = lesson_id.as_ref().and_then(|id| uuid::Uuid::parse_str(id).ok());
+    // Room broadcasts go to everyone; replies (acks, errors) only to this client
+    let mut subscription = rooms::subscribe(conn.room);
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<ServerFrame>();
+
+    let _ = reply_tx.send(ServerFrame::new(ServerMessage::Welcome {
+        protocol_version: PROTOCOL_VERSION,
+        user_id: conn.user_id,
+        room: conn.room,
+    }));
+
+    match presence::join(&conn.state.db, conn.room, conn.id, conn.user_id, &conn.username, conn.user_type.clone()).await {
+        Ok(Some(participant)) => {
+            conn.state.broker.publish(conn.room, ServerMessage::Presence { event: PresenceEvent::Joined, participant });
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("failed to record {} joining {:?}: {e:?}", conn.user_id, conn.room),
+    }
+    match presence::participants(&conn.state.db, conn.room).await {
+        Ok(participants) => {
+            let _ = reply_tx.send(ServerFrame::new(ServerMessage::Roster { participants }));
+        }
+        Err(e) => tracing::error!("failed to load the roster of {:?}: {e:?}", conn.room),
+    }
+
+    // Record enrolled students' presence in lesson rooms for attendance
+    let mut attendance_session = None;
+    // Whether this user teaches the lesson, which decides what they see of polls
+    let mut teacher = false;
+    // Live chat that was already replayed from history
+    let mut replayed = HashSet::new();
+    if let Some(lesson_id) = conn.lesson_id {
+        if let Ok(Some(lesson)) = conn.state.db.get_lesson(lesson_id).await {
+            // Staff and anyone else who isn't enrolled just look in
+            if conn.state.db.is_enrolled(lesson.classroom_id, conn.user_id).await.unwrap_or(false) {
+                let _ = conn.state.db.add_lesson_participant(lesson_id, conn.user_id).await;
+                attendance_session = conn.state.db.start_attendance_session(lesson_id, conn.user_id).await.ok();
+            }
+            if lesson.chat_closed {
+                let _ = reply_tx.send(ServerFrame::new(ServerMessage::System {
+                    text: "The teacher has closed the chat".to_string(),
+                }));
+            }
+            if let Some(seconds) = lesson.chat_slow_mode_seconds {
+                let _ = reply_tx.send(ServerFrame::new(ServerMessage::System {
+                    text: format!("Slow mode is on: one message every {seconds} seconds"),
+                }));
+            }
+        }
+        // We're already subscribed, so anything posted while this loads
+        // arrives live and is de-duplicated against the replay
+        match load_history(&conn.state, lesson_id, since).await {
+            Ok((history, ids)) => {
+                let _ = reply_tx.send(ServerFrame::new(history));
+                replayed = ids;
+            }
+            Err(e) => tracing::error!("failed to load chat history for lesson {lesson_id}: {e:?}"),
+        }
+        match load_changes(&conn.state, lesson_id, since).await {
+            Ok(changes) => {
+                for change in changes {
+                    let _ = reply_tx.send(ServerFrame::new(change));
+                }
+            }
+            Err(e) => tracing::error!("failed to load chat changes for lesson {lesson_id}: {e:?}"),
+        }
+        match load_private_history(&conn.state, lesson_id, conn.user_id).await {
+            Ok(history) => {
+                let _ = reply_tx.send(ServerFrame::new(history));
+            }
+            Err(e) => tracing::error!("failed to load private messages for lesson {lesson_id}: {e:?}"),
+        }
+        match load_hands(&conn.state, lesson_id).await {
+            Ok(hands) => {
+                let _ = reply_tx.send(ServerFrame::new(hands));
+            }
+            Err(e) => tracing::error!("failed to load raised hands for lesson {lesson_id}: {e:?}"),
+        }
+        teacher = teaches(&conn, lesson_id).await.unwrap_or(false);
+        match load_polls(&conn.state, lesson_id, conn.user_id, teacher).await {
+            Ok(polls) => {
+                let _ = reply_tx.send(ServerFrame::new(polls));
+            }
+            Err(e) => tracing::error!("failed to load polls for lesson {lesson_id}: {e:?}"),
+        }
+        match load_whiteboard(&conn.state, lesson_id).await {
+            Ok(snapshot) => {
+                let _ = reply_tx.send(ServerFrame::new(snapshot));
+            }
+            Err(e) => tracing::error!("failed to load the whiteboard for lesson {lesson_id}: {e:?}"),
+        }
+        match load_playback(&conn.state, lesson_id).await {
+            Ok(playback) => {
+                let _ = reply_tx.send(ServerFrame::new(playback));
+            }
+            Err(e) => tracing::error!("failed to load the shared video for lesson {lesson_id}: {e:?}"),
+        }
+        match load_breakouts(&conn.state, lesson_id).await {
+            Ok(breakouts) => {
+                let _ = reply_tx.send(ServerFrame::new(breakouts));
+            }
+            Err(e) => tracing::error!("failed to load breakout rooms for lesson {lesson_id}: {e:?}"),
+        }
+    }
+    if let Room::Breakout { lesson_id, id } = conn.room {
+        match load_breakout_history(&conn.state, id).await {
+            Ok((history, ids)) => {
+                let _ = reply_tx.send(ServerFrame::new(history));
+                replayed = ids;
+            }
+            Err(e) => tracing::error!("failed to load chat history for breakout room {id}: {e:?}"),
+        }
+        match load_breakouts(&conn.state, lesson_id).await {
+            Ok(breakouts) => {
+                let _ = reply_tx.send(ServerFrame::new(breakouts));
+            }
+            Err(e) => tracing::error!("failed to load breakout rooms for lesson {lesson_id}: {e:?}"),
+        }
+    }
+    let user_id = conn.user_id;
+    let (conn_id, room) = (conn.id, conn.room);
+    let state = conn.state.clone();
+    let send_state = conn.state.clone();
 
     // Listen for incoming messages
-    tokio::spawn(async move {
-        while let Some(msg) = receiver.next().await {
-            if let Ok(msg) = msg {
-                match msg {
-                    Message::Text(text) => {
-                        // Parse as ChatMessage
-                        if let Ok(mut chat_msg) = serde_json::from_str::<ChatMessage>(&text) {
-                            chat_msg.user_id = user_id.clone();
-                            // Permission check: if student, check muted and chat_closed
-                            if let Some(lesson_id) = lesson_id_uuid {
-                                let is_teacher = claims.user_type == crate::models::UserType::Teacher;
-                                let is_muted = if !is_teacher {
-                                    state_clone.db.is_participant_muted(lesson_id, uuid::Uuid::parse_str(&user_id).unwrap()).await.unwrap_or(false)
-                                } else { false };
-                                let lesson = state_clone.db.get_lesson(lesson_id).await.unwrap_or(None);
-                                let chat_closed = lesson.map(|l| l.chat_closed).unwrap_or(false);
-                                if chat_closed && !is_teacher {
-                                    continue;
-                                }
-                                if is_muted && !is_teacher {
-                                    continue;
-                                }
-                                // Save to DB
-                                let db_msg = crate::models::LessonChatMessage {
-                                    id: uuid::Uuid::new_v4(),
-                                    lesson_id,
-                                    user_id: uuid::Uuid::parse_str(&user_id).unwrap(),
-                                    username: chat_msg.username.clone(),
-                                    message: chat_msg.message.clone(),
-                                    timestamp: chrono::Utc::now(),
-                                    deleted: false,
-                                };
-                                let _ = state_clone.db.add_chat_message(&db_msg).await;
+    let mut recv_task = tokio::spawn(async move {
+        while let Some(Ok(msg)) = receiver.next().await {
+            let active = matches!(msg, Message::Text(_) | Message::Binary(_));
+            match presence::touch(&conn.state.db, room, conn_id, user_id, active).await {
+                Ok(Some(participant)) => {
+                    conn.state.broker.publish(room, ServerMessage::Presence { event: PresenceEvent::Active, participant });
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!("failed to record activity on connection {conn_id}: {e:?}"),
+            }
+            let reply = match msg {
+                Message::Text(text) => handle_frame(&conn, &text).await,
+                Message::Binary(_) => ServerFrame::new(error(ErrorCode::InvalidFrame, "binary frames are not supported")),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if reply_tx.send(reply).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Outgoing messages
+    let mut send_task = tokio::spawn(async move {
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.tick().await;
+        // Latest chat seq this client has been sent, to resync from if it lags
+        let mut last_seq = since;
+        'conn: loop {
+            // Replies first, so welcome and history go out before any live message
+            let frame = tokio::select! {
+                biased;
+                _ = heartbeat.tick() => {
+                    if presence::is_dead(conn_id) {
+                        break;
+                    }
+                    match presence::check_idle(&send_state.db, room, conn_id, user_id).await {
+                        Ok(Some(participant)) => {
+                            send_state.broker.publish(room, ServerMessage::Presence { event: PresenceEvent::Idle, participant });
+                        }
+                        Ok(None) => {}
+                        Err(e) => tracing::error!("failed to refresh connection {conn_id}: {e:?}"),
+                    }
+                    if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                reply = reply_rx.recv() => match reply {
+                    Some(frame) => frame,
+                    None => break,
+                },
+                event = subscription.recv() => match event {
+                    Ok(RoomEvent { to: Some(to), .. }) if to != user_id => continue,
+                    Ok(RoomEvent { message: ServerMessage::Chat(chat), .. }) if replayed.contains(&chat.id) => continue,
+                    Ok(event) => ServerFrame::new(event.message),
+                    Err(Lagged(missed)) => {
+                        tracing::warn!("connection {conn_id} fell {missed} events behind in {room:?}; resyncing");
+                        for frame in resync(&send_state, room, user_id, teacher, missed, last_seq, &mut replayed).await {
+                            last_seq = last_seq.max(latest_seq(&frame));
+                            let text = serde_json::to_string(&frame).unwrap();
+                            if sender.send(Message::Text(text)).await.is_err() {
+                                break 'conn;
                             }
-                            let _ = tx_clone.send(chat_msg);
                         }
+                        continue;
                     }
-                    Message::Close(_) => break,
-                    _ => {}
+                },
+            };
+            last_seq = last_seq.max(latest_seq(&frame));
+            let text = serde_json::to_string(&frame).unwrap();
+            if sender.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Whichever side finishes first (client closed, send failed) ends the connection
+    tokio::select! {
+        _ = &mut recv_task => send_task.abort(),
+        _ = &mut send_task => recv_task.abort(),
+    }
+
+    let left = presence::leave(&state.db, room, conn_id, user_id).await.unwrap_or_else(|e| {
+        tracing::error!("failed to record {user_id} leaving {room:?}: {e:?}");
+        None
+    });
+    if let Some(participant) = left {
+        state.broker.publish(room, ServerMessage::Presence { event: PresenceEvent::Left, participant });
+        throttle::forget_quiet_senders();
+        // A hand left up by someone who has gone would hold up the queue
+        if let Some(lesson_id) = room.lesson_id() {
+            if let Ok(true) = state.db.lower_hand(lesson_id, user_id).await {
+                if let Ok(hands) = load_hands(&state, lesson_id).await {
+                    state.broker.publish(room, hands);
                 }
             }
         }
+    }
+    if let Some(session_id) = attendance_session {
+        let _ = state.db.end_attendance_session(session_id).await;
+    }
+}
+
+// Seq of the newest chat message in a frame, if it carries any
+fn latest_seq(frame: &ServerFrame) -> Option<i64> {
+    match &frame.message {
+        ServerMessage::Chat(chat) => chat.seq,
+        ServerMessage::History { messages, .. } => messages.last().and_then(|m| m.seq),
+        _ => None,
+    }
+}
+
+// Catches up a connection whose channel discarded events it hadn't read yet.
+// Presence is refreshed with a new roster; in lesson rooms the stored chat after
+// the last message it saw (and deletes and edits to earlier ones), the private
+// messages, raised hands, polls, the whiteboard, the shared video and breakout
+// rooms are replayed, in breakout
+// rooms their chat and the lesson's rooms, elsewhere it is told what it missed.
+async fn resync(
+    state: &AppState,
+    room: Room,
+    user_id: Uuid,
+    teacher: bool,
+    missed: u64,
+    last_seq: Option<i64>,
+    replayed: &mut HashSet<Uuid>,
+) -> Vec<ServerFrame> {
+    let mut frames = Vec::new();
+    match presence::participants(&state.db, room).await {
+        Ok(participants) => frames.push(ServerFrame::new(ServerMessage::Roster { participants })),
+        Err(e) => tracing::error!("failed to resync the roster of {room:?}: {e:?}"),
+    }
+    match room {
+        Room::Lesson { id: lesson_id } => {
+            match load_history(state, lesson_id, last_seq).await {
+                Ok((history, ids)) => {
+                    replayed.extend(ids);
+                    frames.push(ServerFrame::new(history));
+                }
+                Err(e) => tracing::error!("failed to resync chat history for lesson {lesson_id}: {e:?}"),
+            }
+            match load_changes(state, lesson_id, last_seq).await {
+                Ok(changes) => frames.extend(changes.into_iter().map(ServerFrame::new)),
+                Err(e) => tracing::error!("failed to resync chat changes for lesson {lesson_id}: {e:?}"),
+            }
+            match load_private_history(state, lesson_id, user_id).await {
+                Ok(history) => frames.push(ServerFrame::new(history)),
+                Err(e) => tracing::error!("failed to resync private messages for lesson {lesson_id}: {e:?}"),
+            }
+            match load_hands(state, lesson_id).await {
+                Ok(hands) => frames.push(ServerFrame::new(hands)),
+                Err(e) => tracing::error!("failed to resync raised hands for lesson {lesson_id}: {e:?}"),
+            }
+            match load_polls(state, lesson_id, user_id, teacher).await {
+                Ok(polls) => frames.push(ServerFrame::new(polls)),
+                Err(e) => tracing::error!("failed to resync polls for lesson {lesson_id}: {e:?}"),
+            }
+            match load_whiteboard(state, lesson_id).await {
+                Ok(snapshot) => frames.push(ServerFrame::new(snapshot)),
+                Err(e) => tracing::error!("failed to resync the whiteboard for lesson {lesson_id}: {e:?}"),
+            }
+            match load_playback(state, lesson_id).await {
+                Ok(playback) => frames.push(ServerFrame::new(playback)),
+                Err(e) => tracing::error!("failed to resync the shared video for lesson {lesson_id}: {e:?}"),
+            }
+            match load_breakouts(state, lesson_id).await {
+                Ok(breakouts) => frames.push(ServerFrame::new(breakouts)),
+                Err(e) => tracing::error!("failed to resync breakout rooms for lesson {lesson_id}: {e:?}"),
+            }
+        }
+        Room::Breakout { lesson_id, id } => {
+            match load_breakout_history(state, id).await {
+                Ok((history, ids)) => {
+                    replayed.extend(ids);
+                    frames.push(ServerFrame::new(history));
+                }
+                Err(e) => tracing::error!("failed to resync chat history for breakout room {id}: {e:?}"),
+            }
+            match load_breakouts(state, lesson_id).await {
+                Ok(breakouts) => frames.push(ServerFrame::new(breakouts)),
+                Err(e) => tracing::error!("failed to resync breakout rooms for lesson {lesson_id}: {e:?}"),
+            }
+        }
+        _ => frames.push(ServerFrame::new(ServerMessage::System {
+            text: format!("Your connection fell behind and {missed} updates were missed"),
+        })),
+    }
+    frames
+}
+
+fn error(code: ErrorCode, message: impl Into<String>) -> ServerMessage {
+    ServerMessage::Error { code, message: message.into(), retry_after_ms: None }
+}
+
+fn throttled(t: Throttled) -> ServerMessage {
+    let retry_after = t.retry_after();
+    let (code, message) = match t {
+        Throttled::RateLimited(_) => (ErrorCode::RateLimited, "you are sending messages too quickly".to_string()),
+        Throttled::SlowMode(_) => (
+            ErrorCode::SlowMode,
+            format!("slow mode is on; you can send another message in {} seconds", retry_after.as_secs_f64().ceil()),
+        ),
+    };
+    ServerMessage::Error { code, message, retry_after_ms: Some(retry_after.as_millis() as u64) }
+}
+
+// Parses one text frame and returns the reply to send back to the client
+async fn handle_frame(conn: &Connection, text: &str) -> ServerFrame {
+    let value: serde_json::Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => return ServerFrame::new(error(ErrorCode::InvalidFrame, e.to_string())),
+    };
+    // Pull the correlation id out first so even malformed frames get a matching reply
+    let id = value.get("id").and_then(|v| v.as_str()).map(|s| s.to_string());
+    let version_error = || error(ErrorCode::UnsupportedVersion, format!("expected \"v\": {PROTOCOL_VERSION}"));
+    let is_current_version = value.get("v").and_then(|v| v.as_u64()) == Some(PROTOCOL_VERSION as u64);
+    let frame: ClientFrame = match serde_json::from_value(value) {
+        Ok(frame) => frame,
+        // A newer client's message types won't parse; say why rather than "unknown variant"
+        Err(_) if !is_current_version => return ServerFrame::reply(id, version_error()),
+        Err(e) => return ServerFrame::reply(id, error(ErrorCode::InvalidFrame, e.to_string())),
+    };
+    if frame.v != PROTOCOL_VERSION {
+        return ServerFrame::reply(frame.id, version_error());
+    }
+
+    let reply = match frame.message {
+        ClientMessage::Ping => ServerMessage::Pong,
+        ClientMessage::Chat { message, reply_to } => ack(post_chat(conn, message, reply_to).await),
+        ClientMessage::Edit { message_id, message } => ack(edit_chat(conn, message_id, message).await),
+        ClientMessage::React { message_id, emoji } => ack(react(conn, message_id, emoji, true).await),
+        ClientMessage::Unreact { message_id, emoji } => ack(react(conn, message_id, emoji, false).await),
+        ClientMessage::Private { to, message, include_co_teachers } => {
+            ack(send_private(conn, to, message, include_co_teachers).await)
+        }
+        ClientMessage::RaiseHand => done(raise_hand(conn).await),
+        ClientMessage::LowerHand { user_id } => done(lower_hand(conn, user_id).await),
+        ClientMessage::CallOn { user_id } => done(call_on(conn, user_id).await),
+        ClientMessage::ClearHands => done(clear_hands(conn).await),
+        ClientMessage::LaunchPoll { question, options, correct_option } => {
+            ack(launch_poll(conn, question, options, correct_option).await)
+        }
+        ClientMessage::Answer { poll_id, option } => done(answer_poll(conn, poll_id, option).await),
+        ClientMessage::ClosePoll { poll_id } => done(close_poll(conn, poll_id).await),
+        ClientMessage::RevealPoll { poll_id } => done(reveal_poll(conn, poll_id).await),
+        ClientMessage::Draw { op } => done(draw(conn, op).await),
+        ClientMessage::SetWhiteboardAccess { students_can_draw } => {
+            done(set_whiteboard_access(conn, students_can_draw).await)
+        }
+        ClientMessage::LoadVideo { url } => done(load_video(conn, url).await),
+        ClientMessage::Play { position } => done(control_playback(conn, PlaybackChange::Play(position)).await),
+        ClientMessage::Pause { position } => done(control_playback(conn, PlaybackChange::Pause(position)).await),
+        ClientMessage::Seek { position } => done(control_playback(conn, PlaybackChange::Seek(position)).await),
+        ClientMessage::SetRate { rate } => done(control_playback(conn, PlaybackChange::Rate(rate)).await),
+        ClientMessage::CloseVideo => done(close_video(conn).await),
+        ClientMessage::StartBreakouts { split, minutes } => done(start_breakouts(conn, split, minutes).await),
+        ClientMessage::BroadcastToBreakouts { message } => done(broadcast_to_breakouts(conn, message).await),
+        ClientMessage::SetBreakoutTimer { minutes } => done(set_breakout_timer(conn, minutes).await),
+        ClientMessage::EndBreakouts => done(end_breakouts(conn).await),
+        ClientMessage::SyncPlayback => match lesson_only(conn, "shared videos") {
+            Ok(lesson_id) => load_playback(&conn.state, lesson_id).await
+                .unwrap_or_else(internal("could not look up the video")),
+            Err(e) => e,
+        },
+    };
+    ServerFrame::reply(frame.id, reply)
+}
+
+fn ack(result: Result<Uuid, ServerMessage>) -> ServerMessage {
+    match result {
+        Ok(message_id) => ServerMessage::Ack { message_id: Some(message_id) },
+        Err(e) => e,
+    }
+}
+
+fn done(result: Result<(), ServerMessage>) -> ServerMessage {
+    match result {
+        Ok(()) => ServerMessage::Ack { message_id: None },
+        Err(e) => e,
+    }
+}
+
+fn is_staff(conn: &Connection) -> bool {
+    matches!(conn.user_type, UserType::Teacher | UserType::Admin)
+}
+
+fn lesson_only(conn: &Connection, what: &str) -> Result<Uuid, ServerMessage> {
+    conn.lesson_id.ok_or_else(|| error(ErrorCode::NotAllowed, format!("{what} only work in lesson chat")))
+}
+
+fn internal(message: &str) -> impl Fn(anyhow::Error) -> ServerMessage + '_ {
+    move |_| error(ErrorCode::Internal, message)
+}
+
+fn clean_message(message: String) -> Result<String, ServerMessage> {
+    let message = message.trim().to_string();
+    if message.is_empty() || message.chars().count() > MAX_CHAT_LENGTH {
+        return Err(error(
+            ErrorCode::InvalidMessage,
+            format!("messages must be between 1 and {MAX_CHAT_LENGTH} characters"),
+        ));
+    }
+    Ok(message)
+}
+
+// Checks a student may take part in the chat at all: throttling, then (in
+// lesson and breakout rooms) mutes and closed chat. Teachers and admins skip this.
+async fn check_student(conn: &Connection) -> Result<Option<ChatPolicy>, ServerMessage> {
+    // Throttle before touching the database, so a flood costs nothing
+    throttle::take_token(conn.room, conn.user_id).map_err(throttled)?;
+    let Some(lesson_id) = conn.room.parent_lesson_id() else {
+        return Ok(None);
+    };
+    let policy = conn.state.db.get_chat_policy(lesson_id, conn.user_id).await
+        .map_err(internal("message could not be checked"))?;
+    if let Some(policy) = &policy {
+        if policy.is_muted {
+            return Err(error(ErrorCode::Muted, "you have been muted by the teacher"));
+        }
+        if policy.chat_closed {
+            return Err(error(ErrorCode::ChatClosed, "the teacher has closed the chat"));
+        }
+    }
+    Ok(policy)
+}
+
+// Checks the text of a student's new or edited message against the lesson's
+// length cap and the classroom's content filter, masking it in place. Returns
+// the classroom and reasons if the filter flagged it.
+async fn check_student_text(
+    conn: &Connection,
+    policy: Option<&ChatPolicy>,
+    text: &mut String,
+) -> Result<Option<(Uuid, Vec<String>)>, ServerMessage> {
+    if let Some(max) = policy.and_then(|p| p.max_length) {
+        if text.chars().count() > max as usize {
+            return Err(error(
+                ErrorCode::InvalidMessage,
+                format!("messages are limited to {max} characters in this lesson"),
+            ));
+        }
+    }
+    let (classroom_id, rule_ids) = match (conn.room, policy) {
+        (Room::Classroom { id }, _) => (id, None),
+        (_, Some(policy)) => (policy.classroom_id, Some(policy.filter_rule_ids.as_slice())),
+        _ => return Ok(None),
+    };
+    let reasons = filter_message(conn, classroom_id, rule_ids, text).await?;
+    Ok((!reasons.is_empty()).then_some((classroom_id, reasons)))
+}
+
+// Puts a message the filter flagged into the classroom's moderation queue
+async fn queue_flagged(conn: &Connection, flagged: Option<(Uuid, Vec<String>)>, message_id: Uuid, text: &str) {
+    let Some((classroom_id, reasons)) = flagged else {
+        return;
+    };
+    let flag = FlaggedChatMessage {
+        id: Uuid::new_v4(),
+        classroom_id,
+        lesson_id: conn.room.parent_lesson_id(),
+        breakout_room_id: match conn.room {
+            Room::Breakout { id, .. } => Some(id),
+            _ => None,
+        },
+        message_id,
+        user_id: conn.user_id,
+        username: conn.username.clone(),
+        message: text.to_string(),
+        reasons,
+        status: FlagStatus::Pending,
+        created_at: chrono::Utc::now(),
+        reviewed_by: None,
+        reviewed_at: None,
+        message_deleted: false,
+    };
+    if let Err(e) = conn.state.db.create_flagged_chat_message(&flag).await {
+        tracing::error!("failed to queue flagged message {message_id} for review: {e:?}");
+    }
+}
+
+async fn post_chat(conn: &Connection, message: String, reply_to: Option<Uuid>) -> Result<Uuid, ServerMessage> {
+    let mut chat = ChatMessage {
+        id: Uuid::new_v4(),
+        seq: None,
+        user_id: conn.user_id,
+        username: conn.username.clone(),
+        role: Role::from(&conn.user_type),
+        message: clean_message(message)?,
+        timestamp: chrono::Utc::now(),
+        reply_to,
+        edited_at: None,
+        reactions: Vec::new(),
+    };
+
+    let mut flagged = None;
+    let mut slow_mode = None;
+    if !is_staff(conn) {
+        let policy = check_student(conn).await?;
+        flagged = check_student_text(conn, policy.as_ref(), &mut chat.message).await?;
+        slow_mode = policy.and_then(|p| p.slow_mode_seconds).map(|s| Duration::from_secs(s as u64));
+    }
+    if let Some(reply_to) = reply_to {
+        let lesson_id = lesson_only(conn, "replies")?;
+        let original = conn.state.db.get_chat_message(lesson_id, reply_to).await
+            .map_err(internal("message could not be checked"))?;
+        if original.is_none() {
+            return Err(error(ErrorCode::NotFound, "the message you replied to no longer exists"));
+        }
+    }
+    if !is_staff(conn) {
+        throttle::check_slow_mode(conn.room, conn.user_id, slow_mode).map_err(throttled)?;
+    }
+
+    if let Some(lesson_id) = conn.lesson_id {
+        // Save to DB
+        let db_msg = LessonChatMessage {
+            id: chat.id,
+            lesson_id,
+            user_id: conn.user_id,
+            username: chat.username.clone(),
+            user_type: conn.user_type.clone(),
+            message: chat.message.clone(),
+            timestamp: chat.timestamp,
+            deleted: false,
+            seq: 0,
+            edited_at: None,
+            reply_to,
+        };
+        match conn.state.db.add_chat_message(&db_msg).await {
+            Ok(seq) => chat.seq = Some(seq),
+            Err(_) => return Err(error(ErrorCode::Internal, "message could not be saved")),
+        }
+    }
+    if let Room::Breakout { lesson_id, id } = conn.room {
+        save_breakout_message(conn, lesson_id, id, &chat).await?;
+    }
+
+    let (id, text) = (chat.id, chat.message.clone());
+    conn.state.broker.publish(conn.room, ServerMessage::Chat(Box::new(chat)));
+    queue_flagged(conn, flagged, id, &text).await;
+    Ok(id)
+}
+
+// Someone else who may be in the connection's lesson room, whether or not
+// they're connected
+async fn lesson_member(conn: &Connection, user_id: Uuid) -> Result<User, ServerMessage> {
+    let not_in_lesson = || error(ErrorCode::NotFound, "that person isn't in this lesson");
+    let user = conn.state.db.get_user_by_id(user_id).await
+        .map_err(internal("could not look up that person"))?
+        .filter(|u| u.is_active)
+        .ok_or_else(not_in_lesson)?;
+    match authorize_room(&conn.state, &user, conn.room).await {
+        Ok(()) => Ok(user),
+        Err(StatusCode::FORBIDDEN) => Err(not_in_lesson()),
+        Err(_) => Err(error(ErrorCode::Internal, "could not look up that person")),
+    }
+}
+
+// Sends a private message to one person in the lesson. It's delivered on the
+// lesson room to the recipient, the sender's own connections and, if asked, the
+// lesson's teachers. Students can only write to teachers, and their messages
+// are throttled and filtered like public chat, except that flag rules don't
+// queue them: a teacher reads them anyway. Mutes and closed chat don't apply,
+// so a muted student can still talk to the teacher.
+async fn send_private(conn: &Connection, to: Uuid, message: String, include_co_teachers: bool) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "private messages")?;
+    let mut text = clean_message(message)?;
+    if to == conn.user_id {
+        return Err(error(ErrorCode::InvalidMessage, "you can't message yourself"));
+    }
+    let db = &conn.state.db;
+    let teachers = db.get_lesson_teacher_ids(lesson_id).await.map_err(internal("message could not be checked"))?;
+    if !is_staff(conn) && !teachers.contains(&to) {
+        return Err(error(ErrorCode::NotAllowed, "you can only message your teachers privately"));
+    }
+    let recipient = lesson_member(conn, to).await?;
+
+    if !is_staff(conn) {
+        throttle::take_token(conn.room, conn.user_id).map_err(throttled)?;
+        let policy = db.get_chat_policy(lesson_id, conn.user_id).await.map_err(internal("message could not be checked"))?;
+        check_student_text(conn, policy.as_ref(), &mut text).await?;
+    }
+
+    let stored = LessonPrivateMessage {
+        id: Uuid::new_v4(),
+        lesson_id,
+        sender_id: conn.user_id,
+        sender_name: conn.username.clone(),
+        sender_type: conn.user_type.clone(),
+        recipient_id: to,
+        recipient_name: recipient.display_name().chars().take(100).collect(),
+        message: text,
+        include_co_teachers,
+        created_at: chrono::Utc::now(),
+    };
+    db.add_private_message(&stored).await.map_err(internal("message could not be saved"))?;
+
+    let id = stored.id;
+    let mut audience = vec![conn.user_id, to];
+    if include_co_teachers {
+        audience.extend(teachers);
+    }
+    let mut delivered = HashSet::new();
+    let message = PrivateMessage::from(stored);
+    for user_id in audience.into_iter().filter(|id| delivered.insert(*id)) {
+        conn.state.broker.publish_to(conn.room, user_id, ServerMessage::Private(Box::new(message.clone())));
+    }
+    Ok(id)
+}
+
+// Whether the connection's user teaches the lesson: its teacher, a co-teacher
+// of its classroom, or an admin
+async fn teaches(conn: &Connection, lesson_id: Uuid) -> Result<bool, ServerMessage> {
+    if conn.user_type == UserType::Admin {
+        return Ok(true);
+    }
+    let teachers = conn.state.db.get_lesson_teacher_ids(lesson_id).await.map_err(internal("could not check your role"))?;
+    Ok(teachers.contains(&conn.user_id))
+}
+
+async fn load_hands(state: &AppState, lesson_id: Uuid) -> anyhow::Result<ServerMessage> {
+    let hands = state.db.get_raised_hands(lesson_id).await?;
+    Ok(ServerMessage::Hands { queue: hands.into_iter().map(protocol::RaisedHand::from).collect() })
+}
+
+// The queue is stored, so every instance sends the same one
+async fn publish_hands(conn: &Connection, lesson_id: Uuid) -> Result<(), ServerMessage> {
+    let hands = load_hands(&conn.state, lesson_id).await.map_err(internal("the queue could not be loaded"))?;
+    conn.state.broker.publish(conn.room, hands);
+    Ok(())
+}
+
+async fn raise_hand(conn: &Connection) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "raised hands")?;
+    if teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "teachers don't raise hands"));
+    }
+    // Raising and lowering over and over would spam everyone's queue
+    throttle::take_token(conn.room, conn.user_id).map_err(throttled)?;
+    if conn.state.db.raise_hand(lesson_id, conn.user_id, &conn.username).await.map_err(internal("your hand could not be raised"))? {
+        publish_hands(conn, lesson_id).await?;
+    }
+    Ok(())
+}
+
+async fn lower_hand(conn: &Connection, user_id: Option<Uuid>) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "raised hands")?;
+    let user_id = match user_id {
+        Some(id) if id != conn.user_id => {
+            if !teaches(conn, lesson_id).await? {
+                return Err(error(ErrorCode::NotAllowed, "only teachers can lower someone else's hand"));
+            }
+            id
+        }
+        _ => conn.user_id,
+    };
+    if conn.state.db.lower_hand(lesson_id, user_id).await.map_err(internal("the hand could not be lowered"))? {
+        publish_hands(conn, lesson_id).await?;
+    }
+    Ok(())
+}
+
+// Gives someone the floor and logs it as their speaking turn
+async fn call_on(conn: &Connection, user_id: Uuid) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "raised hands")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can call on someone"));
+    }
+    let user = lesson_member(conn, user_id).await?;
+    let username: String = user.display_name().chars().take(100).collect();
+    conn.state.db.call_on(lesson_id, user_id, &username, conn.user_id).await
+        .map_err(internal("the turn could not be recorded"))?;
+    conn.state.broker.publish(conn.room, ServerMessage::CalledOn { user_id, username });
+    publish_hands(conn, lesson_id).await
+}
+
+async fn clear_hands(conn: &Connection) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "raised hands")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can clear hands"));
+    }
+    if conn.state.db.clear_hands(lesson_id).await.map_err(internal("hands could not be cleared"))? > 0 {
+        publish_hands(conn, lesson_id).await?;
+    }
+    Ok(())
+}
+
+// How a poll looks to one viewer: teachers always see the results and a quiz's
+// answer, students once the teacher reveals them
+pub async fn poll_view(state: &AppState, poll: LessonPoll, teacher: bool) -> anyhow::Result<Poll> {
+    let show = teacher || poll.revealed;
+    let (id, correct_option, options) = (poll.id, poll.correct_option, poll.options.len());
+    let mut view = Poll::new(poll);
+    if show {
+        view.correct_option = correct_option.map(|c| c as usize);
+        view.results = Some(PollResults::new(options, &state.db.get_poll_counts(id).await?));
+    }
+    Ok(view)
+}
+
+async fn load_polls(state: &AppState, lesson_id: Uuid, user_id: Uuid, teacher: bool) -> anyhow::Result<ServerMessage> {
+    let answers: HashMap<Uuid, i32> = state.db.get_poll_answers(lesson_id, user_id).await?.into_iter().collect();
+    let mut polls = Vec::new();
+    for poll in state.db.get_polls(lesson_id).await? {
+        let your_answer = answers.get(&poll.id).map(|&option| option as usize);
+        polls.push(Poll { your_answer, ..poll_view(state, poll, teacher).await? });
+    }
+    Ok(ServerMessage::Polls { polls })
+}
+
+// Sends a message to the lesson's teachers, and to an admin running it
+async fn publish_to_teachers(conn: &Connection, lesson_id: Uuid, message: ServerMessage) -> Result<(), ServerMessage> {
+    let mut teachers = conn.state.db.get_lesson_teacher_ids(lesson_id).await.map_err(internal("could not find the teachers"))?;
+    if conn.user_type == UserType::Admin && !teachers.contains(&conn.user_id) {
+        teachers.push(conn.user_id);
+    }
+    for teacher in teachers {
+        conn.state.broker.publish_to(conn.room, teacher, message.clone());
+    }
+    Ok(())
+}
+
+// Sends everyone the class's view of a poll, and the teachers theirs, which
+// arrives after it and replaces it
+async fn publish_poll(conn: &Connection, lesson_id: Uuid, poll: LessonPoll) -> Result<(), ServerMessage> {
+    let revealed = poll.revealed;
+    let class_view = Poll::new(poll.clone());
+    let teacher_view = poll_view(&conn.state, poll, true).await.map_err(internal("the poll could not be loaded"))?;
+    if revealed {
+        conn.state.broker.publish(conn.room, ServerMessage::Poll(Box::new(teacher_view)));
+        return Ok(());
+    }
+    conn.state.broker.publish(conn.room, ServerMessage::Poll(Box::new(class_view)));
+    publish_to_teachers(conn, lesson_id, ServerMessage::Poll(Box::new(teacher_view))).await
+}
+
+// A poll of the connection's lesson, for a teacher to change
+async fn taught_poll(conn: &Connection, poll_id: Uuid) -> Result<(Uuid, LessonPoll), ServerMessage> {
+    let lesson_id = lesson_only(conn, "polls")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can run polls"));
+    }
+    let poll = conn.state.db.get_poll(lesson_id, poll_id).await
+        .map_err(internal("the poll could not be loaded"))?
+        .ok_or_else(|| error(ErrorCode::NotFound, "that poll doesn't exist"))?;
+    Ok((lesson_id, poll))
+}
+
+async fn launch_poll(
+    conn: &Connection,
+    question: String,
+    options: Vec<String>,
+    correct_option: Option<usize>,
+) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "polls")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can run polls"));
+    }
+    let question = question.trim().to_string();
+    if question.is_empty() || question.chars().count() > MAX_POLL_QUESTION_LENGTH {
+        return Err(error(
+            ErrorCode::InvalidMessage,
+            format!("questions must be between 1 and {MAX_POLL_QUESTION_LENGTH} characters"),
+        ));
+    }
+    let options: Vec<String> = options.iter().map(|o| o.trim().to_string()).collect();
+    if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+        return Err(error(ErrorCode::InvalidMessage, format!("polls need between 2 and {MAX_POLL_OPTIONS} options")));
+    }
+    if options.iter().any(|o| o.is_empty() || o.chars().count() > MAX_POLL_OPTION_LENGTH) {
+        return Err(error(
+            ErrorCode::InvalidMessage,
+            format!("options must be between 1 and {MAX_POLL_OPTION_LENGTH} characters"),
+        ));
+    }
+    if correct_option.is_some_and(|c| c >= options.len()) {
+        return Err(error(ErrorCode::InvalidMessage, "the correct option must be one of the options"));
+    }
+    let poll = LessonPoll {
+        id: Uuid::new_v4(),
+        lesson_id,
+        created_by: conn.user_id,
+        question,
+        options,
+        correct_option: correct_option.map(|c| c as i32),
+        revealed: false,
+        created_at: chrono::Utc::now(),
+        closed_at: None,
+    };
+    conn.state.db.create_poll(&poll).await.map_err(internal("the poll could not be saved"))?;
+    let id = poll.id;
+    publish_poll(conn, lesson_id, poll).await?;
+    Ok(id)
+}
+
+// Records a student's answer and streams the new tallies to the teachers
+async fn answer_poll(conn: &Connection, poll_id: Uuid, option: usize) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "polls")?;
+    if teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "teachers don't answer polls"));
+    }
+    let db = &conn.state.db;
+    let poll = db.get_poll(lesson_id, poll_id).await
+        .map_err(internal("the poll could not be loaded"))?
+        .ok_or_else(|| error(ErrorCode::NotFound, "that poll doesn't exist"))?;
+    if option >= poll.options.len() {
+        return Err(error(ErrorCode::InvalidMessage, "that isn't one of the options"));
+    }
+    if poll.closed_at.is_some() {
+        return Err(error(ErrorCode::NotAllowed, "this poll is closed"));
+    }
+    // Also false if the poll closed since we loaded it
+    if !db.answer_poll(poll_id, conn.user_id, &conn.username, option as i32).await.map_err(internal("your answer could not be saved"))? {
+        return Err(error(ErrorCode::NotAllowed, "you have already answered this poll"));
+    }
+    let counts = db.get_poll_counts(poll_id).await.map_err(internal("the results could not be loaded"))?;
+    let results = PollResults::new(poll.options.len(), &counts);
+    publish_to_teachers(conn, lesson_id, ServerMessage::PollResults { poll_id, results }).await
+}
+
+async fn close_poll(conn: &Connection, poll_id: Uuid) -> Result<(), ServerMessage> {
+    let (lesson_id, mut poll) = taught_poll(conn, poll_id).await?;
+    if conn.state.db.close_poll(poll_id).await.map_err(internal("the poll could not be closed"))? {
+        poll.closed_at = Some(chrono::Utc::now());
+        publish_poll(conn, lesson_id, poll).await?;
+    }
+    Ok(())
+}
+
+async fn reveal_poll(conn: &Connection, poll_id: Uuid) -> Result<(), ServerMessage> {
+    let (lesson_id, mut poll) = taught_poll(conn, poll_id).await?;
+    if conn.state.db.reveal_poll(poll_id).await.map_err(internal("the results could not be revealed"))? {
+        poll.revealed = true;
+        poll.closed_at = poll.closed_at.or(Some(chrono::Utc::now()));
+        publish_poll(conn, lesson_id, poll).await?;
+    }
+    Ok(())
+}
+
+// What's on the lesson's whiteboard, and the seq of the last op it includes
+pub async fn whiteboard_elements(state: &AppState, lesson_id: Uuid) -> anyhow::Result<(Option<i64>, Vec<WhiteboardElement>)> {
+    let ops = state.db.get_whiteboard_ops(lesson_id).await?;
+    let seq = ops.last().map(|o| o.seq);
+    let mut replayed = Vec::with_capacity(ops.len());
+    for o in ops {
+        replayed.push((o.user_id, serde_json::from_value::<WhiteboardOp>(o.op)?));
+    }
+    Ok((seq, whiteboard::replay(replayed)))
+}
+
+async fn load_whiteboard(state: &AppState, lesson_id: Uuid) -> anyhow::Result<ServerMessage> {
+    let lesson = state.db.get_lesson(lesson_id).await?.ok_or_else(|| anyhow::anyhow!("lesson {lesson_id} not found"))?;
+    let (seq, elements) = whiteboard_elements(state, lesson_id).await?;
+    Ok(ServerMessage::WhiteboardSnapshot { seq, elements, students_can_draw: lesson.whiteboard_students_can_draw })
+}
+
+async fn draw(conn: &Connection, op: WhiteboardOp) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "whiteboards")?;
+    whiteboard::validate(&op).map_err(|e| error(ErrorCode::InvalidMessage, e))?;
+    let db = &conn.state.db;
+    let teacher = teaches(conn, lesson_id).await?;
+    if !teacher {
+        throttle::take_token(conn.room, conn.user_id).map_err(throttled)?;
+        let lesson = db.get_lesson(lesson_id).await
+            .map_err(internal("could not check the whiteboard"))?
+            .ok_or_else(|| error(ErrorCode::NotFound, "this lesson no longer exists"))?;
+        if !lesson.whiteboard_students_can_draw {
+            return Err(error(ErrorCode::NotAllowed, "the teacher hasn't opened the whiteboard to students"));
+        }
+    }
+    let author = |id| async move {
+        db.get_whiteboard_element_author(lesson_id, id).await.map_err(internal("could not check the whiteboard"))
+    };
+    match &op {
+        // Duplicate ids and a full board are caught by the insert
+        WhiteboardOp::Stroke { .. } | WhiteboardOp::Shape { .. } => {}
+        WhiteboardOp::Erase { id } => match author(*id).await? {
+            None => return Err(error(ErrorCode::NotFound, "that element isn't on the board")),
+            Some(author) if !teacher && author != conn.user_id => {
+                return Err(error(ErrorCode::NotAllowed, "you can only erase your own drawing"));
+            }
+            Some(_) => {}
+        },
+        WhiteboardOp::Clear if !teacher => {
+            return Err(error(ErrorCode::NotAllowed, "only teachers can clear the whiteboard"));
+        }
+        WhiteboardOp::Clear => {}
+    }
+    let stored = serde_json::to_value(&op).map_err(|e| internal("the drawing could not be saved")(e.into()))?;
+    let seq = db.add_whiteboard_op(lesson_id, conn.user_id, &stored, whiteboard::MAX_ELEMENTS).await
+        .map_err(internal("the drawing could not be saved"))?;
+    let Some(seq) = seq else {
+        // Only strokes and shapes are ever refused
+        let id = op.element_id().unwrap_or_default();
+        return Err(match author(id).await? {
+            Some(_) => error(ErrorCode::InvalidMessage, "that element id is already on the board"),
+            None => error(ErrorCode::NotAllowed, "the whiteboard is full; erase or clear some of it first"),
+        });
+    };
+    conn.state.broker.publish(conn.room, ServerMessage::Whiteboard { seq, user_id: conn.user_id, op });
+    Ok(())
+}
+
+async fn set_whiteboard_access(conn: &Connection, students_can_draw: bool) -> Result<(), ServerMessage> {
+    let lesson_id = lesson_only(conn, "whiteboards")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can open the whiteboard to students"));
+    }
+    conn.state.db.set_whiteboard_access(lesson_id, students_can_draw).await.map_err(internal("the whiteboard could not be changed"))?;
+    conn.state.broker.publish(conn.room, ServerMessage::WhiteboardAccess { students_can_draw });
+    Ok(())
+}
+
+// The lesson's video as of now
+async fn load_playback(state: &AppState, lesson_id: Uuid) -> anyhow::Result<ServerMessage> {
+    let now = chrono::Utc::now();
+    let video = state.db.get_playback(lesson_id).await?.map(|p| VideoPlayback::at(p, now));
+    Ok(ServerMessage::Playback { video, server_time: now })
+}
+
+enum PlaybackChange {
+    Play(Option<f64>),
+    Pause(Option<f64>),
+    Seek(f64),
+    Rate(f64),
+}
+
+fn check_position(position: f64) -> Result<f64, ServerMessage> {
+    if (0.0..=MAX_VIDEO_POSITION).contains(&position) {
+        Ok(position)
+    } else {
+        Err(error(ErrorCode::InvalidMessage, "positions must be between 0 and 24 hours, in seconds"))
+    }
+}
+
+async fn video_teacher(conn: &Connection) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "shared videos")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can control the video"));
+    }
+    Ok(lesson_id)
+}
+
+// The state is stored as where the video was at the moment of the change, so
+// everyone, including anyone who joins later, can work out where it is now
+async fn save_playback(conn: &Connection, mut playback: LessonPlayback) -> Result<(), ServerMessage> {
+    let now = chrono::Utc::now();
+    playback.position = playback.position_at(now).min(MAX_VIDEO_POSITION);
+    playback.updated_by = conn.user_id;
+    playback.updated_at = now;
+    conn.state.db.save_playback(&playback).await.map_err(internal("the video could not be updated"))?;
+    let video = VideoPlayback::at(playback, now);
+    conn.state.broker.publish(conn.room, ServerMessage::Playback { video: Some(video), server_time: now });
+    Ok(())
+}
+
+async fn load_video(conn: &Connection, url: String) -> Result<(), ServerMessage> {
+    let lesson_id = video_teacher(conn).await?;
+    let url = url.trim();
+    if !(url.starts_with("http://") || url.starts_with("https://"))
+        || url.len() > MAX_VIDEO_URL_LENGTH
+        || url.contains(char::is_whitespace)
+    {
+        return Err(error(ErrorCode::InvalidMessage, "videos must be an http or https link"));
+    }
+    let playback = LessonPlayback {
+        lesson_id,
+        video_url: url.to_string(),
+        playing: false,
+        position: 0.0,
+        rate: 1.0,
+        updated_by: conn.user_id,
+        updated_at: chrono::Utc::now(),
+    };
+    save_playback(conn, playback).await
+}
+
+async fn control_playback(conn: &Connection, change: PlaybackChange) -> Result<(), ServerMessage> {
+    let lesson_id = video_teacher(conn).await?;
+    let mut playback = conn.state.db.get_playback(lesson_id).await
+        .map_err(internal("could not look up the video"))?
+        .ok_or_else(|| error(ErrorCode::NotFound, "no video has been loaded"))?;
+    // Carry the video forward to now before changing it
+    let now = chrono::Utc::now();
+    playback.position = playback.position_at(now);
+    playback.updated_at = now;
+    match change {
+        PlaybackChange::Play(position) | PlaybackChange::Pause(position) => {
+            playback.playing = matches!(change, PlaybackChange::Play(_));
+            if let Some(position) = position {
+                playback.position = check_position(position)?;
+            }
+        }
+        PlaybackChange::Seek(position) => playback.position = check_position(position)?,
+        PlaybackChange::Rate(rate) => {
+            if !VIDEO_RATES.contains(&rate) {
+                return Err(error(ErrorCode::InvalidMessage, "playback speed must be between 0.25 and 4"));
+            }
+            playback.rate = rate;
+        }
+    }
+    save_playback(conn, playback).await
+}
+
+async fn close_video(conn: &Connection) -> Result<(), ServerMessage> {
+    let lesson_id = video_teacher(conn).await?;
+    if !conn.state.db.clear_playback(lesson_id).await.map_err(internal("the video could not be closed"))? {
+        return Err(error(ErrorCode::NotFound, "no video has been loaded"));
+    }
+    conn.state.broker.publish(conn.room, ServerMessage::Playback { video: None, server_time: chrono::Utc::now() });
+    Ok(())
+}
+
+// The lesson's open breakout rooms with who's in each
+async fn load_breakouts(state: &AppState, lesson_id: Uuid) -> anyhow::Result<ServerMessage> {
+    let rooms = state.db.get_breakout_rooms(lesson_id, true).await?;
+    let ids: Vec<Uuid> = rooms.iter().map(|r| r.id).collect();
+    let members = state.db.get_breakout_members(&ids).await?;
+    let ends_at = rooms.first().and_then(|r| r.ends_at);
+    let rooms = rooms
+        .into_iter()
+        .map(|r| BreakoutRoom {
+            members: members
+                .iter()
+                .filter(|m| m.room_id == r.id)
+                .map(|m| BreakoutMember { user_id: m.user_id, username: m.username.clone() })
+                .collect(),
+            id: r.id,
+            name: r.name,
+        })
+        .collect();
+    Ok(ServerMessage::Breakouts { rooms, ends_at })
+}
+
+// The latest messages of a breakout room, and their ids
+async fn load_breakout_history(state: &AppState, room_id: Uuid) -> anyhow::Result<(ServerMessage, HashSet<Uuid>)> {
+    let mut messages = state.db.get_breakout_messages(room_id, HISTORY_ON_JOIN + 1).await?;
+    let has_more = messages.len() as i64 > HISTORY_ON_JOIN;
+    if has_more {
+        messages.remove(0);
+    }
+    let ids = messages.iter().map(|m| m.id).collect();
+    let messages = messages.into_iter().map(ChatMessage::from).collect();
+    Ok((ServerMessage::History { messages, has_more }, ids))
+}
+
+// Breakout chat is kept with its room for the teacher; nothing can be said in
+// a room once everyone has been called back or its time is up
+async fn save_breakout_message(conn: &Connection, lesson_id: Uuid, room_id: Uuid, chat: &ChatMessage) -> Result<(), ServerMessage> {
+    let room = conn.state.db.get_breakout_room(lesson_id, room_id).await.map_err(internal("message could not be saved"))?;
+    if room.is_none_or(|r| r.closed_at.is_some() || r.ends_at.is_some_and(|e| e <= chat.timestamp)) {
+        return Err(error(ErrorCode::NotAllowed, "this breakout room has closed"));
+    }
+    let msg = LessonBreakoutMessage {
+        id: chat.id,
+        room_id,
+        user_id: chat.user_id,
+        username: chat.username.clone(),
+        user_type: conn.user_type.clone(),
+        message: chat.message.clone(),
+        created_at: chat.timestamp,
+        deleted_at: None,
+        deleted_by: None,
+    };
+    conn.state.db.add_breakout_message(&msg).await.map_err(internal("message could not be saved"))
+}
+
+async fn breakout_teacher(conn: &Connection) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "breakout rooms")?;
+    if !teaches(conn, lesson_id).await? {
+        return Err(error(ErrorCode::NotAllowed, "only teachers can run breakout rooms"));
+    }
+    Ok(lesson_id)
+}
+
+fn breakout_end(minutes: Option<u32>) -> Result<Option<chrono::DateTime<chrono::Utc>>, ServerMessage> {
+    match minutes {
+        None => Ok(None),
+        Some(m) if (1..=MAX_BREAKOUT_MINUTES).contains(&m) => {
+            Ok(Some(chrono::Utc::now() + chrono::Duration::minutes(m as i64)))
+        }
+        Some(_) => Err(error(
+            ErrorCode::InvalidMessage,
+            format!("breakout timers must be between 1 and {MAX_BREAKOUT_MINUTES} minutes"),
+        )),
+    }
+}
+
+// Tells the lesson room and the given breakout rooms what's open now
+async fn announce_breakouts(conn: &Connection, lesson_id: Uuid, rooms: &[Uuid]) -> Result<(), ServerMessage> {
+    publish_breakouts(&conn.state, lesson_id, rooms).await.map_err(internal("could not look up the breakout rooms"))
+}
+
+// Sends the lesson's open rooms to the lesson room and to `rooms`
+async fn publish_breakouts(state: &AppState, lesson_id: Uuid, rooms: &[Uuid]) -> anyhow::Result<()> {
+    let breakouts = load_breakouts(state, lesson_id).await?;
+    for &id in rooms {
+        state.broker.publish(Room::Breakout { lesson_id, id }, breakouts.clone());
+    }
+    state.broker.publish(Room::Lesson { id: lesson_id }, breakouts);
+    Ok(())
+}
+
+// Calls everyone back from breakout rooms whose timer has run out, as if the
+// teacher had sent `end_breakouts`
+pub fn spawn_breakout_timer(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BREAKOUT_TIMER_TICK);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = close_expired_breakouts(&state).await {
+                tracing::error!("closing expired breakout rooms failed: {e:?}");
+            }
+        }
     });
+}
 
-    // Outgoing messages
-    while let Ok(msg) = rx.recv().await {
-        let text = serde_json::to_string(&msg).unwrap();
-        if sender.send(Message::Text(text)).await.is_err() {
-            break;
+async fn close_expired_breakouts(state: &AppState) -> anyhow::Result<()> {
+    let mut closed: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+    for (lesson_id, id) in state.db.close_expired_breakouts().await? {
+        closed.entry(lesson_id).or_default().push(id);
+    }
+    for (lesson_id, rooms) in closed {
+        publish_breakouts(state, lesson_id, &rooms).await?;
+    }
+    Ok(())
+}
+
+// Splits the lesson into breakout rooms: the students in the lesson room
+// shuffled into even groups, or the groups the teacher picked, who may be
+// anyone in the lesson
+async fn start_breakouts(conn: &Connection, split: BreakoutSplit, minutes: Option<u32>) -> Result<(), ServerMessage> {
+    let lesson_id = breakout_teacher(conn).await?;
+    let ends_at = breakout_end(minutes)?;
+    let groups: Vec<Vec<(Uuid, String)>> = match split {
+        BreakoutSplit::Random { rooms } => {
+            let mut students: Vec<(Uuid, String)> = presence::participants(&conn.state.db, conn.room).await
+                .map_err(internal("the breakout rooms could not be opened"))?
+                .into_iter()
+                .filter(|p| p.role == Role::Student)
+                .map(|p| (p.user_id, p.username))
+                .collect();
+            if students.is_empty() {
+                return Err(error(ErrorCode::NotAllowed, "there are no students in the lesson to split up"));
+            }
+            if rooms == 0 || rooms > MAX_BREAKOUT_ROOMS.min(students.len()) {
+                return Err(error(
+                    ErrorCode::InvalidMessage,
+                    format!("choose between 1 and {} rooms", MAX_BREAKOUT_ROOMS.min(students.len())),
+                ));
+            }
+            // Random v4 ids make a good enough shuffle
+            students.sort_by_cached_key(|_| Uuid::new_v4());
+            let mut groups = vec![Vec::new(); rooms];
+            for (i, student) in students.into_iter().enumerate() {
+                groups[i % rooms].push(student);
+            }
+            groups
+        }
+        BreakoutSplit::Manual { groups } => {
+            if groups.is_empty() || groups.len() > MAX_BREAKOUT_ROOMS || groups.iter().any(|g| g.is_empty()) {
+                return Err(error(
+                    ErrorCode::InvalidMessage,
+                    format!("give between 1 and {MAX_BREAKOUT_ROOMS} groups, none of them empty"),
+                ));
+            }
+            let mut placed = HashSet::new();
+            let mut named = Vec::with_capacity(groups.len());
+            for group in groups {
+                let mut members = Vec::with_capacity(group.len());
+                for user_id in group {
+                    if !placed.insert(user_id) {
+                        return Err(error(ErrorCode::InvalidMessage, "each person can only be in one room"));
+                    }
+                    let user = lesson_member(conn, user_id).await?;
+                    members.push((user_id, user.display_name().chars().take(100).collect()));
+                }
+                named.push(members);
+            }
+            named
+        }
+    };
+    let rooms: Vec<(String, Vec<(Uuid, String)>)> = groups
+        .into_iter()
+        .enumerate()
+        .map(|(i, members)| (format!("Room {}", i + 1), members))
+        .collect();
+    let opened = conn.state.db.open_breakouts(lesson_id, &rooms, ends_at).await
+        .map_err(internal("the breakout rooms could not be opened"))?;
+    if !opened {
+        return Err(error(ErrorCode::NotAllowed, "breakout rooms are already open; call everyone back first"));
+    }
+    announce_breakouts(conn, lesson_id, &[]).await
+}
+
+// Posts the teacher's message in every open room, where it's kept with the
+// room's own chat
+async fn broadcast_to_breakouts(conn: &Connection, message: String) -> Result<(), ServerMessage> {
+    let lesson_id = breakout_teacher(conn).await?;
+    let message = clean_message(message)?;
+    let rooms = conn.state.db.get_breakout_rooms(lesson_id, true).await.map_err(internal("could not look up the breakout rooms"))?;
+    if rooms.is_empty() {
+        return Err(error(ErrorCode::NotFound, "no breakout rooms are open"));
+    }
+    let timestamp = chrono::Utc::now();
+    for room in rooms {
+        let chat = ChatMessage {
+            id: Uuid::new_v4(),
+            seq: None,
+            user_id: conn.user_id,
+            username: conn.username.clone(),
+            role: Role::from(&conn.user_type),
+            message: message.clone(),
+            timestamp,
+            reply_to: None,
+            edited_at: None,
+            reactions: Vec::new(),
+        };
+        save_breakout_message(conn, lesson_id, room.id, &chat).await?;
+        conn.state.broker.publish(Room::Breakout { lesson_id, id: room.id }, ServerMessage::Chat(Box::new(chat)));
+    }
+    Ok(())
+}
+
+async fn set_breakout_timer(conn: &Connection, minutes: Option<u32>) -> Result<(), ServerMessage> {
+    let lesson_id = breakout_teacher(conn).await?;
+    let ends_at = breakout_end(minutes)?;
+    let rooms = conn.state.db.set_breakout_timer(lesson_id, ends_at).await.map_err(internal("the timer could not be set"))?;
+    if rooms.is_empty() {
+        return Err(error(ErrorCode::NotFound, "no breakout rooms are open"));
+    }
+    announce_breakouts(conn, lesson_id, &rooms).await
+}
+
+// Closes every open room; the empty `breakouts` frame calls everyone back
+async fn end_breakouts(conn: &Connection) -> Result<(), ServerMessage> {
+    let lesson_id = breakout_teacher(conn).await?;
+    let rooms = conn.state.db.close_breakouts(lesson_id).await.map_err(internal("the breakout rooms could not be closed"))?;
+    if rooms.is_empty() {
+        return Err(error(ErrorCode::NotFound, "no breakout rooms are open"));
+    }
+    announce_breakouts(conn, lesson_id, &rooms).await
+}
+
+// Authors can change the text of their own messages; it goes through the same
+// checks as a new message, except slow mode
+async fn edit_chat(conn: &Connection, message_id: Uuid, message: String) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "edits")?;
+    let mut text = clean_message(message)?;
+    let mut flagged = None;
+    if !is_staff(conn) {
+        let policy = check_student(conn).await?;
+        flagged = check_student_text(conn, policy.as_ref(), &mut text).await?;
+    }
+    let original = conn.state.db.get_chat_message(lesson_id, message_id).await
+        .map_err(internal("message could not be checked"))?
+        .ok_or_else(|| error(ErrorCode::NotFound, "that message no longer exists"))?;
+    if original.user_id != conn.user_id {
+        return Err(error(ErrorCode::NotAllowed, "you can only edit your own messages"));
+    }
+    if original.message == text {
+        return Ok(message_id);
+    }
+    let edited_at = chrono::Utc::now();
+    conn.state.db.edit_chat_message(message_id, &text, edited_at).await
+        .map_err(internal("message could not be saved"))?;
+    conn.state.broker.publish(conn.room, ServerMessage::Edited { message_id, message: text.clone(), edited_at });
+    queue_flagged(conn, flagged, message_id, &text).await;
+    Ok(message_id)
+}
+
+// Longest reaction accepted, in characters; emoji with skin tones or joined
+// sequences (families, flags) take several
+const MAX_REACTION_LENGTH: usize = 16;
+
+fn is_emoji(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars().count() <= MAX_REACTION_LENGTH
+        && !s.is_ascii()
+        && !s.chars().any(|c| c.is_whitespace() || c.is_ascii_alphabetic())
+}
+
+async fn react(conn: &Connection, message_id: Uuid, emoji: String, add: bool) -> Result<Uuid, ServerMessage> {
+    let lesson_id = lesson_only(conn, "reactions")?;
+    let emoji = emoji.trim();
+    if !is_emoji(emoji) {
+        return Err(error(ErrorCode::InvalidMessage, "reactions must be a single emoji"));
+    }
+    if !is_staff(conn) {
+        check_student(conn).await?;
+    }
+    let db = &conn.state.db;
+    if db.get_chat_message(lesson_id, message_id).await.map_err(internal("reaction could not be saved"))?.is_none() {
+        return Err(error(ErrorCode::NotFound, "that message no longer exists"));
+    }
+    let saved = if add {
+        db.add_chat_reaction(message_id, conn.user_id, emoji).await
+    } else {
+        db.remove_chat_reaction(message_id, conn.user_id, emoji).await
+    };
+    saved.map_err(internal("reaction could not be saved"))?;
+    let rows = db.get_chat_reactions(&[message_id]).await.map_err(internal("reaction could not be saved"))?;
+    let reactions = Reaction::group(rows).remove(&message_id).unwrap_or_default();
+    conn.state.broker.publish(conn.room, ServerMessage::Reactions { message_id, reactions });
+    Ok(message_id)
+}
+
+// Runs the classroom's content filter over a student's message, masking it in
+// place. Returns why it was flagged, if it was. Given the classroom's current
+// `rule_ids`, the rules are only read when the cached ones are out of date.
+async fn filter_message(
+    conn: &Connection,
+    classroom_id: Uuid,
+    rule_ids: Option<&[Uuid]>,
+    text: &mut String,
+) -> Result<Vec<String>, ServerMessage> {
+    let rules = match rule_ids.and_then(|ids| content_filter::cached(classroom_id, ids)) {
+        Some(rules) => rules,
+        None => {
+            let rules = conn.state.db.get_chat_filter_rules(classroom_id).await
+                .map_err(internal("message could not be checked"))?;
+            content_filter::compiled(classroom_id, &rules)
         }
+    };
+    let verdict = content_filter::apply(&rules, text);
+    if verdict.blocked {
+        return Err(error(ErrorCode::Blocked, "your message was blocked by the classroom's chat filter"));
     }
+    *text = verdict.text;
+    Ok(verdict.flags)
 }
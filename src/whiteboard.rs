@@ -0,0 +1,271 @@
+// The lesson whiteboard. Every change is an op in the lesson's ordered log, and
+// the board is what replaying the log leaves: strokes and shapes in the order
+// they were drawn, minus those erased since, starting over at each clear. Ops
+// are checked before they are stored, so replaying and rendering trust them.
+// The stored log drops ops once a clear or erase makes them moot, and the board
+// holds at most MAX_ELEMENTS, so replaying it on join stays cheap.
+use std::fmt::Write;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::protocol::{ShapeKind, WhiteboardElement, WhiteboardOp};
+
+pub const BOARD_WIDTH: f32 = 1600.0;
+pub const BOARD_HEIGHT: f32 = 900.0;
+
+pub const MAX_ELEMENTS: i64 = 2000;
+const MAX_POINTS: usize = 2000;
+const MAX_LINE_WIDTH: f32 = 50.0;
+
+static COLOR: Lazy<Regex> = Lazy::new(|| Regex::new(r"^#[0-9a-fA-F]{6}$").unwrap());
+
+fn check_point([x, y]: [f32; 2]) -> Result<(), String> {
+    if (0.0..=BOARD_WIDTH).contains(&x) && (0.0..=BOARD_HEIGHT).contains(&y) {
+        Ok(())
+    } else {
+        Err(format!("points must be on the {BOARD_WIDTH}x{BOARD_HEIGHT} board"))
+    }
+}
+
+fn check_color(color: &str) -> Result<(), String> {
+    if COLOR.is_match(color) {
+        Ok(())
+    } else {
+        Err("colors must look like #1e293b".to_string())
+    }
+}
+
+fn check_width(width: f32) -> Result<(), String> {
+    if width > 0.0 && width <= MAX_LINE_WIDTH {
+        Ok(())
+    } else {
+        Err(format!("line widths must be above 0 and at most {MAX_LINE_WIDTH}"))
+    }
+}
+
+// Whether an op is fit to store; the error is meant for the person drawing
+pub fn validate(op: &WhiteboardOp) -> Result<(), String> {
+    match op {
+        WhiteboardOp::Stroke { points, color, width, .. } => {
+            if points.is_empty() || points.len() > MAX_POINTS {
+                return Err(format!("strokes need between 1 and {MAX_POINTS} points"));
+            }
+            points.iter().try_for_each(|&p| check_point(p))?;
+            check_color(color)?;
+            check_width(*width)
+        }
+        WhiteboardOp::Shape { from, to, color, width, fill, .. } => {
+            check_point(*from)?;
+            check_point(*to)?;
+            check_color(color)?;
+            if let Some(fill) = fill {
+                check_color(fill)?;
+            }
+            check_width(*width)
+        }
+        WhiteboardOp::Erase { .. } | WhiteboardOp::Clear => Ok(()),
+    }
+}
+
+// What's on the board after `ops`, each with who drew it, in log order
+pub fn replay(ops: impl IntoIterator<Item = (Uuid, WhiteboardOp)>) -> Vec<WhiteboardElement> {
+    let mut elements: Vec<WhiteboardElement> = Vec::new();
+    for (user_id, op) in ops {
+        match op {
+            WhiteboardOp::Erase { id } => elements.retain(|e| e.op.element_id() != Some(id)),
+            WhiteboardOp::Clear => elements.clear(),
+            op => elements.push(WhiteboardElement { user_id, op }),
+        }
+    }
+    elements
+}
+
+// The board as a standalone SVG document, the same size as the board
+pub fn to_svg(elements: &[WhiteboardElement]) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {BOARD_WIDTH} {BOARD_HEIGHT}" width="{BOARD_WIDTH}" height="{BOARD_HEIGHT}">"#
+    );
+    let _ = writeln!(svg, r##"<rect width="100%" height="100%" fill="#ffffff"/>"##);
+    for element in elements {
+        match &element.op {
+            WhiteboardOp::Stroke { points, color, width, .. } => {
+                if let [[x, y]] = points.as_slice() {
+                    // A click without a drag is a dot
+                    let _ = writeln!(svg, r#"<circle cx="{x}" cy="{y}" r="{}" fill="{color}"/>"#, width / 2.0);
+                } else {
+                    let points: Vec<String> = points.iter().map(|[x, y]| format!("{x},{y}")).collect();
+                    let _ = writeln!(
+                        svg,
+                        r#"<polyline points="{}" fill="none" stroke="{color}" stroke-width="{width}" stroke-linecap="round" stroke-linejoin="round"/>"#,
+                        points.join(" ")
+                    );
+                }
+            }
+            WhiteboardOp::Shape { shape, from: [x1, y1], to: [x2, y2], color, width, fill, .. } => {
+                let stroke = format!(r#"stroke="{color}" stroke-width="{width}""#);
+                let fill = fill.as_deref().unwrap_or("none");
+                match shape {
+                    ShapeKind::Line | ShapeKind::Arrow => {
+                        let _ = writeln!(svg, r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" {stroke} stroke-linecap="round"/>"#);
+                        if *shape == ShapeKind::Arrow {
+                            let [left, right] = arrow_head([*x1, *y1], [*x2, *y2], *width);
+                            let _ = writeln!(
+                                svg,
+                                r#"<polyline points="{},{} {x2},{y2} {},{}" fill="none" {stroke} stroke-linecap="round" stroke-linejoin="round"/>"#,
+                                left[0], left[1], right[0], right[1]
+                            );
+                        }
+                    }
+                    ShapeKind::Rect => {
+                        let _ = writeln!(
+                            svg,
+                            r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{fill}" {stroke}/>"#,
+                            x1.min(*x2),
+                            y1.min(*y2),
+                            (x2 - x1).abs(),
+                            (y2 - y1).abs()
+                        );
+                    }
+                    ShapeKind::Ellipse => {
+                        let _ = writeln!(
+                            svg,
+                            r#"<ellipse cx="{}" cy="{}" rx="{}" ry="{}" fill="{fill}" {stroke}/>"#,
+                            (x1 + x2) / 2.0,
+                            (y1 + y2) / 2.0,
+                            (x2 - x1).abs() / 2.0,
+                            (y2 - y1).abs() / 2.0
+                        );
+                    }
+                }
+            }
+            WhiteboardOp::Erase { .. } | WhiteboardOp::Clear => {}
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// The ends of the two short lines that make an arrow's head at `to`
+fn arrow_head(from: [f32; 2], to: [f32; 2], width: f32) -> [[f32; 2]; 2] {
+    let length = (width * 4.0).max(12.0);
+    let angle = (to[1] - from[1]).atan2(to[0] - from[0]);
+    let spread = std::f32::consts::PI / 7.0;
+    [angle + spread, angle - spread].map(|a| [to[0] - length * a.cos(), to[1] - length * a.sin()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stroke(id: Uuid, points: Vec<[f32; 2]>) -> WhiteboardOp {
+        WhiteboardOp::Stroke { id, points, color: "#1e293b".to_string(), width: 4.0 }
+    }
+
+    fn shape(id: Uuid, shape: ShapeKind, fill: Option<&str>) -> WhiteboardOp {
+        WhiteboardOp::Shape {
+            id,
+            shape,
+            from: [100.0, 200.0],
+            to: [40.0, 80.0],
+            color: "#ff0000".to_string(),
+            width: 2.0,
+            fill: fill.map(str::to_string),
+        }
+    }
+
+    fn ids(elements: &[WhiteboardElement]) -> Vec<Uuid> {
+        elements.iter().filter_map(|e| e.op.element_id()).collect()
+    }
+
+    #[test]
+    fn accepts_ops_on_the_board() {
+        let id = Uuid::new_v4();
+        assert!(validate(&stroke(id, vec![[0.0, 0.0], [BOARD_WIDTH, BOARD_HEIGHT]])).is_ok());
+        assert!(validate(&shape(id, ShapeKind::Rect, Some("#ABCDEF"))).is_ok());
+        assert!(validate(&WhiteboardOp::Erase { id }).is_ok());
+        assert!(validate(&WhiteboardOp::Clear).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_strokes() {
+        let id = Uuid::new_v4();
+        assert!(validate(&stroke(id, vec![])).is_err());
+        assert!(validate(&stroke(id, vec![[1.0, 1.0]; MAX_POINTS + 1])).is_err());
+        assert!(validate(&stroke(id, vec![[1.0, 1.0], [BOARD_WIDTH + 1.0, 1.0]])).is_err());
+        assert!(validate(&stroke(id, vec![[-1.0, 1.0]])).is_err());
+        assert!(validate(&stroke(id, vec![[f32::NAN, 1.0]])).is_err());
+        let WhiteboardOp::Stroke { points, .. } = stroke(id, vec![[1.0, 1.0]]) else { unreachable!() };
+        for (color, width) in [("red", 4.0), ("#12345", 4.0), ("#1e293b\"/>", 4.0), ("#1e293b", 0.0), ("#1e293b", 51.0)] {
+            let op = WhiteboardOp::Stroke { id, points: points.clone(), color: color.to_string(), width };
+            assert!(validate(&op).is_err(), "{color} {width}");
+        }
+    }
+
+    #[test]
+    fn rejects_bad_shapes() {
+        let id = Uuid::new_v4();
+        assert!(validate(&shape(id, ShapeKind::Rect, Some("none"))).is_err());
+        let WhiteboardOp::Shape { color, width, fill, .. } = shape(id, ShapeKind::Line, None) else { unreachable!() };
+        let off_board = [0.0, BOARD_HEIGHT + 0.5];
+        let op = WhiteboardOp::Shape { id, shape: ShapeKind::Line, from: [0.0, 0.0], to: off_board, color, width, fill };
+        assert!(validate(&op).is_err());
+    }
+
+    #[test]
+    fn replays_in_order_with_erases_and_clears() {
+        let (ana, ben) = (Uuid::new_v4(), Uuid::new_v4());
+        let [a, b, c, d] = [(); 4].map(|_| Uuid::new_v4());
+        let ops = vec![
+            (ana, stroke(a, vec![[1.0, 1.0]])),
+            (ben, shape(b, ShapeKind::Rect, None)),
+            (ana, stroke(c, vec![[2.0, 2.0]])),
+            (ben, WhiteboardOp::Erase { id: b }),
+            // Erasing something that isn't there changes nothing
+            (ben, WhiteboardOp::Erase { id: d }),
+        ];
+        let elements = replay(ops.clone());
+        assert_eq!(ids(&elements), vec![a, c]);
+        assert!(elements.iter().all(|e| e.user_id == ana));
+
+        let mut cleared = ops;
+        cleared.push((ana, WhiteboardOp::Clear));
+        assert!(replay(cleared.clone()).is_empty());
+        cleared.push((ben, shape(d, ShapeKind::Ellipse, None)));
+        let elements = replay(cleared);
+        assert_eq!(ids(&elements), vec![d]);
+        assert_eq!(elements[0].user_id, ben);
+    }
+
+    #[test]
+    fn renders_each_element() {
+        let user_id = Uuid::new_v4();
+        let element = |op| WhiteboardElement { user_id, op };
+        let svg = to_svg(&[
+            element(stroke(Uuid::new_v4(), vec![[10.0, 20.0]])),
+            element(stroke(Uuid::new_v4(), vec![[10.0, 20.0], [30.5, 40.0]])),
+            element(shape(Uuid::new_v4(), ShapeKind::Rect, Some("#00ff00"))),
+            element(shape(Uuid::new_v4(), ShapeKind::Ellipse, None)),
+            element(shape(Uuid::new_v4(), ShapeKind::Arrow, None)),
+        ]);
+        assert!(svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 1600 900""#));
+        assert!(svg.ends_with("</svg>\n"));
+        assert!(svg.contains(r##"<circle cx="10" cy="20" r="2" fill="#1e293b"/>"##));
+        assert!(svg.contains(r##"<polyline points="10,20 30.5,40" fill="none" stroke="#1e293b" stroke-width="4""##));
+        // Boxes are normalized, whichever corner the drag started at
+        assert!(svg.contains(r##"<rect x="40" y="80" width="60" height="120" fill="#00ff00" stroke="#ff0000" stroke-width="2"/>"##));
+        assert!(svg.contains(r##"<ellipse cx="70" cy="140" rx="30" ry="60" fill="none" stroke="#ff0000" stroke-width="2"/>"##));
+        assert!(svg.contains(r#"<line x1="100" y1="200" x2="40" y2="80""#));
+        // The arrow's head is drawn at its `to` end
+        assert!(svg.contains(" 40,80 "));
+        assert_eq!(svg.lines().count(), 2 + 6 + 1);
+    }
+
+    #[test]
+    fn renders_an_empty_board() {
+        assert_eq!(to_svg(&[]).lines().count(), 3);
+    }
+}
@@ -188,7 +188,7 @@ document.addEventListener('DOMContentLoaded', async function() {
                         <p><b>Classroom:</b> ${lesson.classroom_id}</p>
                         <p><b>Scheduled:</b> ${new Date(lesson.scheduled_at).toLocaleString()}</p>
                         <div class="lesson-actions">
-                            <button class="btn btn-outline" onclick="startClass('${lesson.classroom_id}')">Start</button>
+                            <button class="btn btn-outline" onclick="startClass('${lesson.classroom_id}', '${lesson.id}')">Start</button>
                         </div>
                     </div>
                 `).join('');
@@ -272,8 +272,9 @@ document.addEventListener('DOMContentLoaded', async function() {
                     teacher_id: JSON.parse(localStorage.getItem('currentUser')).id,
                     title,
                     description,
-                    scheduled_at,
-                    is_active: true,
+                    // datetime-local has no offset; send the instant in the browser's zone
+                    scheduled_at: new Date(scheduled_at).toISOString(),
+                    status: 'scheduled',
                     chat_closed: false,
                     created_at: new Date().toISOString()
                 })
@@ -387,8 +388,13 @@ function requestZoomMeeting(classroomId) {
     });
 }
 
-function startClass() {
-    window.open('/api/classroom/new-session', '_blank');
+function startClass(classroomId, lessonId) {
+    if (!classroomId) {
+        window.open('/api/classroom/new-session', '_blank');
+        return;
+    }
+    const query = lessonId ? `?lesson=${lessonId}` : '';
+    window.open(`/api/classroom/${classroomId}${query}`, '_blank');
 }
 
 function createClass() {
@@ -401,9 +407,9 @@ function openBooks() {
     alert('Digital books section coming soon!');
 }
 
+// Videos are watched together inside a live lesson, played by the teacher
 function watchVideos() {
-    // Implementation for video watching
-    alert('Video library coming soon!');
+    document.querySelector('.nav-item[href="#classrooms"]').click();
 }
 
 function uploadMaterial() {